@@ -0,0 +1,208 @@
+use super::{cfg::BootEntry, fs::BootServicesExt, var};
+use alloc::{string::String, vec::Vec};
+#[cfg(target_arch = "x86_64")]
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use uefi::{
+    proto::loaded_image::LoadedImage,
+    table::{
+        boot::{OpenProtocolAttributes, OpenProtocolParams},
+        runtime::{VariableAttributes, VariableVendor},
+    },
+    Guid, Handle,
+};
+
+/// The vendor GUID of the Boot Loader Interface variables that
+/// `systemd-boot` publishes and `bootctl` and `systemd-analyze` read.
+const VENDOR: VariableVendor = VariableVendor(Guid::from_values(
+    0x4a67b082,
+    0x0a4c,
+    0x41cf,
+    0xb6c7,
+    0x440b29bb8c4f,
+));
+
+const FEATURE_CONFIG_TIMEOUT_ONE_SHOT: u64 = 1 << 1;
+const FEATURE_ENTRY_DEFAULT: u64 = 1 << 2;
+const FEATURE_ENTRY_ONESHOT: u64 = 1 << 3;
+const FEATURE_BOOT_COUNTING: u64 = 1 << 4;
+const FEATURE_SORT_KEY: u64 = 1 << 8;
+const FEATURE_SAVED_ENTRY: u64 = 1 << 9;
+
+const FEATURES: u64 = FEATURE_CONFIG_TIMEOUT_ONE_SHOT
+    | FEATURE_ENTRY_DEFAULT
+    | FEATURE_ENTRY_ONESHOT
+    | FEATURE_BOOT_COUNTING
+    | FEATURE_SORT_KEY
+    | FEATURE_SAVED_ENTRY;
+
+#[cfg(target_arch = "x86_64")]
+static TICKS_PER_US: AtomicU64 = AtomicU64::new(0);
+
+/// Microseconds since the CPU was reset, as systemd counts them, if the
+/// platform has a clock for it.
+#[cfg(target_arch = "x86_64")]
+pub fn time_usec() -> Option<u64> {
+    let mut ticks_per_us = TICKS_PER_US.load(Ordering::Relaxed);
+    if ticks_per_us == 0 {
+        let system_table = uefi_services::system_table();
+        let system_table = unsafe { system_table.as_ref() };
+        let start = unsafe { _rdtsc() };
+        system_table.boot_services().stall(1000);
+        ticks_per_us = (unsafe { _rdtsc() } - start) / 1000;
+        TICKS_PER_US.store(ticks_per_us, Ordering::Relaxed);
+    }
+    unsafe { _rdtsc() }.checked_div(ticks_per_us)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn time_usec() -> Option<u64> {
+    None
+}
+
+/// Publishes what the loader knows about itself and the firmware, with the
+/// time it started at.
+pub fn set_loader_info(image_handle: Handle, init_usec: Option<u64>) {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let firmware_revision = system_table.firmware_revision();
+    let uefi_revision = system_table.uefi_revision();
+    let loader_info = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+    set_string("LoaderInfo", loader_info);
+    set_string(
+        "LoaderFirmwareInfo",
+        &format!(
+            "{} {}.{:02}",
+            system_table.firmware_vendor(),
+            firmware_revision.major(),
+            firmware_revision.minor()
+        ),
+    );
+    set_string(
+        "LoaderFirmwareType",
+        &format!(
+            "UEFI {}.{:02}",
+            uefi_revision.major(),
+            uefi_revision.minor()
+        ),
+    );
+    set("LoaderFeatures", &FEATURES.to_le_bytes());
+    if let Some(init_usec) = init_usec {
+        set_string("LoaderTimeInitUSec", &format!("{init_usec}"));
+    }
+    if let Some(uuid) = partition_uuid(image_handle) {
+        set_string("LoaderDevicePartUUID", &uuid);
+    }
+}
+
+/// Publishes the ids of the entries in the menu.
+pub fn set_entries(boot_entries: &[BootEntry]) {
+    let ids = boot_entries
+        .iter()
+        .flat_map(|entry| entry.id().encode_utf16().chain([0]))
+        .flat_map(u16::to_le_bytes)
+        .collect::<Vec<_>>();
+    set("LoaderEntries", &ids);
+}
+
+/// Publishes the entry about to be booted and the time it's launched at.
+pub fn set_selected(entry: &BootEntry) {
+    set_string("LoaderEntrySelected", entry.id());
+    if let Some(exec_usec) = time_usec() {
+        set_string("LoaderTimeExecUSec", &format!("{exec_usec}"));
+    }
+}
+
+/// Publishes the entry booted while the default is `@saved`, which is the one
+/// `bootctl` reports the next boot will pick. It's non-volatile like
+/// `systemd-boot`'s, so it's only written when it changes.
+pub fn set_last_booted(entry: &BootEntry) {
+    let name = "LoaderEntryLastBooted";
+    if var::get_string(name, &VENDOR).as_deref() != Some(entry.id()) {
+        let _ = var::set_string(name, &VENDOR, var::non_volatile(), entry.id());
+    }
+}
+
+/// The entry the OS asked for, either for this boot only through
+/// `LoaderEntryOneShot`, which is consumed, or through `LoaderEntryDefault`,
+/// where `@saved` stands for the entry booted last.
+pub fn default_entry() -> Option<String> {
+    take_string("LoaderEntryOneShot").or_else(|| var::get_string("LoaderEntryDefault", &VENDOR))
+}
+
+/// The timeout the OS asked for this boot only, where `menu-force` waits
+/// forever, `menu-hidden` and `menu-disabled` boot right away.
+pub fn timeout_one_shot() -> Option<Option<u64>> {
+    let timeout = take_string("LoaderConfigTimeoutOneShot")?;
+    match timeout.as_str() {
+        "menu-force" => Some(None),
+        "menu-hidden" | "menu-disabled" => Some(Some(0)),
+        timeout => timeout.parse().ok().map(Some),
+    }
+}
+
+/// Reads a variable and deletes it so that it applies only once. Deleting
+/// takes the attributes it was written with, volatile when systemd sets it.
+fn take_string(name: &str) -> Option<String> {
+    let (data, attributes) = var::get_with_attributes(name, &VENDOR)?;
+    let _ = var::set(name, &VENDOR, attributes, &[]);
+    var::from_utf16(&data)
+}
+
+/// The unique partition GUID of the loader's own partition, in upper case as
+/// systemd writes it.
+fn partition_uuid(image_handle: Handle) -> Option<String> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let loaded_image = boot_services
+        .open_protocol::<LoadedImage>(
+            OpenProtocolParams {
+                handle: image_handle,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+        .ok()?;
+    let device = unsafe { &*loaded_image.interface.get() }.device();
+    let device_path = boot_services.get_device_path_text(image_handle, device)?;
+    gpt_partition_uuid(&device_path)
+}
+
+/// Picks the signature out of the `HD(n,GPT,uuid,start,size)` node of a device
+/// path in text form.
+fn gpt_partition_uuid(device_path: &str) -> Option<String> {
+    let (_, node) = device_path.split_once("HD(")?;
+    let mut fields = node.split([',', ')']);
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(_), Some("GPT"), Some(uuid)) => Some(uuid.to_ascii_uppercase()),
+        _ => None,
+    }
+}
+
+fn set(name: &str, data: &[u8]) {
+    let attributes = VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS;
+    let _ = var::set(name, &VENDOR, attributes, data);
+}
+
+fn set_string(name: &str, value: &str) {
+    let attributes = VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS;
+    let _ = var::set_string(name, &VENDOR, attributes, value);
+}
+
+#[test_case]
+fn partition_uuids() {
+    let device_path = "PciRoot(0x0)/Pci(0x1,0x1)/Ata(0x0)/\
+                       HD(1,GPT,c12a7328-f81f-11d2-ba4b-00a0c93ec93b,0x800,0x100000)";
+    assert_eq!(
+        gpt_partition_uuid(device_path).as_deref(),
+        Some("C12A7328-F81F-11D2-BA4B-00A0C93EC93B")
+    );
+    assert_eq!(
+        gpt_partition_uuid("PciRoot(0x0)/HD(1,MBR,0x1234,0x800,0x1000)"),
+        None
+    );
+}
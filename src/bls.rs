@@ -0,0 +1,307 @@
+use super::{
+    cfg::{BootEntry, EntryKind},
+    fs::{self, FileExt, FileSystem},
+    uki,
+};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::cmp::Ordering;
+use uefi::{proto::media::file::FileMode, Error, Handle};
+
+pub const ENTRIES_PATH: &str = "\\loader\\entries";
+
+#[cfg(target_arch = "x86_64")]
+pub const ARCHITECTURE: &str = "x64";
+#[cfg(target_arch = "x86")]
+pub const ARCHITECTURE: &str = "ia32";
+#[cfg(target_arch = "aarch64")]
+pub const ARCHITECTURE: &str = "aa64";
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+pub const ARCHITECTURE: &str = "";
+
+/// A Boot Loader Specification Type #1 entry or Type #2 unified kernel image.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BlsEntry {
+    pub id: String,
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub machine_id: Option<String>,
+    pub sort_key: Option<String>,
+    pub linux: Option<String>,
+    pub initrd: Vec<String>,
+    pub efi: Option<String>,
+    pub options: Vec<String>,
+    pub architecture: Option<String>,
+    pub counter: Option<Counter>,
+}
+
+/// The tries left and done of an entry under boot counting, kept in its file
+/// name as `name+LEFT-DONE.conf` until the OS blesses it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counter {
+    pub path: String,
+    pub left: u32,
+    pub done: u32,
+}
+
+impl Counter {
+    /// Renames the entry's file to count one more try.
+    pub fn count_attempt(&self, image_handle: Handle) -> Result<(), Error> {
+        let name = self.path.rsplit('\\').next().unwrap_or_default();
+        let (id, _) = split_counter(name);
+        let name = counted_name(&id, self.left.saturating_sub(1), self.done + 1);
+        fs::get(image_handle)
+            .open(&self.path, FileMode::ReadWrite)?
+            .rename(&name)
+    }
+
+    /// Whether the entry ran out of tries without the OS blessing it.
+    pub fn is_bad(&self) -> bool {
+        self.left == 0
+    }
+}
+
+impl BlsEntry {
+    /// Parses the `key value` lines of a `.conf` file named `id`, which keeps
+    /// its suffix as `systemd-boot` entry ids do.
+    pub fn parse(id: &str, text: &str) -> Self {
+        let mut entry = Self {
+            id: id.to_string(),
+            ..Self::default()
+        };
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(char::is_whitespace)
+                .map_or((line, ""), |(key, value)| (key, value.trim()));
+            let value = value.to_string();
+            match key {
+                "title" => entry.title = Some(value),
+                "version" => entry.version = Some(value),
+                "machine-id" => entry.machine_id = Some(value),
+                "sort-key" => entry.sort_key = Some(value),
+                "linux" => entry.linux = Some(path(&value)),
+                "initrd" => entry.initrd.push(path(&value)),
+                "efi" => entry.efi = Some(path(&value)),
+                "options" => entry.options.push(value),
+                "architecture" => entry.architecture = Some(value),
+                _ => (),
+            }
+        }
+        entry
+    }
+
+    /// Whether the entry can run on this machine at all.
+    pub fn is_bootable(&self) -> bool {
+        let architecture = self.architecture.as_deref();
+        (self.linux.is_some() || self.efi.is_some())
+            && architecture.map_or(true, |architecture| {
+                architecture.eq_ignore_ascii_case(ARCHITECTURE)
+            })
+    }
+
+    /// Whether the entry ran out of tries, see `Counter::is_bad`.
+    pub fn is_bad(&self) -> bool {
+        self.counter.as_ref().map_or(false, Counter::is_bad)
+    }
+
+    /// Orders entries the way the specification asks menus to list them, with
+    /// the ones that ran out of tries last.
+    pub fn compare(&self, other: &Self) -> Ordering {
+        let sort_key = match (&self.sort_key, &other.sort_key) {
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(a), Some(b)) => a
+                .cmp(b)
+                .then_with(|| self.machine_id.cmp(&other.machine_id))
+                .then_with(|| {
+                    let version = |entry: &Self| entry.version.clone().unwrap_or_default();
+                    version_compare(&version(other), &version(self))
+                }),
+            (None, None) => Ordering::Equal,
+        };
+        let bad = self.is_bad().cmp(&other.is_bad());
+        bad.then(sort_key)
+            .then_with(|| version_compare(&other.id, &self.id))
+    }
+
+    pub fn title(&self) -> String {
+        let title = self.title.as_ref().or(self.version.as_ref());
+        title.unwrap_or(&self.id).clone()
+    }
+}
+
+impl From<&BlsEntry> for BootEntry {
+    fn from(entry: &BlsEntry) -> Self {
+        let (kind, path) = match &entry.linux {
+            Some(linux) => (EntryKind::Linux, linux.clone()),
+            None => (EntryKind::Efi, entry.efi.clone().unwrap_or_default()),
+        };
+        Self {
+            id: entry.id.clone(),
+            title: entry.title(),
+            kind,
+            path,
+            initrd: entry.initrd.clone(),
+            options: entry.options.join(" "),
+            counter: entry.counter.clone(),
+            ..Self::default()
+        }
+    }
+}
+
+/// Reads the Type #1 entries in `/loader/entries` and the Type #2 unified
+/// kernel images on the loader's volume, sorted for the menu.
+pub fn entries(image_handle: Handle) -> Vec<BlsEntry> {
+    let file_system = fs::get(image_handle);
+    let names = file_system.read_dir(ENTRIES_PATH).unwrap_or_default();
+    let mut entries = names
+        .iter()
+        .filter(|name| name.ends_with(".conf"))
+        .filter_map(|name| {
+            let path = format!("{ENTRIES_PATH}\\{name}");
+            let text = file_system.open(&path, FileMode::Read).ok()?.load().ok()?;
+            let (id, tries) = split_counter(name);
+            let mut entry = BlsEntry::parse(&id, &String::from_utf8_lossy(&text));
+            entry.counter = tries.map(|(left, done)| Counter { path, left, done });
+            Some(entry)
+        })
+        .filter(BlsEntry::is_bootable)
+        .collect::<Vec<_>>();
+    entries.extend(uki::entries(image_handle));
+    entries.sort_by(BlsEntry::compare);
+    entries
+}
+
+/// Splits `name+LEFT-DONE.conf`, where `-DONE` is optional, into the id
+/// `name.conf` and the counter.
+pub fn split_counter(name: &str) -> (String, Option<(u32, u32)>) {
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let counter = stem.rsplit_once('+').and_then(|(base, counter)| {
+        let (left, done) = counter.split_once('-').unwrap_or((counter, "0"));
+        let is_number = |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit());
+        if !is_number(left) || !is_number(done) {
+            return None;
+        }
+        Some((base, (left.parse().ok()?, done.parse().ok()?)))
+    });
+    match counter {
+        Some((base, tries)) if extension.is_empty() => (base.to_string(), Some(tries)),
+        Some((base, tries)) => (format!("{base}.{extension}"), Some(tries)),
+        None => (name.to_string(), None),
+    }
+}
+
+/// The file name of the entry `id` with `left` tries left and `done` done.
+fn counted_name(id: &str, left: u32, done: u32) -> String {
+    match id.rsplit_once('.') {
+        Some((stem, extension)) => format!("{stem}+{left}-{done}.{extension}"),
+        None => format!("{id}+{left}-{done}"),
+    }
+}
+
+/// Turns a path relative to the root of the volume into a UEFI path.
+fn path(path: &str) -> String {
+    path.replace('/', "\\")
+}
+
+/// Compares versions as the UAPI version format specification does, where
+/// `~` sorts before anything, `-` and `^` separate releases and numbers
+/// compare numerically.
+pub fn version_compare(a: &str, b: &str) -> Ordering {
+    let is_valid = |c: &u8| c.is_ascii_alphanumeric() || b"~-^.".contains(c);
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    let first = |s: &[u8]| s.first().copied().unwrap_or(0);
+    loop {
+        while !a.is_empty() && !is_valid(&a[0]) {
+            a = &a[1..];
+        }
+        while !b.is_empty() && !is_valid(&b[0]) {
+            b = &b[1..];
+        }
+        for separator in [b'~', b'-', b'^', b'.'] {
+            if separator == b'-' && (a.is_empty() || b.is_empty()) {
+                return a.cmp(b);
+            }
+            if first(a) == separator || first(b) == separator {
+                let ordering = (first(a) != separator).cmp(&(first(b) != separator));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                (a, b) = (&a[1..], &b[1..]);
+            }
+        }
+        let (aa, bb);
+        if first(a).is_ascii_digit() || first(b).is_ascii_digit() {
+            let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+            (aa, bb) = (digits(a), digits(b));
+            let ordering = (aa != 0).cmp(&(bb != 0));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            let strip = |s: &[u8]| s.iter().take_while(|&&c| c == b'0').count();
+            let (za, zb) = (strip(&a[..aa]), strip(&b[..bb]));
+            let ordering = (aa - za)
+                .cmp(&(bb - zb))
+                .then_with(|| a[za..aa].cmp(&b[zb..bb]));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        } else {
+            let letters = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_alphabetic()).count();
+            (aa, bb) = (letters(a), letters(b));
+            let len = aa.min(bb);
+            let ordering = a[..len].cmp(&b[..len]).then_with(|| aa.cmp(&bb));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        (a, b) = (&a[aa..], &b[bb..]);
+    }
+}
+
+#[test_case]
+fn parse() {
+    let entry = BlsEntry::parse(
+        "6a9857a393724b7a981ebb5b8495b9ea-6.0.5-300.fc37.x86_64.conf",
+        "# comment\n\
+         title      Fedora Linux 37\n\
+         version    6.0.5-300.fc37.x86_64\n\
+         linux      /vmlinuz-6.0.5-300.fc37.x86_64\n\
+         initrd     /initramfs-6.0.5-300.fc37.x86_64.img\n\
+         options    root=UUID=1234\n\
+         options    quiet\n",
+    );
+    assert_eq!(entry.title(), "Fedora Linux 37");
+    assert_eq!(
+        entry.linux.as_deref(),
+        Some("\\vmlinuz-6.0.5-300.fc37.x86_64")
+    );
+    let boot_entry = BootEntry::from(&entry);
+    assert_eq!(boot_entry.kind, EntryKind::Linux);
+    assert_eq!(boot_entry.options, "root=UUID=1234 quiet");
+}
+
+#[test_case]
+fn boot_counting() {
+    let (id, tries) = split_counter("fedora-6.0.5+3-0.conf");
+    assert_eq!((id.as_str(), tries), ("fedora-6.0.5.conf", Some((3, 0))));
+    assert_eq!(split_counter("fedora+2.conf").1, Some((2, 0)));
+    assert_eq!(split_counter("fedora+x-1.conf").1, None);
+    assert_eq!(counted_name("fedora.conf", 2, 1), "fedora+2-1.conf");
+}
+
+#[test_case]
+fn compare_versions() {
+    let cmp = version_compare;
+    assert_eq!(cmp("6.0.5", "6.0.10"), Ordering::Less);
+    assert_eq!(cmp("251~rc1", "251"), Ordering::Less);
+    assert_eq!(cmp("123-9", "123.1-1"), Ordering::Less);
+    assert_eq!(cmp("1.0a", "1.0"), Ordering::Greater);
+    assert_eq!(cmp("00123", "123"), Ordering::Equal);
+    assert_eq!(cmp("fc37", "fc38"), Ordering::Less);
+}
@@ -0,0 +1,178 @@
+#[cfg(target_arch = "x86_64")]
+use super::{bzimage, handoff, limine, multiboot2};
+use super::{
+    cfg::{BootEntry, EntryKind},
+    fs::{self, BootServicesExt, FileExt, FileSystem},
+    gop::{self, Interaction},
+    linux, net, secureboot, sha256,
+    str::ToCString16,
+    tpm,
+};
+use alloc::vec::Vec;
+use core::{ffi::c_void, mem::transmute};
+use uefi::{
+    proto::{loaded_image::LoadedImage, media::file::FileMode},
+    table::{
+        boot::{LoadImageSource, OpenProtocolAttributes, OpenProtocolParams},
+        cfg::{ACPI2_GUID, ACPI_GUID},
+        Boot, SystemTable,
+    },
+    Error, Handle, Result, Status,
+};
+
+pub trait Launch {
+    fn launch(&self, image_handle: Handle) -> Result;
+}
+
+impl Launch for BootEntry {
+    fn launch(&self, image_handle: Handle) -> Result {
+        if !self.options.is_empty() {
+            let options = tpm::utf16(&self.options);
+            tpm::measure(tpm::PCR_KERNEL_CONFIG, &options, &self.options)?;
+        }
+        match self.kind {
+            EntryKind::Efi => chainload(image_handle, self),
+            EntryKind::Linux => linux::boot(image_handle, self),
+            #[cfg(target_arch = "x86_64")]
+            EntryKind::BootParams => bzimage::boot(image_handle, self, false),
+            #[cfg(target_arch = "x86_64")]
+            EntryKind::Handover => bzimage::boot(image_handle, self, true),
+            #[cfg(target_arch = "x86_64")]
+            EntryKind::Elf => handoff::boot(image_handle, self),
+            #[cfg(target_arch = "x86_64")]
+            EntryKind::Multiboot2 => multiboot2::boot(image_handle, self),
+            #[cfg(target_arch = "x86_64")]
+            EntryKind::Limine => limine::boot(image_handle, self),
+            #[cfg(not(target_arch = "x86_64"))]
+            EntryKind::BootParams
+            | EntryKind::Handover
+            | EntryKind::Elf
+            | EntryKind::Multiboot2
+            | EntryKind::Limine => Err(Error::from(Status::UNSUPPORTED)),
+        }
+    }
+}
+
+/// Loads one of the files of `entry`, refusing with `SECURITY_VIOLATION`
+/// after telling the user if it doesn't match the digest the entry expects.
+pub fn load(image_handle: Handle, entry: &BootEntry, path: &str) -> Result<Vec<u8>> {
+    let buffer = match net::is_url(path) {
+        true => net::fetch(image_handle, path)?,
+        false => {
+            let (file_system, file_path) = fs::locate(image_handle, path)?;
+            file_system.open(&file_path, FileMode::Read)?.load()?
+        }
+    };
+    if let Some(expected) = entry.sha256.get(path) {
+        let actual = sha256::to_hex(&sha256::digest(&buffer));
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            let _ = gop::get().alert(
+                "Integrity check failed",
+                &[
+                    &entry.title,
+                    path,
+                    "expected SHA-256",
+                    expected,
+                    "actual SHA-256",
+                    &actual,
+                ],
+            );
+            return Err(Error::from(Status::SECURITY_VIOLATION));
+        }
+    }
+    Ok(buffer)
+}
+
+/// Loads the kernel of `entry` that the loader starts itself, checked under
+/// Secure Boot like `LoadImage` would check it.
+pub fn load_kernel(image_handle: Handle, entry: &BootEntry) -> Result<Vec<u8>> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let buffer = load(image_handle, entry, &entry.path)?;
+    secureboot::verify(system_table.boot_services(), image_handle, &buffer)
+        .map_err(|err| refuse(entry, err.status()))?;
+    tpm::measure(tpm::PCR_BOOT_LOADER_CODE, &buffer, &entry.path)?;
+    Ok(buffer)
+}
+
+/// Loads an initrd or a module of `entry` and measures it.
+pub fn load_initrd(image_handle: Handle, entry: &BootEntry, path: &str) -> Result<Vec<u8>> {
+    let buffer = load(image_handle, entry, path)?;
+    tpm::measure(tpm::PCR_KERNEL_INITRD, &buffer, path)?;
+    Ok(buffer)
+}
+
+pub fn chainload(image_handle: Handle, entry: &BootEntry) -> Result {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let buffer = load(image_handle, entry, &entry.path)?;
+    let file_path = match net::is_url(&entry.path) {
+        true => None,
+        false => boot_services.get_file_device_path(image_handle, &entry.path),
+    };
+    let handle = secureboot::with_shim(boot_services, || {
+        boot_services.load_image(
+            image_handle,
+            LoadImageSource::FromBuffer {
+                buffer: &buffer,
+                file_path,
+            },
+        )
+    });
+    if let Some(file_path) = file_path {
+        let _ = boot_services.free_pool(file_path.as_ffi_ptr() as *mut u8);
+    }
+    let handle = handle.map_err(|err| match err.status() {
+        status @ (Status::SECURITY_VIOLATION | Status::ACCESS_DENIED) => refuse(entry, status),
+        status => Error::from(status),
+    })?;
+    let load_options = entry.options.to_cstring16();
+    if !entry.options.is_empty() {
+        let loaded_image = boot_services.open_protocol::<LoadedImage>(
+            OpenProtocolParams {
+                handle,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?;
+        unsafe {
+            (*loaded_image.interface.get()).set_load_options(
+                load_options.as_ptr().cast(),
+                load_options.num_bytes() as u32,
+            );
+        }
+    }
+    boot_services.start_image(handle)
+}
+
+/// Tells the user why Secure Boot refused the image of `entry`.
+fn refuse(entry: &BootEntry, status: Status) -> Error {
+    let _ = gop::get().alert(
+        "Secure Boot verification failed",
+        &[&entry.title, &entry.path, secureboot::describe(status)],
+    );
+    Error::from(status)
+}
+
+/// The address of the firmware's system table, which `SystemTable` wraps
+/// transparently, for kernels that call boot or runtime services themselves.
+pub fn system_table_address() -> *const c_void {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref().unsafe_clone() };
+    unsafe { transmute::<SystemTable<Boot>, *const c_void>(system_table) }
+}
+
+/// The ACPI 2.0 RSDP if the firmware has one, else the ACPI 1.0 RSDP.
+pub fn acpi_rsdp() -> Option<u64> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let config_table = system_table.config_table();
+    [ACPI2_GUID, ACPI_GUID].iter().find_map(|guid| {
+        config_table
+            .iter()
+            .find(|entry| entry.guid == *guid)
+            .map(|entry| entry.address as u64)
+    })
+}
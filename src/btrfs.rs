@@ -0,0 +1,732 @@
+use super::{
+    bytes::{le_i64, le_u16, le_u32, le_u64},
+    vfs::{Disk, Kind, Metadata, NodeId, Subvolume, Volume, VolumeInfo},
+};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::cell::RefCell;
+use ruzstd::{io::Read, StreamingDecoder};
+use uefi::{Error, Result, Status};
+
+const SUPERBLOCK_OFFSET: u64 = 0x10000;
+const SUPERBLOCK_SIZE: usize = 4096;
+const MAGIC: &[u8; 8] = b"_BHRfS_M";
+
+/// What can be read knowing the trees this driver walks: zstd is decompressed
+/// like zlib, and the others only change how space is allocated and
+/// accounted for or how the trees are laid out.
+const INCOMPAT_SUPPORTED: u64 = 0x1 // MIXED_BACKREF
+    | 0x2 // DEFAULT_SUBVOL
+    | 0x4 // MIXED_GROUPS
+    | 0x10 // COMPRESS_ZSTD
+    | 0x20 // BIG_METADATA
+    | 0x40 // EXTENDED_IREF
+    | 0x80 // RAID56
+    | 0x100 // SKINNY_METADATA
+    | 0x200 // NO_HOLES
+    | 0x400 // METADATA_UUID
+    | 0x800 // RAID1C34
+    | 0x1000; // ZONED
+
+const ROOT_TREE_OBJECTID: u64 = 1;
+const ROOT_TREE_DIR_OBJECTID: u64 = 6;
+const FS_TREE_OBJECTID: u64 = 5;
+const FIRST_FREE_OBJECTID: u64 = 256;
+const FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+
+const INODE_ITEM: u8 = 1;
+const INODE_REF: u8 = 12;
+const DIR_ITEM: u8 = 84;
+const DIR_INDEX: u8 = 96;
+const EXTENT_DATA: u8 = 108;
+const ROOT_ITEM: u8 = 132;
+const ROOT_BACKREF: u8 = 144;
+const CHUNK_ITEM: u8 = 228;
+
+const HEADER_SIZE: usize = 101;
+const KEY_SIZE: usize = 17;
+const ITEM_SIZE: usize = KEY_SIZE + 8;
+const KEY_PTR_SIZE: usize = KEY_SIZE + 16;
+const CHUNK_SIZE: usize = 48;
+const STRIPE_SIZE: usize = 32;
+/// Chunks spread over several stripes rather than mirrored on each.
+const BLOCK_GROUP_STRIPED: u64 = 0x8 | 0x40 | 0x80 | 0x100;
+/// How deep a tree can be.
+const MAX_LEVEL: u8 = 7;
+
+const FILE_EXTENT_INLINE: u8 = 0;
+const FILE_EXTENT_PREALLOC: u8 = 2;
+const COMPRESS_NONE: u8 = 0;
+const COMPRESS_ZLIB: u8 = 1;
+const COMPRESS_ZSTD: u8 = 3;
+
+/// A read-only Btrfs file system on a single device, whose root is the
+/// top-level subvolume so that every subvolume and snapshot can be reached.
+pub struct Btrfs<D> {
+    disk: D,
+    superblock: Superblock,
+    chunks: Vec<Chunk>,
+    /// The root node and level of each tree looked up so far.
+    trees: RefCell<BTreeMap<u64, (u64, u8)>>,
+    nodes: RefCell<Nodes>,
+    /// The compressed extent decompressed last, since files are read in
+    /// pieces.
+    extent: RefCell<Option<(u64, Vec<u8>)>>,
+}
+
+impl<D: Disk> Btrfs<D> {
+    pub fn new(disk: D) -> Result<Self> {
+        let mut bytes = vec![0; SUPERBLOCK_SIZE];
+        disk.read(SUPERBLOCK_OFFSET, &mut bytes)?;
+        let superblock = Superblock::parse(&bytes)?;
+        let mut btrfs = Self {
+            disk,
+            chunks: parse_sys_chunk_array(&superblock.sys_chunk_array)?,
+            superblock,
+            trees: RefCell::new(BTreeMap::new()),
+            nodes: RefCell::new(Nodes::default()),
+            extent: RefCell::new(None),
+        };
+        let chunk_root = (
+            btrfs.superblock.chunk_root,
+            btrfs.superblock.chunk_root_level,
+        );
+        let first = Key::new(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM, 0);
+        let last = Key::new(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM, u64::MAX);
+        let chunks = btrfs
+            .search(chunk_root, first, last)?
+            .into_iter()
+            .map(|(key, data)| Chunk::parse(key.offset, &data).map(|(chunk, _)| chunk))
+            .collect::<Result<Vec<_>>>()?;
+        btrfs.chunks = chunks;
+        let root = (btrfs.superblock.root, btrfs.superblock.root_level);
+        btrfs.trees.get_mut().insert(ROOT_TREE_OBJECTID, root);
+        Ok(btrfs)
+    }
+
+    /// Reads from the logical address space, which chunks map to the disk.
+    fn read_logical(&self, address: u64, buffer: &mut [u8]) -> Result {
+        let mut done = 0;
+        while done < buffer.len() {
+            let address = address + done as u64;
+            let chunk = self
+                .chunks
+                .iter()
+                .find(|chunk| chunk.logical <= address && address < chunk.logical + chunk.length)
+                .ok_or_else(|| Error::from(Status::VOLUME_CORRUPTED))?;
+            let physical = chunk.physical(self.superblock.devid)?;
+            let within = address - chunk.logical;
+            let len = (chunk.length - within).min((buffer.len() - done) as u64) as usize;
+            self.disk
+                .read(physical + within, &mut buffer[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Collects the items of a tree from `first` to `last`, in order.
+    fn search(&self, root: (u64, u8), first: Key, last: Key) -> Result<Vec<(Key, Vec<u8>)>> {
+        let mut items = Vec::new();
+        self.search_node(root.0, root.1, &first, &last, &mut items)?;
+        Ok(items)
+    }
+
+    fn search_node(
+        &self,
+        address: u64,
+        level: u8,
+        first: &Key,
+        last: &Key,
+        items: &mut Vec<(Key, Vec<u8>)>,
+    ) -> Result {
+        let mut node = vec![0; self.superblock.node_size as usize];
+        self.read_logical(address, &mut node)?;
+        if level > MAX_LEVEL || node.get(0x64) != Some(&level) {
+            return Err(Error::from(Status::VOLUME_CORRUPTED));
+        }
+        let count = le_u32(&node, 0x60).ok_or_else(corrupted)? as usize;
+        if level == 0 {
+            for i in 0..count {
+                let item = slice(&node, HEADER_SIZE + i * ITEM_SIZE, ITEM_SIZE)?;
+                let key = Key::parse(item).ok_or_else(corrupted)?;
+                if key < *first || key > *last {
+                    continue;
+                }
+                let offset = HEADER_SIZE + le_u32(item, KEY_SIZE).ok_or_else(corrupted)? as usize;
+                let size = le_u32(item, KEY_SIZE + 4).ok_or_else(corrupted)? as usize;
+                items.push((key, slice(&node, offset, size)?.to_vec()));
+            }
+            return Ok(());
+        }
+        let pointers = (0..count)
+            .map(|i| {
+                let pointer = slice(&node, HEADER_SIZE + i * KEY_PTR_SIZE, KEY_PTR_SIZE)?;
+                let key = Key::parse(pointer).ok_or_else(corrupted)?;
+                Ok((key, le_u64(pointer, KEY_SIZE).ok_or_else(corrupted)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (i, (key, child)) in pointers.iter().enumerate() {
+            let next = pointers.get(i + 1).map(|(key, _)| key);
+            if *key <= *last && next.map_or(true, |next| next > first) {
+                self.search_node(*child, level - 1, first, last, items)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The root node and level of a subvolume's tree, from its newest root
+    /// item.
+    fn tree(&self, id: u64) -> Result<(u64, u8)> {
+        if let Some(root) = self.trees.borrow().get(&id) {
+            return Ok(*root);
+        }
+        let root_tree = (self.superblock.root, self.superblock.root_level);
+        let first = Key::new(id, ROOT_ITEM, 0);
+        let last = Key::new(id, ROOT_ITEM, u64::MAX);
+        let (_, item) = self
+            .search(root_tree, first, last)?
+            .pop()
+            .ok_or_else(|| Error::from(Status::NOT_FOUND))?;
+        let address = le_u64(&item, 0xb0).ok_or_else(corrupted)?;
+        let root = (address, *item.get(0xee).unwrap_or(&0));
+        self.trees.borrow_mut().insert(id, root);
+        Ok(root)
+    }
+
+    fn item(&self, tree: u64, key: Key) -> Result<Vec<u8>> {
+        let (_, item) = self
+            .search(self.tree(tree)?, key, key)?
+            .pop()
+            .ok_or_else(|| Error::from(Status::NOT_FOUND))?;
+        Ok(item)
+    }
+
+    /// The subvolume Linux mounts when not told which.
+    fn default_subvolume(&self) -> Result<u64> {
+        let first = Key::new(ROOT_TREE_DIR_OBJECTID, DIR_ITEM, 0);
+        let last = Key::new(ROOT_TREE_DIR_OBJECTID, DIR_ITEM, u64::MAX);
+        let items = self.search(self.tree(ROOT_TREE_OBJECTID)?, first, last)?;
+        let default = items.iter().find_map(|(_, item)| {
+            let entry = DirEntry::parse(item)?;
+            (entry.name == b"default").then_some(entry.location.objectid)
+        });
+        Ok(default.unwrap_or(FS_TREE_OBJECTID))
+    }
+
+    /// The path of a directory from the root of its subvolume.
+    fn dir_path(&self, tree: u64, mut inode: u64) -> Result<String> {
+        let mut names = Vec::new();
+        while inode != FIRST_FREE_OBJECTID {
+            let first = Key::new(inode, INODE_REF, 0);
+            let last = Key::new(inode, INODE_REF, u64::MAX);
+            let (key, item) = self
+                .search(self.tree(tree)?, first, last)?
+                .pop()
+                .ok_or_else(|| Error::from(Status::VOLUME_CORRUPTED))?;
+            let len = le_u16(&item, 8).ok_or_else(corrupted)? as usize;
+            names.push(String::from_utf8_lossy(slice(&item, 10, len)?).into_owned());
+            if names.len() > 4096 {
+                return Err(Error::from(Status::VOLUME_CORRUPTED));
+            }
+            inode = key.offset;
+        }
+        names.reverse();
+        Ok(names.join("/"))
+    }
+
+    /// Decompresses an extent, keeping it for the next read.
+    fn decompressed(&self, address: u64, size: u64, compression: u8, len: u64) -> Result<Vec<u8>> {
+        if let Some((cached, data)) = &*self.extent.borrow() {
+            if *cached == address {
+                return Ok(data.clone());
+            }
+        }
+        let mut compressed = vec![0; size as usize];
+        self.read_logical(address, &mut compressed)?;
+        let data = decompress(compression, &compressed, len as usize)?;
+        *self.extent.borrow_mut() = Some((address, data.clone()));
+        Ok(data)
+    }
+}
+
+impl<D: Disk> Volume for Btrfs<D> {
+    fn root(&self) -> NodeId {
+        self.nodes
+            .borrow_mut()
+            .intern(FS_TREE_OBJECTID, FIRST_FREE_OBJECTID)
+    }
+
+    fn metadata(&self, node: NodeId) -> Result<Metadata> {
+        let (tree, inode) = self.nodes.borrow().get(node)?;
+        let item = self.item(tree, Key::new(inode, INODE_ITEM, 0))?;
+        let time_at = |offset| le_i64(&item, offset).ok_or_else(corrupted);
+        Ok(Metadata {
+            kind: match le_u32(&item, 0x34).ok_or_else(corrupted)? & 0xf000 {
+                0x4000 => Kind::Directory,
+                0xa000 => Kind::Symlink,
+                _ => Kind::File,
+            },
+            size: le_u64(&item, 0x10).ok_or_else(corrupted)?,
+            accessed: time_at(0x70)?,
+            changed: time_at(0x7c)?,
+            modified: time_at(0x88)?,
+        })
+    }
+
+    /// Lists a directory in the order of its index, where subvolumes and
+    /// snapshots show as directories holding their root.
+    fn read_dir(&self, node: NodeId) -> Result<Vec<(String, NodeId)>> {
+        let (tree, inode) = self.nodes.borrow().get(node)?;
+        let first = Key::new(inode, DIR_INDEX, 0);
+        let last = Key::new(inode, DIR_INDEX, u64::MAX);
+        let items = self.search(self.tree(tree)?, first, last)?;
+        let mut nodes = self.nodes.borrow_mut();
+        let entries = items
+            .iter()
+            .filter_map(|(_, item)| DirEntry::parse(item))
+            .map(|entry| {
+                let name = String::from_utf8_lossy(&entry.name).into_owned();
+                let node = match entry.location.kind {
+                    ROOT_ITEM => nodes.intern(entry.location.objectid, FIRST_FREE_OBJECTID),
+                    _ => nodes.intern(tree, entry.location.objectid),
+                };
+                (name, node)
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn read(&self, node: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        let size = self.metadata(node)?.size;
+        let (tree, inode) = self.nodes.borrow().get(node)?;
+        let len = (buffer.len() as u64).min(size.saturating_sub(offset)) as usize;
+        let buffer = &mut buffer[..len];
+        if len == 0 {
+            return Ok(0);
+        }
+        buffer.fill(0);
+        let end = offset + len as u64;
+        let first = Key::new(inode, EXTENT_DATA, 0);
+        let last = Key::new(inode, EXTENT_DATA, end - 1);
+        for (key, item) in self.search(self.tree(tree)?, first, last)? {
+            let extent = FileExtent::parse(&item)?;
+            let start = key.offset;
+            let (from, to) = (start.max(offset), (start + extent.len).min(end));
+            if from >= to {
+                continue;
+            }
+            let output = &mut buffer[(from - offset) as usize..(to - offset) as usize];
+            let skip = extent.offset + from - start;
+            let data = match extent.data {
+                ExtentData::Inline(data) if extent.compression == COMPRESS_NONE => data,
+                ExtentData::Inline(data) => {
+                    decompress(extent.compression, &data, extent.ram_bytes as usize)?
+                }
+                ExtentData::Hole => continue,
+                ExtentData::Disk { address, .. } if extent.compression == COMPRESS_NONE => {
+                    self.read_logical(address + skip, output)?;
+                    continue;
+                }
+                ExtentData::Disk { address, size } => {
+                    self.decompressed(address, size, extent.compression, extent.ram_bytes)?
+                }
+            };
+            let data = data.get(skip as usize..).unwrap_or_default();
+            let len = data.len().min(output.len());
+            output[..len].copy_from_slice(&data[..len]);
+        }
+        Ok(len)
+    }
+
+    fn info(&self) -> VolumeInfo {
+        VolumeInfo {
+            label: self.superblock.label.clone(),
+            uuid: self.superblock.fsid,
+            size: self.superblock.total_bytes,
+            free_space: self
+                .superblock
+                .total_bytes
+                .saturating_sub(self.superblock.bytes_used),
+            block_size: self.superblock.sector_size,
+        }
+    }
+
+    /// Walks the back references of every subvolume up to the top level.
+    fn subvolumes(&self) -> Vec<Subvolume> {
+        let default = self.default_subvolume().unwrap_or(FS_TREE_OBJECTID);
+        let root_tree = (self.superblock.root, self.superblock.root_level);
+        let first = Key::new(FIRST_FREE_OBJECTID, ROOT_BACKREF, 0);
+        let last = Key::new(u64::MAX, ROOT_BACKREF, u64::MAX);
+        let items = self.search(root_tree, first, last).unwrap_or_default();
+        let parents = items
+            .iter()
+            .filter(|(key, _)| key.kind == ROOT_BACKREF)
+            .filter_map(|(key, item)| {
+                let dir = le_u64(item, 0)?;
+                let name = slice(item, 18, le_u16(item, 16)? as usize).ok()?;
+                let name = String::from_utf8_lossy(name).into_owned();
+                Some((key.objectid, (key.offset, dir, name)))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let path = |mut id| {
+            let mut components = Vec::new();
+            while let Some((parent, dir, name)) = parents.get(&id) {
+                components.push(name.clone());
+                let dir = self.dir_path(*parent, *dir).ok()?;
+                if !dir.is_empty() {
+                    components.push(dir);
+                }
+                id = *parent;
+                if components.len() > 4096 {
+                    return None;
+                }
+            }
+            components.reverse();
+            (id == FS_TREE_OBJECTID).then(|| components.join("/"))
+        };
+        let mut subvolumes = Vec::from([Subvolume {
+            id: FS_TREE_OBJECTID,
+            path: String::new(),
+            is_default: default == FS_TREE_OBJECTID,
+            is_snapshot: false,
+            created: 0,
+        }]);
+        for &id in parents.keys() {
+            let path = match path(id) {
+                Some(path) => path,
+                None => continue,
+            };
+            let first = Key::new(id, ROOT_ITEM, 0);
+            let last = Key::new(id, ROOT_ITEM, u64::MAX);
+            let item = match self
+                .search(root_tree, first, last)
+                .map(|mut items| items.pop())
+            {
+                Ok(Some((_, item))) => item,
+                _ => continue,
+            };
+            let parent_uuid = item.get(0x107..0x117).unwrap_or_default();
+            subvolumes.push(Subvolume {
+                id,
+                path,
+                is_default: default == id,
+                is_snapshot: parent_uuid.iter().any(|&byte| byte != 0),
+                created: le_i64(&item, 0x153).unwrap_or_default(),
+            });
+        }
+        subvolumes
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Superblock {
+    fsid: [u8; 16],
+    root: u64,
+    chunk_root: u64,
+    total_bytes: u64,
+    bytes_used: u64,
+    sector_size: u32,
+    node_size: u32,
+    root_level: u8,
+    chunk_root_level: u8,
+    devid: u64,
+    label: String,
+    sys_chunk_array: Vec<u8>,
+}
+
+impl Superblock {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.get(0x40..0x48) != Some(MAGIC) {
+            return Err(Error::from(Status::UNSUPPORTED));
+        }
+        let incompat = le_u64(bytes, 0xbc).ok_or_else(corrupted)?;
+        if le_u64(bytes, 0x88) != Some(1) || incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Error::from(Status::UNSUPPORTED));
+        }
+        let superblock = Self::read(bytes).ok_or_else(corrupted)?;
+        if !(HEADER_SIZE as u32 + 1..=0x10000).contains(&superblock.node_size) {
+            return Err(corrupted());
+        }
+        Ok(superblock)
+    }
+
+    fn read(bytes: &[u8]) -> Option<Self> {
+        let sys_chunk_array_size = (le_u32(bytes, 0xa0)? as usize).min(2048);
+        let label = bytes.get(0x12b..0x22b)?;
+        let label = &label[..label.iter().position(|&c| c == 0).unwrap_or(label.len())];
+        Some(Self {
+            fsid: bytes.get(0x20..0x30)?.try_into().ok()?,
+            root: le_u64(bytes, 0x50)?,
+            chunk_root: le_u64(bytes, 0x58)?,
+            total_bytes: le_u64(bytes, 0x70)?,
+            bytes_used: le_u64(bytes, 0x78)?,
+            sector_size: le_u32(bytes, 0x90)?,
+            node_size: le_u32(bytes, 0x94)?,
+            root_level: *bytes.get(0xc6)?,
+            chunk_root_level: *bytes.get(0xc7)?,
+            devid: le_u64(bytes, 0xc9)?,
+            label: String::from_utf8_lossy(label).into_owned(),
+            sys_chunk_array: bytes.get(0x32b..0x32b + sys_chunk_array_size)?.to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    objectid: u64,
+    kind: u8,
+    offset: u64,
+}
+
+impl Key {
+    fn new(objectid: u64, kind: u8, offset: u64) -> Self {
+        Self {
+            objectid,
+            kind,
+            offset,
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        Some(Self::new(
+            le_u64(bytes, 0)?,
+            *bytes.get(8)?,
+            le_u64(bytes, 9)?,
+        ))
+    }
+}
+
+/// Where a range of the logical address space lies on the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Chunk {
+    logical: u64,
+    length: u64,
+    kind: u64,
+    /// The device and offset of each stripe.
+    stripes: Vec<(u64, u64)>,
+}
+
+impl Chunk {
+    /// Parses a chunk item, returning it and its size.
+    fn parse(logical: u64, bytes: &[u8]) -> Result<(Self, usize)> {
+        let header = slice(bytes, 0, CHUNK_SIZE)?;
+        let count = le_u16(header, 0x2c).ok_or_else(corrupted)? as usize;
+        let stripes = slice(bytes, CHUNK_SIZE, count * STRIPE_SIZE)?
+            .chunks_exact(STRIPE_SIZE)
+            .map(|stripe| Some((le_u64(stripe, 0)?, le_u64(stripe, 8)?)))
+            .collect::<Option<_>>()
+            .ok_or_else(corrupted)?;
+        let chunk = Self {
+            logical,
+            length: le_u64(header, 0).ok_or_else(corrupted)?,
+            kind: le_u64(header, 0x18).ok_or_else(corrupted)?,
+            stripes,
+        };
+        Ok((chunk, CHUNK_SIZE + count * STRIPE_SIZE))
+    }
+
+    /// The offset of the chunk on the device, from any copy of it there.
+    fn physical(&self, devid: u64) -> Result<u64> {
+        if self.kind & BLOCK_GROUP_STRIPED != 0 && self.stripes.len() > 1 {
+            return Err(Error::from(Status::UNSUPPORTED));
+        }
+        self.stripes
+            .iter()
+            .find(|(device, _)| *device == devid)
+            .map(|(_, offset)| *offset)
+            .ok_or_else(|| Error::from(Status::NOT_FOUND))
+    }
+}
+
+/// The chunks holding the chunk tree, which the superblock keeps a copy of.
+fn parse_sys_chunk_array(mut bytes: &[u8]) -> Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    while !bytes.is_empty() {
+        let key = Key::parse(bytes).ok_or_else(corrupted)?;
+        if key.kind != CHUNK_ITEM {
+            return Err(corrupted());
+        }
+        let (chunk, size) = Chunk::parse(key.offset, &bytes[KEY_SIZE..])?;
+        chunks.push(chunk);
+        bytes = &bytes[KEY_SIZE + size..];
+    }
+    Ok(chunks)
+}
+
+struct DirEntry {
+    location: Key,
+    name: Vec<u8>,
+}
+
+impl DirEntry {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let header = bytes.get(..30)?;
+        let len = le_u16(header, 0x1b)? as usize;
+        Some(Self {
+            location: Key::parse(header)?,
+            name: bytes.get(30..30 + len)?.to_vec(),
+        })
+    }
+}
+
+enum ExtentData {
+    Inline(Vec<u8>),
+    Hole,
+    Disk { address: u64, size: u64 },
+}
+
+/// A file extent item, covering `len` bytes of the file from `offset` in
+/// the extent.
+struct FileExtent {
+    ram_bytes: u64,
+    compression: u8,
+    data: ExtentData,
+    offset: u64,
+    len: u64,
+}
+
+impl FileExtent {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        slice(bytes, 0, 0x15)?;
+        let ram_bytes = le_u64(bytes, 0x8).ok_or_else(corrupted)?;
+        let compression = bytes[0x10];
+        if bytes[0x14] == FILE_EXTENT_INLINE {
+            return Ok(Self {
+                ram_bytes,
+                compression,
+                data: ExtentData::Inline(bytes[0x15..].to_vec()),
+                offset: 0,
+                len: ram_bytes,
+            });
+        }
+        let extent = slice(bytes, 0, 0x35)?;
+        let u64_at = |offset| le_u64(extent, offset).ok_or_else(corrupted);
+        let address = u64_at(0x15)?;
+        Ok(Self {
+            ram_bytes,
+            compression,
+            data: match address == 0 || extent[0x14] == FILE_EXTENT_PREALLOC {
+                true => ExtentData::Hole,
+                false => ExtentData::Disk {
+                    address,
+                    size: u64_at(0x1d)?,
+                },
+            },
+            offset: u64_at(0x25)?,
+            len: u64_at(0x2d)?,
+        })
+    }
+}
+
+fn decompress(compression: u8, data: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut output = match compression {
+        COMPRESS_ZLIB => miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(data, len)
+            .map_err(|_| Error::from(Status::VOLUME_CORRUPTED))?,
+        COMPRESS_ZSTD => {
+            let mut source = data;
+            let mut decoder = StreamingDecoder::new(&mut source)
+                .map_err(|_| Error::from(Status::VOLUME_CORRUPTED))?;
+            let mut output = vec![0; len];
+            let mut done = 0;
+            while done < len {
+                match decoder.read(&mut output[done..]) {
+                    Ok(0) => break,
+                    Ok(read) => done += read,
+                    Err(_) => return Err(Error::from(Status::VOLUME_CORRUPTED)),
+                }
+            }
+            output
+        }
+        _ => return Err(Error::from(Status::UNSUPPORTED)),
+    };
+    output.resize(len, 0);
+    Ok(output)
+}
+
+/// Gives every inode of every subvolume tree its own id.
+#[derive(Default)]
+struct Nodes {
+    inodes: Vec<(u64, u64)>,
+    ids: BTreeMap<(u64, u64), NodeId>,
+}
+
+impl Nodes {
+    fn intern(&mut self, tree: u64, inode: u64) -> NodeId {
+        let inodes = &mut self.inodes;
+        *self.ids.entry((tree, inode)).or_insert_with(|| {
+            inodes.push((tree, inode));
+            inodes.len() as NodeId - 1
+        })
+    }
+
+    fn get(&self, node: NodeId) -> Result<(u64, u64)> {
+        let inode = self.inodes.get(node as usize);
+        inode.copied().ok_or_else(|| Error::from(Status::NOT_FOUND))
+    }
+}
+
+fn slice(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    bytes.get(offset..offset + len).ok_or_else(corrupted)
+}
+
+fn corrupted() -> Error {
+    Error::from(Status::VOLUME_CORRUPTED)
+}
+
+#[test_case]
+fn items() {
+    let mut array = Vec::new();
+    array.extend(FIRST_CHUNK_TREE_OBJECTID.to_le_bytes());
+    array.push(CHUNK_ITEM);
+    array.extend(0x100000u64.to_le_bytes());
+    let mut chunk = [0; CHUNK_SIZE + STRIPE_SIZE];
+    chunk[..8].copy_from_slice(&0x400000u64.to_le_bytes());
+    chunk[0x18] = 0x22;
+    chunk[0x2c] = 1;
+    chunk[CHUNK_SIZE] = 1;
+    chunk[CHUNK_SIZE + 8..CHUNK_SIZE + 16].copy_from_slice(&0x500000u64.to_le_bytes());
+    array.extend(chunk);
+    let chunks = parse_sys_chunk_array(&array).expect("parse_sys_chunk_array failed");
+    assert_eq!(chunks.len(), 1);
+    assert_eq!((chunks[0].logical, chunks[0].length), (0x100000, 0x400000));
+    assert_eq!(chunks[0].physical(1).ok(), Some(0x500000));
+    assert!(chunks[0].physical(2).is_err());
+
+    let mut extent = [0; 0x35];
+    extent[0x8] = 0x10;
+    extent[0x10] = COMPRESS_ZLIB;
+    extent[0x14] = 1;
+    extent[0x15] = 0x20;
+    extent[0x1d] = 0x8;
+    extent[0x25] = 0x4;
+    extent[0x2d] = 0xc;
+    let extent = FileExtent::parse(&extent).expect("FileExtent::parse failed");
+    assert!(matches!(
+        extent.data,
+        ExtentData::Disk {
+            address: 0x20,
+            size: 0x8
+        }
+    ));
+    assert_eq!((extent.offset, extent.len), (0x4, 0xc));
+
+    let zlib = [
+        0x78, 0x9c, 0x4b, 0x4c, 0x4a, 0x06, 0x00, 0x02, 0x4d, 0x01, 0x27,
+    ];
+    assert_eq!(
+        decompress(COMPRESS_ZLIB, &zlib, 4).ok(),
+        Some(b"abc\0".to_vec())
+    );
+    let zstd = [
+        0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x58, 0x19, 0x00, 0x00, 0x61, 0x62, 0x63,
+    ];
+    assert_eq!(
+        decompress(COMPRESS_ZSTD, &zstd, 3).ok(),
+        Some(b"abc".to_vec())
+    );
+
+    let mut nodes = Nodes::default();
+    assert_eq!(nodes.intern(5, 256), 0);
+    assert_eq!(nodes.intern(257, 256), 1);
+    assert_eq!(nodes.intern(5, 256), 0);
+    assert_eq!(nodes.get(1).ok(), Some((257, 256)));
+}
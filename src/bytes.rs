@@ -0,0 +1,34 @@
+/// The little-endian `u16` at `offset`, or `None` past the end of `bytes`.
+pub fn le_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    array(bytes, offset).map(u16::from_le_bytes)
+}
+
+/// The little-endian `u32` at `offset`, or `None` past the end of `bytes`.
+pub fn le_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    array(bytes, offset).map(u32::from_le_bytes)
+}
+
+/// The little-endian `u64` at `offset`, or `None` past the end of `bytes`.
+pub fn le_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    array(bytes, offset).map(u64::from_le_bytes)
+}
+
+/// The little-endian `i64` at `offset`, or `None` past the end of `bytes`.
+pub fn le_i64(bytes: &[u8], offset: usize) -> Option<i64> {
+    array(bytes, offset).map(i64::from_le_bytes)
+}
+
+fn array<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    bytes.get(offset..offset.checked_add(N)?)?.try_into().ok()
+}
+
+#[test_case]
+fn little_endian() {
+    let bytes = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+    assert_eq!(le_u16(&bytes, 7), Some(0x0908));
+    assert_eq!(le_u32(&bytes, 1), Some(0x0504_0302));
+    assert_eq!(le_u64(&bytes, 0), Some(0x0807_0605_0403_0201));
+    assert_eq!(le_i64(&[0xff; 8], 0), Some(-1));
+    assert_eq!(le_u16(&bytes, 8), None);
+    assert_eq!(le_u64(&bytes, usize::MAX), None);
+}
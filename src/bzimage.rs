@@ -0,0 +1,355 @@
+use super::{
+    boot,
+    cfg::BootEntry,
+    gop::{self, FrameBufferInfo},
+    map::{self, E820Entry, PAGE_SIZE},
+};
+use alloc::vec::Vec;
+use core::{
+    arch::asm,
+    ffi::c_void,
+    mem::{size_of, size_of_val, transmute, zeroed},
+    ptr,
+};
+use uefi::{
+    prelude::*,
+    table::boot::{AllocateType, MemoryType},
+    Error, Result,
+};
+
+const SETUP_HEADER_OFFSET: usize = 0x1f1;
+const BOOT_FLAG: u16 = 0xaa55;
+const HDRS_MAGIC: u32 = 0x5372_6448;
+const MIN_VERSION: u16 = 0x020c;
+
+const XLF_KERNEL_64: u16 = 1 << 0;
+const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
+const XLF_EFI_HANDOVER_64: u16 = 1 << 3;
+
+const LOADER_TYPE_UNDEFINED: u8 = 0xff;
+const VIDEO_TYPE_EFI: u8 = 0x70;
+const VIDEO_CAPABILITY_64BIT_BASE: u32 = 1 << 1;
+const EFI64_LOADER_SIGNATURE: u32 = u32::from_le_bytes(*b"EL64");
+
+const STARTUP_64_OFFSET: u64 = 0x200;
+
+/// Boots `entry` with the x86 boot protocol, either through the 64-bit entry
+/// point after `ExitBootServices` or through the EFI handover entry point.
+pub fn boot(image_handle: Handle, entry: &BootEntry, handover: bool) -> Result {
+    let image = boot::load_kernel(image_handle, entry)?;
+    let header = SetupHeader::from_image(&image).ok_or_else(|| Error::from(Status::LOAD_ERROR))?;
+    let xloadflags = header.xloadflags;
+    if handover && xloadflags & XLF_EFI_HANDOVER_64 == 0 {
+        return Err(Error::from(Status::UNSUPPORTED));
+    }
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let setup_sects = match header.setup_sects {
+        0 => 4,
+        setup_sects => setup_sects as usize,
+    };
+    let kernel = image
+        .get((setup_sects + 1) * 512..)
+        .ok_or_else(|| Error::from(Status::LOAD_ERROR))?;
+    let kernel_addr = allocate_kernel(boot_services, &header, kernel.len())?;
+    unsafe { ptr::copy_nonoverlapping(kernel.as_ptr(), kernel_addr as *mut u8, kernel.len()) };
+    let below_4g = AllocateType::MaxAddress(u32::MAX as usize);
+    let boot_params = allocate(boot_services, below_4g, size_of::<BootParams>())?;
+    let boot_params = unsafe {
+        let boot_params = boot_params as *mut BootParams;
+        boot_params.write_bytes(0, 1);
+        &mut *boot_params
+    };
+    let header_end =
+        (0x202 + image[0x201] as usize).min(SETUP_HEADER_OFFSET + size_of::<SetupHeader>());
+    let header = &image[SETUP_HEADER_OFFSET..header_end];
+    unsafe {
+        ptr::copy_nonoverlapping(
+            header.as_ptr(),
+            &mut boot_params.hdr as *mut SetupHeader as *mut u8,
+            header.len(),
+        );
+    }
+    boot_params.hdr.type_of_loader = LOADER_TYPE_UNDEFINED;
+    boot_params.hdr.code32_start =
+        u32::try_from(kernel_addr).map_err(|_| Error::from(Status::LOAD_ERROR))?;
+    let cmdline = entry.options.as_bytes();
+    let cmdline = &cmdline[..cmdline.len().min(boot_params.hdr.cmdline_size as usize)];
+    let cmd_line_ptr = allocate(boot_services, below_4g, cmdline.len() + 1)?;
+    unsafe {
+        let cmd_line_ptr = cmd_line_ptr as *mut u8;
+        ptr::copy_nonoverlapping(cmdline.as_ptr(), cmd_line_ptr, cmdline.len());
+        cmd_line_ptr.add(cmdline.len()).write(0);
+    }
+    boot_params.hdr.cmd_line_ptr = cmd_line_ptr as u32;
+    let mut initrd = Vec::new();
+    for path in &entry.initrd {
+        initrd.extend(boot::load_initrd(image_handle, entry, path)?);
+        initrd.resize((initrd.len() + 3) & !3, 0);
+    }
+    if !initrd.is_empty() {
+        let initrd_addr_max = match xloadflags & XLF_CAN_BE_LOADED_ABOVE_4G {
+            0 => boot_params.hdr.initrd_addr_max as usize,
+            _ => usize::MAX,
+        };
+        let ramdisk = allocate(
+            boot_services,
+            AllocateType::MaxAddress(initrd_addr_max),
+            initrd.len(),
+        )?;
+        unsafe { ptr::copy_nonoverlapping(initrd.as_ptr(), ramdisk as *mut u8, initrd.len()) };
+        boot_params.hdr.ramdisk_image = ramdisk as u32;
+        boot_params.hdr.ramdisk_size = initrd.len() as u32;
+        boot_params.ext_ramdisk_image = (ramdisk >> 32) as u32;
+        boot_params.ext_ramdisk_size = (initrd.len() as u64 >> 32) as u32;
+    }
+    boot_params.screen_info = ScreenInfo::from(FrameBufferInfo::try_from(gop::get())?);
+    if handover {
+        let entry = kernel_addr + STARTUP_64_OFFSET + boot_params.hdr.handover_offset as u64;
+        let handover = unsafe {
+            transmute::<_, extern "sysv64" fn(Handle, *const c_void, *mut BootParams) -> !>(
+                entry as *const c_void,
+            )
+        };
+        unsafe { asm!("cli") };
+        handover(image_handle, boot::system_table_address(), boot_params);
+    }
+    let (system_table, memory_map) = map::exit_boot_services(image_handle)?;
+    boot_params.e820_entries = memory_map
+        .to_e820(&mut boot_params.e820_table)
+        .ok_or_else(|| Error::from(Status::BUFFER_TOO_SMALL))? as u8;
+    let (efi_memmap, efi_memdesc_size, efi_memdesc_version) = memory_map.efi_memory_map();
+    let efi_systab = system_table.get_current_system_table_addr();
+    let efi_memmap_size = efi_memmap.len();
+    let efi_memmap = efi_memmap.as_ptr() as u64;
+    boot_params.efi_info = EfiInfo {
+        efi_loader_signature: EFI64_LOADER_SIGNATURE,
+        efi_systab: efi_systab as u32,
+        efi_memdesc_size: efi_memdesc_size as u32,
+        efi_memdesc_version,
+        efi_memmap: efi_memmap as u32,
+        efi_memmap_size: efi_memmap_size as u32,
+        efi_systab_hi: (efi_systab >> 32) as u32,
+        efi_memmap_hi: (efi_memmap >> 32) as u32,
+    };
+    unsafe { startup_64(kernel_addr + STARTUP_64_OFFSET, boot_params) }
+}
+
+fn allocate(boot_services: &BootServices, ty: AllocateType, size: usize) -> Result<u64> {
+    let count = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    boot_services.allocate_pages(ty, MemoryType::LOADER_DATA, count)
+}
+
+fn allocate_kernel(boot_services: &BootServices, header: &SetupHeader, len: usize) -> Result<u64> {
+    let size = (header.init_size as usize).max(len);
+    let pref_address = AllocateType::Address(header.pref_address as usize);
+    if let Ok(addr) = allocate(boot_services, pref_address, size) {
+        return Ok(addr);
+    }
+    if header.relocatable_kernel == 0 {
+        return Err(Error::from(Status::OUT_OF_RESOURCES));
+    }
+    let align = (header.kernel_alignment as u64).max(PAGE_SIZE);
+    let below_4g = AllocateType::MaxAddress(u32::MAX as usize);
+    let addr = allocate(boot_services, below_4g, size + align as usize)?;
+    Ok((addr + align - 1) & !(align - 1))
+}
+
+/// Jumps to the 64-bit entry point with `__BOOT_CS` (0x10) and `__BOOT_DS`
+/// (0x18) loaded, since the firmware's GDT layout is unspecified.
+unsafe fn startup_64(entry: u64, boot_params: &mut BootParams) -> ! {
+    static GDT: [u64; 4] = [0, 0, 0x00af_9a00_0000_ffff, 0x00cf_9200_0000_ffff];
+    let gdtr = DescriptorTablePointer {
+        limit: size_of_val(&GDT) as u16 - 1,
+        base: GDT.as_ptr() as u64,
+    };
+    asm!(
+        "cli",
+        "lgdt [rdx]",
+        "push 0x10",
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "retfq",
+        "2:",
+        "mov ax, 0x18",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov ss, ax",
+        "jmp rdi",
+        in("rax") 0,
+        in("rdx") &gdtr,
+        in("rdi") entry,
+        in("rsi") boot_params,
+        options(noreturn),
+    )
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SetupHeader {
+    pub setup_sects: u8,
+    pub root_flags: u16,
+    pub syssize: u32,
+    pub ram_size: u16,
+    pub vid_mode: u16,
+    pub root_dev: u16,
+    pub boot_flag: u16,
+    pub jump: u16,
+    pub header: u32,
+    pub version: u16,
+    pub realmode_swtch: u32,
+    pub start_sys_seg: u16,
+    pub kernel_version: u16,
+    pub type_of_loader: u8,
+    pub loadflags: u8,
+    pub setup_move_size: u16,
+    pub code32_start: u32,
+    pub ramdisk_image: u32,
+    pub ramdisk_size: u32,
+    pub bootsect_kludge: u32,
+    pub heap_end_ptr: u16,
+    pub ext_loader_ver: u8,
+    pub ext_loader_type: u8,
+    pub cmd_line_ptr: u32,
+    pub initrd_addr_max: u32,
+    pub kernel_alignment: u32,
+    pub relocatable_kernel: u8,
+    pub min_alignment: u8,
+    pub xloadflags: u16,
+    pub cmdline_size: u32,
+    pub hardware_subarch: u32,
+    pub hardware_subarch_data: u64,
+    pub payload_offset: u32,
+    pub payload_length: u32,
+    pub setup_data: u64,
+    pub pref_address: u64,
+    pub init_size: u32,
+    pub handover_offset: u32,
+    pub kernel_info_offset: u32,
+}
+
+impl SetupHeader {
+    /// Reads the setup header of a bzImage that supports the 64-bit entry.
+    pub fn from_image(image: &[u8]) -> Option<Self> {
+        let header = image.get(SETUP_HEADER_OFFSET..SETUP_HEADER_OFFSET + size_of::<Self>())?;
+        let header = unsafe { header.as_ptr().cast::<Self>().read_unaligned() };
+        let (boot_flag, magic, version) = (header.boot_flag, header.header, header.version);
+        match boot_flag == BOOT_FLAG
+            && magic == HDRS_MAGIC
+            && version >= MIN_VERSION
+            && header.xloadflags & XLF_KERNEL_64 != 0
+        {
+            false => None,
+            true => Some(header),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct ScreenInfo {
+    pub orig_x: u8,
+    pub orig_y: u8,
+    pub ext_mem_k: u16,
+    pub orig_video_page: u16,
+    pub orig_video_mode: u8,
+    pub orig_video_cols: u8,
+    pub flags: u8,
+    pub unused2: u8,
+    pub orig_video_ega_bx: u16,
+    pub unused3: u16,
+    pub orig_video_lines: u8,
+    pub orig_video_is_vga: u8,
+    pub orig_video_points: u16,
+    pub lfb_width: u16,
+    pub lfb_height: u16,
+    pub lfb_depth: u16,
+    pub lfb_base: u32,
+    pub lfb_size: u32,
+    pub cl_magic: u16,
+    pub cl_offset: u16,
+    pub lfb_linelength: u16,
+    pub red_size: u8,
+    pub red_pos: u8,
+    pub green_size: u8,
+    pub green_pos: u8,
+    pub blue_size: u8,
+    pub blue_pos: u8,
+    pub rsvd_size: u8,
+    pub rsvd_pos: u8,
+    pub vesapm_seg: u16,
+    pub vesapm_off: u16,
+    pub pages: u16,
+    pub vesa_attributes: u16,
+    pub capabilities: u32,
+    pub ext_lfb_base: u32,
+    pub _reserved: [u8; 2],
+}
+
+impl From<FrameBufferInfo> for ScreenInfo {
+    fn from(frame_buffer: FrameBufferInfo) -> Self {
+        let mut screen_info: Self = unsafe { zeroed() };
+        if frame_buffer.base == 0 {
+            return screen_info;
+        }
+        screen_info.orig_video_is_vga = VIDEO_TYPE_EFI;
+        screen_info.lfb_width = frame_buffer.width as u16;
+        screen_info.lfb_height = frame_buffer.height as u16;
+        screen_info.lfb_depth = frame_buffer.bpp as u16;
+        screen_info.lfb_base = frame_buffer.base as u32;
+        screen_info.ext_lfb_base = (frame_buffer.base >> 32) as u32;
+        screen_info.lfb_size = frame_buffer.size as u32;
+        screen_info.lfb_linelength = frame_buffer.pitch() as u16;
+        (screen_info.red_size, screen_info.red_pos) = gop::mask_size_shift(frame_buffer.red_mask);
+        (screen_info.green_size, screen_info.green_pos) =
+            gop::mask_size_shift(frame_buffer.green_mask);
+        (screen_info.blue_size, screen_info.blue_pos) =
+            gop::mask_size_shift(frame_buffer.blue_mask);
+        (screen_info.rsvd_size, screen_info.rsvd_pos) =
+            gop::mask_size_shift(frame_buffer.reserved_mask);
+        screen_info.capabilities = VIDEO_CAPABILITY_64BIT_BASE;
+        screen_info
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct EfiInfo {
+    pub efi_loader_signature: u32,
+    pub efi_systab: u32,
+    pub efi_memdesc_size: u32,
+    pub efi_memdesc_version: u32,
+    pub efi_memmap: u32,
+    pub efi_memmap_size: u32,
+    pub efi_systab_hi: u32,
+    pub efi_memmap_hi: u32,
+}
+
+/// The "zero page" handed to the kernel, see Documentation/x86/zero-page.rst.
+#[repr(C, packed)]
+pub struct BootParams {
+    pub screen_info: ScreenInfo,
+    _pad0: [u8; 0x80],
+    pub ext_ramdisk_image: u32,
+    pub ext_ramdisk_size: u32,
+    pub ext_cmd_line_ptr: u32,
+    _pad1: [u8; 0xf4],
+    pub efi_info: EfiInfo,
+    _pad2: [u8; 0x08],
+    pub e820_entries: u8,
+    _pad3: [u8; 0x08],
+    pub hdr: SetupHeader,
+    _pad4: [u8; 0x290 - SETUP_HEADER_OFFSET - size_of::<SetupHeader>()],
+    _edd_mbr_sig_buffer: [u32; 16],
+    pub e820_table: [E820Entry; 128],
+    _pad5: [u8; 0x330],
+}
+
+const _: () = assert!(size_of::<BootParams>() == 0x1000);
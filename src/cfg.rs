@@ -1,32 +1,147 @@
 use super::{
+    bls::Counter,
     fs::FileExt,
     gop::{Color, Resolution},
 };
-use alloc::{str, string::String};
+use alloc::{collections::BTreeMap, str, string::String, vec::Vec};
 use core::ops::{Deref, DerefMut};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use uefi::{proto::media::file::RegularFile, Error};
 
 pub const DEFAULT_LOGO: &[u8] = include_bytes!("boot.bmp");
 
+/// The `default_entry` that preselects the entry booted last.
+pub const SAVED: &str = "@saved";
+
 #[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ConfigData {
     pub background: Color,
     pub logo_path: String,
     pub resolution: Resolution,
+    #[serde(default)]
+    pub boot_entries: Vec<BootEntry>,
+    /// The id or title of the entry to preselect, or `@saved` for the one
+    /// booted last.
+    #[serde(default)]
+    pub default_entry: Option<String>,
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// A `tftp://` or `http(s)://` URL of a JSON document to merge over this
+    /// configuration at startup.
+    #[serde(default)]
+    pub remote_config: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BootEntry {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub kind: EntryKind,
+    /// A file on a volume, or a `tftp://` or `http(s)://` URL to download it
+    /// from, where a URL without a host uses the server DHCP named.
+    pub path: String,
+    #[serde(default)]
+    pub initrd: Vec<String>,
+    #[serde(default)]
+    pub options: String,
+    /// The expected SHA-256 digests in hex of the files the entry loads, by
+    /// their path as written in `path` and `initrd`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sha256: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub counter: Option<Counter>,
+}
+
+impl BootEntry {
+    /// Whether the entry ran out of boot counting tries.
+    pub fn is_bad(&self) -> bool {
+        self.counter.as_ref().map_or(false, Counter::is_bad)
+    }
+
+    /// The `id` that identifies the entry across boots, else its title.
+    pub fn id(&self) -> &str {
+        match self.id.is_empty() {
+            true => &self.title,
+            false => &self.id,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    #[default]
+    Efi,
+    Linux,
+    BootParams,
+    Handover,
+    Elf,
+    Multiboot2,
+    Limine,
+}
+
+/// Lays the document fetched from `remote_config` over `local`: objects are
+/// merged key by key and anything else replaces what was there. Nothing
+/// authenticates the document, so digests only come from `local`: its
+/// entries' `sha256` apply to any merged entry loading the same paths.
+pub fn merge(local: &ConfigData, remote: &[u8]) -> Option<ConfigData> {
+    let mut config = serde_json::to_value(local).ok()?;
+    let mut remote = serde_json::from_slice::<Value>(remote).ok()?;
+    let remote_entries = remote.get_mut("boot_entries").and_then(Value::as_array_mut);
+    for entry in remote_entries.into_iter().flatten() {
+        if let Some(entry) = entry.as_object_mut() {
+            entry.remove("sha256");
+        }
+    }
+    merge_value(&mut config, remote);
+    let mut config = serde_json::from_value::<ConfigData>(config).ok()?;
+    let digests = local
+        .boot_entries
+        .iter()
+        .flat_map(|entry| &entry.sha256)
+        .collect::<BTreeMap<_, _>>();
+    for entry in &mut config.boot_entries {
+        for path in [&entry.path].into_iter().chain(&entry.initrd) {
+            if let Some(&digest) = digests.get(path) {
+                entry.sha256.insert(path.clone(), digest.clone());
+            }
+        }
+    }
+    Some(config)
+}
+
+fn merge_value(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base) => merge_value(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
 }
 
 pub struct Config {
     config_data: ConfigData,
     config_file: RegularFile,
+    loaded: Option<ConfigData>,
 }
 
 impl Config {
     pub fn new(mut config_file: RegularFile) -> Result<Self, Error> {
+        let loaded = serde_json::from_slice::<ConfigData>(&config_file.load()?).ok();
         Ok(Self {
-            config_data: serde_json::from_slice::<ConfigData>(&config_file.load()?)
-                .unwrap_or_default(),
+            config_data: loaded.clone().unwrap_or_default(),
             config_file,
+            loaded,
         })
     }
 }
@@ -45,8 +160,13 @@ impl DerefMut for Config {
     }
 }
 
+/// Writes the configuration back only when it was missing, unreadable or
+/// changed, so that booting doesn't wear the volume.
 impl Drop for Config {
     fn drop(&mut self) {
+        if self.loaded.as_ref() == Some(&self.config_data) {
+            return;
+        }
         self.config_file
             .replace(
                 serde_json::to_string_pretty(&self.config_data)
@@ -56,3 +176,40 @@ impl Drop for Config {
             .expect("File::replace failed");
     }
 }
+
+#[test_case]
+fn remote_config() {
+    let mut local = ConfigData {
+        background: Color { r: 1, g: 2, b: 3 },
+        logo_path: String::from("\\EFI\\boot.bmp"),
+        timeout: Some(5),
+        remote_config: Some(String::from("http:///boot.json")),
+        ..ConfigData::default()
+    };
+    local.boot_entries.push(BootEntry {
+        title: String::from("Linux"),
+        path: String::from("tftp:///vmlinuz"),
+        sha256: BTreeMap::from([(String::from("tftp:///vmlinuz"), String::from("ab"))]),
+        ..BootEntry::default()
+    });
+    let remote = br#"{
+        "timeout": 1,
+        "background": { "r": 0 },
+        "boot_entries": [
+            { "title": "Linux", "kind": "linux", "path": "tftp:///vmlinuz" },
+            {
+                "title": "Other",
+                "path": "tftp:///other.efi",
+                "sha256": { "tftp:///other.efi": "cd", "tftp:///vmlinuz": "cd" }
+            }
+        ]
+    }"#;
+    let merged = merge(&local, remote).unwrap();
+    assert_eq!(merged.timeout, Some(1));
+    assert_eq!(merged.logo_path, local.logo_path);
+    assert_eq!(merged.background, Color { r: 0, g: 2, b: 3 });
+    assert_eq!(merged.boot_entries[0].path, "tftp:///vmlinuz");
+    assert_eq!(merged.boot_entries[0].sha256, local.boot_entries[0].sha256);
+    assert!(merged.boot_entries[1].sha256.is_empty());
+    assert_eq!(merge(&local, b"[1, 2]"), None);
+}
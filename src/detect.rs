@@ -0,0 +1,162 @@
+use super::{
+    bls::ARCHITECTURE,
+    cfg::{BootEntry, EntryKind},
+    fs::{self, BootServicesExt, FileSystem},
+};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use uefi::{
+    prelude::*,
+    proto::{
+        loaded_image::LoadedImage,
+        media::{
+            block::BlockIO,
+            file::{File, FileMode, FileSystemInfo},
+            fs::SimpleFileSystem,
+        },
+    },
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams},
+};
+
+/// Finds the loaders of other operating systems and tools on every volume,
+/// skipping the loader itself.
+pub fn entries(image_handle: Handle) -> Vec<BootEntry> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let own_device_path = boot_services
+        .open_protocol::<LoadedImage>(
+            OpenProtocolParams {
+                handle: image_handle,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+        .ok()
+        .and_then(|loaded_image| {
+            let device = unsafe { &*loaded_image.interface.get() }.device();
+            boot_services.get_device_path_text(image_handle, device)
+        });
+    let own_path = boot_services
+        .get_image_file_path(image_handle)
+        .map(|path| path.to_string())
+        .unwrap_or_default();
+    let mut entries = Vec::new();
+    for volume in fs::volumes() {
+        let file_system = match fs::get_volume(image_handle, volume) {
+            Ok(file_system) => file_system,
+            Err(_) => continue,
+        };
+        let device_path = match boot_services.get_device_path_text(image_handle, volume) {
+            Some(device_path) => device_path,
+            None => continue,
+        };
+        let is_own_volume = own_device_path.as_ref() == Some(&device_path);
+        let prefix = match is_own_volume {
+            true => String::new(),
+            false => device_path + ":",
+        };
+        let mut loaders = loaders();
+        if is_removable(image_handle, volume) {
+            let title = match volume_label(file_system) {
+                Some(label) => format!("Removable media ({label})"),
+                None => "Removable media".to_string(),
+            };
+            let path = format!("\\EFI\\BOOT\\BOOT{}.EFI", ARCHITECTURE.to_uppercase());
+            loaders.push(("auto-efi-default", title, path));
+        }
+        for (id, title, path) in loaders {
+            if is_own_volume && path.eq_ignore_ascii_case(&own_path) {
+                continue;
+            }
+            if file_system.open(&path, FileMode::Read).is_ok() {
+                entries.push(BootEntry {
+                    id: unique_id(&entries, id),
+                    title,
+                    kind: EntryKind::Efi,
+                    path: prefix.clone() + &path,
+                    ..BootEntry::default()
+                });
+            }
+        }
+    }
+    entries
+}
+
+/// `id`, numbered from 2 when a loader on another volume, or another copy on
+/// the same one, already has it.
+fn unique_id(entries: &[BootEntry], id: &str) -> String {
+    (1..)
+        .map(|number| match number {
+            1 => id.to_string(),
+            number => format!("{id}-{number}"),
+        })
+        .find(|candidate| entries.iter().all(|entry| entry.id != *candidate))
+        .expect("Ran out of entry ids")
+}
+
+/// The well-known loaders looked for on every volume, with their ids and
+/// titles.
+fn loaders() -> Vec<(&'static str, String, String)> {
+    [
+        (
+            "auto-windows",
+            "Windows Boot Manager",
+            "\\EFI\\Microsoft\\Boot\\bootmgfw.efi".to_string(),
+        ),
+        (
+            "auto-efi-shell",
+            "UEFI Shell",
+            format!("\\shell{ARCHITECTURE}.efi"),
+        ),
+        (
+            "auto-efi-shell",
+            "UEFI Shell",
+            format!("\\EFI\\tools\\shell{ARCHITECTURE}.efi"),
+        ),
+        (
+            "auto-memtest86",
+            "Memtest86",
+            format!("\\EFI\\memtest86\\memtest{ARCHITECTURE}.efi"),
+        ),
+        (
+            "auto-memtest86-plus",
+            "Memtest86+",
+            "\\EFI\\memtest86+\\memtest.efi".to_string(),
+        ),
+    ]
+    .into_iter()
+    .map(|(id, title, path)| (id, title.to_string(), path))
+    .collect()
+}
+
+fn is_removable(image_handle: Handle, volume: Handle) -> bool {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let block_io = system_table.boot_services().open_protocol::<BlockIO>(
+        OpenProtocolParams {
+            handle: volume,
+            agent: image_handle,
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    );
+    block_io.map_or(false, |block_io| {
+        unsafe { &*block_io.interface.get() }
+            .media()
+            .is_removable_media()
+    })
+}
+
+fn volume_label(file_system: &mut SimpleFileSystem) -> Option<String> {
+    let info = file_system
+        .open_volume()
+        .ok()?
+        .get_boxed_info::<FileSystemInfo>()
+        .ok()?;
+    let label = info.volume_label().to_string();
+    (!label.is_empty()).then_some(label)
+}
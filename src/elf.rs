@@ -0,0 +1,251 @@
+use super::map::{KERNEL_AND_MODULES, PAGE_SIZE};
+use core::{mem::size_of, ptr};
+use uefi::{prelude::*, table::boot::AllocateType, Error, Result};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+pub const EM_X86_64: u16 = 62;
+pub const PT_LOAD: u32 = 1;
+
+/// Kernels linked at or above this address, the top 2 GiB that the kernel
+/// code model addresses, are loaded wherever there is room and mapped to
+/// their virtual addresses, the rest are identity mapped.
+pub const HIGHER_HALF: u64 = 0xffff_ffff_8000_0000;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FileHeader {
+    pub ident: [u8; 16],
+    pub ty: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub ty: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+pub struct Elf<'a> {
+    image: &'a [u8],
+    header: FileHeader,
+}
+
+impl<'a> Elf<'a> {
+    /// Parses a little-endian ELF64 executable for `machine`, checking that
+    /// every program header and segment lies within `image`.
+    pub fn parse(image: &'a [u8], machine: u16) -> Option<Self> {
+        let header = read::<FileHeader>(image, 0)?;
+        if header.ident[..4] != ELF_MAGIC
+            || header.ident[4] != ELFCLASS64
+            || header.ident[5] != ELFDATA2LSB
+            || header.ty != ET_EXEC
+            || header.machine != machine
+            || (header.phentsize as usize) < size_of::<ProgramHeader>()
+        {
+            return None;
+        }
+        let elf = Self { image, header };
+        for index in 0..header.phnum as usize {
+            let program_header =
+                read::<ProgramHeader>(image, program_header_offset(&header, index)?)?;
+            let end = program_header.offset.checked_add(program_header.filesz)?;
+            if end > image.len() as u64 || program_header.filesz > program_header.memsz {
+                return None;
+            }
+        }
+        Some(elf)
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.header.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let (image, header) = (self.image, self.header);
+        (0..header.phnum as usize).filter_map(move |index| {
+            read::<ProgramHeader>(image, program_header_offset(&header, index)?)
+        })
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers()
+            .filter(|program_header| program_header.ty == PT_LOAD && program_header.memsz != 0)
+    }
+
+    /// Copies all `PT_LOAD` segments into one page-aligned allocation
+    /// spanning their virtual addresses, zeroing what the file leaves out.
+    pub fn load(&self, boot_services: &BootServices) -> Result<Kernel> {
+        let (virtual_base, size) = self.span(|segment| segment.vaddr)?;
+        let pages = (size / PAGE_SIZE) as usize;
+        let physical_base = match virtual_base < HIGHER_HALF {
+            true => virtual_base,
+            false => self.span(|segment| segment.paddr)?.0,
+        };
+        let address = AllocateType::Address(physical_base as usize);
+        let physical_base = match boot_services.allocate_pages(address, KERNEL_AND_MODULES, pages) {
+            Ok(physical_base) => physical_base,
+            Err(err) if virtual_base < HIGHER_HALF => return Err(err),
+            Err(_) => {
+                let align = self.segments().map(|segment| segment.align).max();
+                let align = align.unwrap_or(PAGE_SIZE).max(PAGE_SIZE);
+                let extra = ((align - PAGE_SIZE) / PAGE_SIZE) as usize;
+                let physical_base = boot_services.allocate_pages(
+                    AllocateType::AnyPages,
+                    KERNEL_AND_MODULES,
+                    pages + extra,
+                )?;
+                (physical_base + align - 1) & !(align - 1)
+            }
+        };
+        self.copy(physical_base, virtual_base, size, |segment| segment.vaddr);
+        Ok(Kernel {
+            physical_base,
+            virtual_base,
+            size,
+            entry: self.entry(),
+        })
+    }
+
+    /// Copies all `PT_LOAD` segments to their physical addresses, for
+    /// protocols that enter the kernel identity mapped or with paging off.
+    pub fn load_physical(&self, boot_services: &BootServices) -> Result<Kernel> {
+        let (physical_base, size) = self.span(|segment| segment.paddr)?;
+        let address = AllocateType::Address(physical_base as usize);
+        boot_services.allocate_pages(address, KERNEL_AND_MODULES, (size / PAGE_SIZE) as usize)?;
+        self.copy(physical_base, physical_base, size, |segment| segment.paddr);
+        Ok(Kernel {
+            physical_base,
+            virtual_base: physical_base,
+            size,
+            entry: self.entry(),
+        })
+    }
+
+    /// The page-aligned start and size of the range covering all segments.
+    fn span(&self, address: fn(&ProgramHeader) -> u64) -> Result<(u64, u64)> {
+        let base = self.segments().map(|segment| address(&segment)).min();
+        let base = base.ok_or_else(|| Error::from(Status::LOAD_ERROR))? & !(PAGE_SIZE - 1);
+        let end = self
+            .segments()
+            .try_fold(base, |end, segment| {
+                Some(end.max(address(&segment).checked_add(segment.memsz)?))
+            })
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .ok_or_else(|| Error::from(Status::LOAD_ERROR))?;
+        Ok((base, (end - base) & !(PAGE_SIZE - 1)))
+    }
+
+    fn copy(&self, dest: u64, base: u64, size: u64, address: fn(&ProgramHeader) -> u64) {
+        unsafe { (dest as *mut u8).write_bytes(0, size as usize) };
+        for segment in self.segments() {
+            let data = &self.image[segment.offset as usize..][..segment.filesz as usize];
+            let dest = dest + (address(&segment) - base);
+            unsafe { ptr::copy_nonoverlapping(data.as_ptr(), dest as *mut u8, data.len()) };
+        }
+    }
+}
+
+/// Where `Elf::load` put a kernel.
+#[derive(Debug, Clone, Copy)]
+pub struct Kernel {
+    pub physical_base: u64,
+    pub virtual_base: u64,
+    pub size: u64,
+    pub entry: u64,
+}
+
+impl Kernel {
+    pub fn is_higher_half(&self) -> bool {
+        self.virtual_base >= HIGHER_HALF
+    }
+}
+
+/// Where the program header at `index` starts, unless that overflows.
+fn program_header_offset(header: &FileHeader, index: usize) -> Option<usize> {
+    (header.phentsize as usize)
+        .checked_mul(index)?
+        .checked_add(header.phoff.try_into().ok()?)
+}
+
+fn read<T: Copy>(image: &[u8], offset: usize) -> Option<T> {
+    let bytes = image.get(offset..offset.checked_add(size_of::<T>())?)?;
+    Some(unsafe { bytes.as_ptr().cast::<T>().read_unaligned() })
+}
+
+#[test_case]
+fn parse() {
+    let mut image = [0u8; size_of::<FileHeader>() + size_of::<ProgramHeader>()];
+    let header = FileHeader {
+        ident: *b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0",
+        ty: ET_EXEC,
+        machine: EM_X86_64,
+        version: 1,
+        entry: 0xffff_ffff_8000_1000,
+        phoff: size_of::<FileHeader>() as u64,
+        shoff: 0,
+        flags: 0,
+        ehsize: size_of::<FileHeader>() as u16,
+        phentsize: size_of::<ProgramHeader>() as u16,
+        phnum: 1,
+        shentsize: 0,
+        shnum: 0,
+        shstrndx: 0,
+    };
+    let program_header = ProgramHeader {
+        ty: PT_LOAD,
+        flags: 0,
+        offset: 0,
+        vaddr: 0xffff_ffff_8000_0000,
+        paddr: 0x20_0000,
+        filesz: image.len() as u64,
+        memsz: 0x2000,
+        align: 0x1000,
+    };
+    unsafe {
+        image
+            .as_mut_ptr()
+            .cast::<FileHeader>()
+            .write_unaligned(header);
+        image[size_of::<FileHeader>()..]
+            .as_mut_ptr()
+            .cast::<ProgramHeader>()
+            .write_unaligned(program_header);
+    }
+    let elf = Elf::parse(&image, EM_X86_64).expect("Elf::parse failed");
+    assert_eq!(elf.entry(), 0xffff_ffff_8000_1000);
+    assert_eq!(elf.segments().count(), 1);
+    assert!(Elf::parse(&image, 0).is_none());
+    assert!(Elf::parse(&image[..size_of::<FileHeader>()], EM_X86_64).is_none());
+    let header = FileHeader {
+        phoff: u64::MAX,
+        ..header
+    };
+    unsafe {
+        image
+            .as_mut_ptr()
+            .cast::<FileHeader>()
+            .write_unaligned(header);
+    }
+    assert!(Elf::parse(&image, EM_X86_64).is_none());
+}
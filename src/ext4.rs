@@ -0,0 +1,562 @@
+use super::{
+    bytes::{le_u16, le_u32},
+    vfs::{Disk, Kind, Metadata, NodeId, Volume, VolumeInfo},
+};
+use alloc::{string::String, vec::Vec};
+use core::cell::RefCell;
+use uefi::{Error, Result, Status};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: NodeId = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_64BIT: u32 = 0x80;
+/// What can be read without knowing more than extents, htree directories
+/// and 64-bit block groups: an unreplayed journal or multiple mount
+/// protection don't change how files are found, and case-folded names
+/// still match themselves.
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | 0x4 // RECOVER
+    | 0x40 // EXTENTS
+    | INCOMPAT_64BIT
+    | 0x100 // MMP
+    | 0x200 // FLEX_BG
+    | 0x400 // EA_INODE
+    | 0x2000 // CSUM_SEED
+    | 0x4000 // LARGEDIR
+    | 0x20000; // CASEFOLD
+
+const EXTENTS_FL: u32 = 0x80000;
+const EXTENT_MAGIC: u16 = 0xf30a;
+/// Extents longer than this are preallocated but not written yet.
+const MAX_INITIALIZED_EXTENT: u16 = 32768;
+/// How deep an extent tree can be.
+const MAX_EXTENT_DEPTH: u16 = 5;
+/// Symbolic links with shorter targets keep them in the inode.
+const FAST_SYMLINK_SIZE: u64 = 60;
+/// Far bigger than the directories of kernels and boot entries the loader
+/// lists, so that a corrupt inode doesn't make it allocate gigabytes.
+const MAX_DIR_SIZE: u64 = 1 << 26;
+
+/// A read-only ext2, ext3 or ext4 file system.
+pub struct Ext4<D> {
+    disk: D,
+    superblock: Superblock,
+    /// The first block of the inode table of each block group.
+    inode_tables: Vec<u64>,
+    /// The blocks of the file read last, since files are read in pieces.
+    runs: RefCell<Option<(NodeId, Vec<Run>)>>,
+}
+
+impl<D: Disk> Ext4<D> {
+    pub fn new(disk: D) -> Result<Self> {
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        disk.read(SUPERBLOCK_OFFSET, &mut bytes)?;
+        let superblock = Superblock::parse(&bytes)?;
+        // The blocks and the group descriptors after the superblock have to
+        // fit on the partition.
+        let size = superblock.blocks_count.checked_mul(superblock.block_size);
+        if size.map_or(true, |size| size > disk.size()) {
+            return Err(Error::from(Status::VOLUME_CORRUPTED));
+        }
+        let groups = superblock.groups();
+        let offset = (superblock.first_data_block + 1) * superblock.block_size;
+        let table_size = (groups as u64).saturating_mul(superblock.desc_size as u64);
+        if offset.saturating_add(table_size) > disk.size() {
+            return Err(Error::from(Status::VOLUME_CORRUPTED));
+        }
+        let mut descriptors = vec![0; table_size as usize];
+        disk.read(offset, &mut descriptors)?;
+        let inode_tables = descriptors
+            .chunks_exact(superblock.desc_size)
+            .map(|descriptor| {
+                let hi = match superblock.desc_size >= 64 {
+                    true => le_u32(descriptor, 0x28)?,
+                    false => 0,
+                };
+                Some(u64::from(hi) << 32 | u64::from(le_u32(descriptor, 0x8)?))
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(|| Error::from(Status::VOLUME_CORRUPTED))?;
+        Ok(Self {
+            disk,
+            superblock,
+            inode_tables,
+            runs: RefCell::new(None),
+        })
+    }
+
+    fn inode(&self, node: NodeId) -> Result<Inode> {
+        let index = node
+            .checked_sub(1)
+            .ok_or_else(|| Error::from(Status::NOT_FOUND))?;
+        let inodes_per_group = u64::from(self.superblock.inodes_per_group);
+        let table = self
+            .inode_tables
+            .get((index / inodes_per_group) as usize)
+            .ok_or_else(|| Error::from(Status::NOT_FOUND))?;
+        let offset = table * self.superblock.block_size
+            + index % inodes_per_group * u64::from(self.superblock.inode_size);
+        let mut bytes = [0; 128];
+        self.disk.read(offset, &mut bytes)?;
+        Inode::parse(&bytes).ok_or_else(|| Error::from(Status::VOLUME_CORRUPTED))
+    }
+
+    /// Where the blocks of a file are, from its extent tree or its block map.
+    fn runs(&self, inode: &Inode) -> Result<Vec<Run>> {
+        let blocks = (inode.size + self.superblock.block_size - 1) / self.superblock.block_size;
+        let mut runs = Vec::new();
+        if inode.flags & EXTENTS_FL != 0 {
+            self.extents(&inode.block, MAX_EXTENT_DEPTH, &mut runs)?;
+        } else {
+            let pointers = inode
+                .block
+                .chunks_exact(4)
+                .flat_map(|pointer| le_u32(pointer, 0))
+                .collect::<Vec<_>>();
+            let mut logical = 0;
+            for (i, &pointer) in pointers.iter().enumerate() {
+                let depth = i.saturating_sub(11) as u32;
+                self.map(pointer, depth, &mut logical, blocks, &mut runs)?;
+            }
+        }
+        Ok(runs)
+    }
+
+    fn extents(&self, node: &[u8], depth: u16, runs: &mut Vec<Run>) -> Result {
+        match parse_extent_node(node)? {
+            ExtentNode::Leaf(extents) => runs.extend(extents),
+            ExtentNode::Index(children) => {
+                let depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| Error::from(Status::VOLUME_CORRUPTED))?;
+                let mut block = vec![0; self.superblock.block_size as usize];
+                for child in children {
+                    self.disk
+                        .read(child * self.superblock.block_size, &mut block)?;
+                    self.extents(&block, depth, runs)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the blocks behind a block map pointer with `depth` levels of
+    /// indirection, leaving holes out.
+    fn map(
+        &self,
+        pointer: u32,
+        depth: u32,
+        logical: &mut u64,
+        blocks: u64,
+        runs: &mut Vec<Run>,
+    ) -> Result {
+        let per_block = self.superblock.block_size / 4;
+        let span = per_block.pow(depth);
+        if *logical >= blocks {
+            return Ok(());
+        }
+        if pointer == 0 {
+            *logical += span;
+            return Ok(());
+        }
+        if depth == 0 {
+            push_block(runs, *logical, u64::from(pointer));
+            *logical += 1;
+            return Ok(());
+        }
+        let mut block = vec![0; self.superblock.block_size as usize];
+        let offset = u64::from(pointer) * self.superblock.block_size;
+        self.disk.read(offset, &mut block)?;
+        for pointer in block.chunks_exact(4).flat_map(|pointer| le_u32(pointer, 0)) {
+            self.map(pointer, depth - 1, logical, blocks, runs)?;
+        }
+        Ok(())
+    }
+
+    fn read_inode(
+        &self,
+        node: NodeId,
+        inode: &Inode,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize> {
+        let len = (buffer.len() as u64).min(inode.size.saturating_sub(offset)) as usize;
+        let buffer = &mut buffer[..len];
+        if len == 0 {
+            return Ok(0);
+        }
+        if is_fast_symlink(inode) {
+            let target = &inode.block[..inode.size as usize];
+            buffer.copy_from_slice(&target[offset as usize..][..len]);
+            return Ok(len);
+        }
+        let mut cache = self.runs.borrow_mut();
+        if cache.as_ref().map(|(cached, _)| *cached) != Some(node) {
+            *cache = Some((node, self.runs(inode)?));
+        }
+        let runs = &cache.as_ref().expect("no runs").1;
+        let block_size = self.superblock.block_size;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let logical = position / block_size;
+            let within = position % block_size;
+            let run = runs
+                .iter()
+                .find(|run| run.logical <= logical && logical < run.logical + run.len);
+            let next = runs
+                .iter()
+                .map(|run| run.logical)
+                .filter(|&start| start > logical)
+                .min();
+            let (end, physical) = match run {
+                Some(run) => (run.logical + run.len, run.physical),
+                None => (next.unwrap_or(u64::MAX / block_size), None),
+            };
+            let count = ((end - logical).saturating_mul(block_size) - within)
+                .min((len - done) as u64) as usize;
+            let chunk = &mut buffer[done..done + count];
+            match physical {
+                Some(physical) => {
+                    let physical = physical + logical - run.expect("no run").logical;
+                    self.disk.read(physical * block_size + within, chunk)?
+                }
+                None => chunk.fill(0),
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+}
+
+impl<D: Disk> Volume for Ext4<D> {
+    fn root(&self) -> NodeId {
+        ROOT_INODE
+    }
+
+    fn metadata(&self, node: NodeId) -> Result<Metadata> {
+        let inode = self.inode(node)?;
+        Ok(Metadata {
+            kind: match inode.mode & 0xf000 {
+                0x4000 => Kind::Directory,
+                0xa000 => Kind::Symlink,
+                _ => Kind::File,
+            },
+            size: inode.size,
+            accessed: inode.accessed.into(),
+            modified: inode.modified.into(),
+            changed: inode.changed.into(),
+        })
+    }
+
+    /// Reads every block of the directory in turn, which also works for
+    /// htree directories: their index blocks look like empty blocks.
+    fn read_dir(&self, node: NodeId) -> Result<Vec<(String, NodeId)>> {
+        let inode = self.inode(node)?;
+        if inode.size > MAX_DIR_SIZE {
+            return Err(Error::from(Status::VOLUME_CORRUPTED));
+        }
+        let mut bytes = vec![0; inode.size as usize];
+        self.read_inode(node, &inode, 0, &mut bytes)?;
+        let file_type = self.superblock.feature_incompat & INCOMPAT_FILETYPE != 0;
+        let mut entries = Vec::new();
+        for block in bytes.chunks(self.superblock.block_size as usize) {
+            entries.extend(parse_dir_block(block, file_type));
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, node: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        let inode = self.inode(node)?;
+        self.read_inode(node, &inode, offset, buffer)
+    }
+
+    fn info(&self) -> VolumeInfo {
+        VolumeInfo {
+            label: self.superblock.label.clone(),
+            uuid: self.superblock.uuid,
+            size: self.superblock.blocks_count * self.superblock.block_size,
+            free_space: self.superblock.free_blocks_count * self.superblock.block_size,
+            block_size: self.superblock.block_size as u32,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Superblock {
+    block_size: u64,
+    blocks_count: u64,
+    free_blocks_count: u64,
+    first_data_block: u64,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: u16,
+    desc_size: usize,
+    feature_incompat: u32,
+    uuid: [u8; 16],
+    label: String,
+}
+
+impl Superblock {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        if le_u16(bytes, 0x38) != Some(MAGIC) {
+            return Err(Error::from(Status::UNSUPPORTED));
+        }
+        let superblock = Self::read(bytes).ok_or_else(|| Error::from(Status::VOLUME_CORRUPTED))?;
+        if superblock.feature_incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Error::from(Status::UNSUPPORTED));
+        }
+        if superblock.blocks_per_group == 0
+            || superblock.inodes_per_group == 0
+            || superblock.inode_size < 128
+            || superblock.desc_size < 32
+            || superblock.first_data_block >= superblock.blocks_count
+        {
+            return Err(Error::from(Status::VOLUME_CORRUPTED));
+        }
+        Ok(superblock)
+    }
+
+    fn read(bytes: &[u8]) -> Option<Self> {
+        let feature_incompat = match le_u32(bytes, 0x4c)? {
+            0 => 0,
+            _ => le_u32(bytes, 0x60)?,
+        };
+        let is_64bit = feature_incompat & INCOMPAT_64BIT != 0;
+        let hi = |offset| match is_64bit {
+            true => le_u32(bytes, offset).map(|hi| u64::from(hi) << 32),
+            false => Some(0),
+        };
+        let log_block_size = le_u32(bytes, 0x18)?;
+        Some(Self {
+            block_size: 1024 << log_block_size.min(6),
+            blocks_count: hi(0x150)? | u64::from(le_u32(bytes, 0x4)?),
+            free_blocks_count: hi(0x158)? | u64::from(le_u32(bytes, 0xc)?),
+            first_data_block: le_u32(bytes, 0x14)?.into(),
+            blocks_per_group: le_u32(bytes, 0x20)?,
+            inodes_per_group: le_u32(bytes, 0x28)?,
+            inode_size: match le_u32(bytes, 0x4c)? {
+                0 => 128,
+                _ => le_u16(bytes, 0x58)?,
+            },
+            desc_size: match is_64bit {
+                true => le_u16(bytes, 0xfe)?.into(),
+                false => 32,
+            },
+            feature_incompat,
+            uuid: bytes.get(0x68..0x78)?.try_into().ok()?,
+            label: String::from_utf8_lossy(bytes.get(0x78..0x88)?)
+                .trim_end_matches('\0')
+                .into(),
+        })
+    }
+
+    fn groups(&self) -> usize {
+        let blocks = self.blocks_count - self.first_data_block;
+        ((blocks + u64::from(self.blocks_per_group) - 1) / u64::from(self.blocks_per_group))
+            as usize
+    }
+}
+
+struct Inode {
+    mode: u16,
+    size: u64,
+    accessed: i32,
+    changed: i32,
+    modified: i32,
+    flags: u32,
+    /// The extent tree root, block map or fast symbolic link target.
+    block: [u8; 60],
+}
+
+impl Inode {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        Some(Self {
+            mode: le_u16(bytes, 0x0)?,
+            size: u64::from(le_u32(bytes, 0x6c)?) << 32 | u64::from(le_u32(bytes, 0x4)?),
+            accessed: le_u32(bytes, 0x8)? as i32,
+            changed: le_u32(bytes, 0xc)? as i32,
+            modified: le_u32(bytes, 0x10)? as i32,
+            flags: le_u32(bytes, 0x20)?,
+            block: bytes.get(0x28..0x64)?.try_into().ok()?,
+        })
+    }
+}
+
+fn is_fast_symlink(inode: &Inode) -> bool {
+    inode.mode & 0xf000 == 0xa000 && inode.size < FAST_SYMLINK_SIZE && inode.flags & EXTENTS_FL == 0
+}
+
+/// Consecutive blocks of a file, with no `physical` blocks for extents that
+/// read as zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Run {
+    logical: u64,
+    physical: Option<u64>,
+    len: u64,
+}
+
+fn push_block(runs: &mut Vec<Run>, logical: u64, physical: u64) {
+    if let Some(Run {
+        logical: start,
+        physical: Some(first),
+        len,
+    }) = runs.last_mut()
+    {
+        if *start + *len == logical && *first + *len == physical {
+            *len += 1;
+            return;
+        }
+    }
+    runs.push(Run {
+        logical,
+        physical: Some(physical),
+        len: 1,
+    });
+}
+
+enum ExtentNode {
+    Leaf(Vec<Run>),
+    /// The blocks holding the next level of the tree.
+    Index(Vec<u64>),
+}
+
+fn parse_extent_node(bytes: &[u8]) -> Result<ExtentNode> {
+    let corrupted = || Error::from(Status::VOLUME_CORRUPTED);
+    if bytes.len() < 12 || le_u16(bytes, 0) != Some(EXTENT_MAGIC) {
+        return Err(corrupted());
+    }
+    let entries = (le_u16(bytes, 2).ok_or_else(corrupted)? as usize).min((bytes.len() - 12) / 12);
+    let entries = bytes[12..12 + entries * 12].chunks_exact(12);
+    if le_u16(bytes, 6).ok_or_else(corrupted)? == 0 {
+        let extents = entries.map(|entry| {
+            let len = le_u16(entry, 4)?;
+            let physical = u64::from(le_u16(entry, 6)?) << 32 | u64::from(le_u32(entry, 8)?);
+            Some(Run {
+                logical: le_u32(entry, 0)?.into(),
+                physical: (len <= MAX_INITIALIZED_EXTENT).then_some(physical),
+                len: match len > MAX_INITIALIZED_EXTENT {
+                    true => len - MAX_INITIALIZED_EXTENT,
+                    false => len,
+                }
+                .into(),
+            })
+        });
+        let extents = extents.collect::<Option<_>>().ok_or_else(corrupted)?;
+        return Ok(ExtentNode::Leaf(extents));
+    }
+    let children = entries
+        .map(|entry| Some(u64::from(le_u16(entry, 8)?) << 32 | u64::from(le_u32(entry, 4)?)));
+    let children = children.collect::<Option<_>>().ok_or_else(corrupted)?;
+    Ok(ExtentNode::Index(children))
+}
+
+/// The names and inodes in a directory block, leaving out `.`, `..` and
+/// unused entries.
+fn parse_dir_block(block: &[u8], file_type: bool) -> Vec<(String, NodeId)> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= block.len() {
+        let entry = &block[offset..];
+        let (inode, raw_len) = match (le_u32(entry, 0), le_u16(entry, 4)) {
+            (Some(inode), Some(raw_len)) => (inode, raw_len),
+            _ => break,
+        };
+        let rec_len = rec_len(raw_len, block.len());
+        if rec_len < 8 || rec_len > entry.len() {
+            break;
+        }
+        let name_len: usize = match (file_type, le_u16(entry, 6)) {
+            (true, _) => entry[6].into(),
+            (false, Some(name_len)) => name_len.into(),
+            (false, None) => break,
+        };
+        let name = &entry[8..(8 + name_len).min(rec_len)];
+        if inode != 0 && name != b"." && name != b".." {
+            entries.push((String::from_utf8_lossy(name).into_owned(), inode.into()));
+        }
+        offset += rec_len;
+    }
+    entries
+}
+
+/// Decodes the length of a directory entry, which stores 65536 and more in
+/// its lowest bits for blocks of 64 KiB and more.
+fn rec_len(raw: u16, block_size: usize) -> usize {
+    match (raw, block_size >= 0x10000) {
+        (0 | 0xffff, true) => block_size,
+        (raw, true) => usize::from(raw & 0xfffc) | usize::from(raw & 3) << 16,
+        (raw, false) => raw.into(),
+    }
+}
+
+#[test_case]
+fn parsers() {
+    let mut superblock = [0; SUPERBLOCK_SIZE];
+    superblock[0x4..0x8].copy_from_slice(&8192u32.to_le_bytes());
+    superblock[0xc..0x10].copy_from_slice(&100u32.to_le_bytes());
+    superblock[0x18] = 2;
+    superblock[0x20..0x24].copy_from_slice(&32768u32.to_le_bytes());
+    superblock[0x28..0x2c].copy_from_slice(&2048u32.to_le_bytes());
+    superblock[0x38..0x3a].copy_from_slice(&MAGIC.to_le_bytes());
+    superblock[0x4c] = 1;
+    superblock[0x58..0x5a].copy_from_slice(&256u16.to_le_bytes());
+    superblock[0x60..0x64].copy_from_slice(&(INCOMPAT_FILETYPE | INCOMPAT_64BIT).to_le_bytes());
+    superblock[0x78..0x7c].copy_from_slice(b"root");
+    superblock[0xfe..0x100].copy_from_slice(&64u16.to_le_bytes());
+    superblock[0x150] = 1;
+    let parsed = Superblock::parse(&superblock).expect("Superblock::parse failed");
+    assert_eq!(parsed.block_size, 4096);
+    assert_eq!(parsed.blocks_count, 1 << 32 | 8192);
+    assert_eq!((parsed.desc_size, parsed.inode_size), (64, 256));
+    assert_eq!(parsed.label, "root");
+    superblock[0x60] |= 0x10;
+    assert!(Superblock::parse(&superblock).is_err());
+
+    let mut node = [0; 36];
+    node[0..2].copy_from_slice(&EXTENT_MAGIC.to_le_bytes());
+    node[2] = 2;
+    node[12 + 4] = 8;
+    node[12 + 6] = 1;
+    node[12 + 8] = 0x10;
+    node[24..28].copy_from_slice(&8u32.to_le_bytes());
+    node[28..30].copy_from_slice(&(MAX_INITIALIZED_EXTENT + 4).to_le_bytes());
+    let runs = match parse_extent_node(&node) {
+        Ok(ExtentNode::Leaf(runs)) => runs,
+        _ => panic!("not a leaf"),
+    };
+    let expected = [
+        Run {
+            logical: 0,
+            physical: Some(1 << 32 | 0x10),
+            len: 8,
+        },
+        Run {
+            logical: 8,
+            physical: None,
+            len: 4,
+        },
+    ];
+    assert_eq!(runs, expected);
+
+    let mut block = [0; 64];
+    block[0] = 2;
+    block[4] = 12;
+    block[6] = 1;
+    block[8] = b'.';
+    block[12] = 2;
+    block[16] = 12;
+    block[18] = 2;
+    block[20..22].copy_from_slice(b"..");
+    block[24] = 12;
+    block[28] = 40;
+    block[30] = 7;
+    block[32..39].copy_from_slice(b"vmlinuz");
+    let entries = parse_dir_block(&block, true);
+    assert_eq!(entries, [(String::from("vmlinuz"), 12)]);
+    assert_eq!(rec_len(0, 0x10000), 0x10000);
+    assert_eq!(rec_len(0xfffc | 1, 0x40000), 0x1fffc);
+}
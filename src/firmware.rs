@@ -0,0 +1,108 @@
+use super::var;
+use alloc::{string::String, vec::Vec};
+use uefi::{
+    table::runtime::{ResetType, VariableVendor},
+    Result, Status,
+};
+
+const EFI_OS_INDICATIONS_BOOT_TO_FW_UI: u64 = 1;
+
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+const LOAD_OPTION_HIDDEN: u32 = 0x8;
+
+/// A `Boot####` load option of the firmware boot manager.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootOption {
+    pub number: u16,
+    pub description: String,
+}
+
+/// Whether the firmware offers to stop in its setup UI on the next boot.
+pub fn supports_setup() -> bool {
+    let supported = var::get("OsIndicationsSupported", &VariableVendor::GLOBAL_VARIABLE);
+    read_u64(supported) & EFI_OS_INDICATIONS_BOOT_TO_FW_UI != 0
+}
+
+/// Asks the firmware to stop in its setup UI and resets.
+pub fn reboot_to_setup() -> Result {
+    let indications = var::get("OsIndications", &VariableVendor::GLOBAL_VARIABLE);
+    let indications = read_u64(indications) | EFI_OS_INDICATIONS_BOOT_TO_FW_UI;
+    var::set(
+        "OsIndications",
+        &VariableVendor::GLOBAL_VARIABLE,
+        var::non_volatile(),
+        &indications.to_le_bytes(),
+    )?;
+    reset()
+}
+
+/// The active, visible load options in `BootOrder`.
+pub fn boot_options() -> Vec<BootOption> {
+    let boot_order = var::get("BootOrder", &VariableVendor::GLOBAL_VARIABLE).unwrap_or_default();
+    boot_order
+        .chunks_exact(2)
+        .map(|number| u16::from_le_bytes([number[0], number[1]]))
+        .filter_map(|number| {
+            let name = format!("Boot{number:04X}");
+            let load_option = var::get(&name, &VariableVendor::GLOBAL_VARIABLE)?;
+            let (attributes, description) = parse_load_option(&load_option)?;
+            let is_shown = attributes & (LOAD_OPTION_ACTIVE | LOAD_OPTION_HIDDEN);
+            (is_shown == LOAD_OPTION_ACTIVE).then_some(BootOption {
+                number,
+                description,
+            })
+        })
+        .collect()
+}
+
+/// Makes the firmware boot `option` once and resets.
+pub fn reboot_to(option: &BootOption) -> Result {
+    var::set(
+        "BootNext",
+        &VariableVendor::GLOBAL_VARIABLE,
+        var::non_volatile(),
+        &option.number.to_le_bytes(),
+    )?;
+    reset()
+}
+
+fn reset() -> ! {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    system_table
+        .runtime_services()
+        .reset(ResetType::Cold, Status::SUCCESS, None)
+}
+
+fn read_u64(data: Option<Vec<u8>>) -> u64 {
+    let data = data.unwrap_or_default();
+    let data = data.get(..8).and_then(|data| data.try_into().ok());
+    data.map_or(0, u64::from_le_bytes)
+}
+
+/// Splits an `EFI_LOAD_OPTION` into its attributes and description.
+fn parse_load_option(data: &[u8]) -> Option<(u32, String)> {
+    let attributes = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+    let description = data
+        .get(6..)?
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0);
+    let description = char::decode_utf16(description)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+    Some((attributes, description))
+}
+
+#[test_case]
+fn load_option() {
+    let mut data = Vec::from(1u32.to_le_bytes());
+    data.extend(4u16.to_le_bytes());
+    data.extend("UEFI PXEv4\0".encode_utf16().flat_map(u16::to_le_bytes));
+    data.extend([0x7f, 0xff, 0x04, 0x00]);
+    assert_eq!(
+        parse_load_option(&data),
+        Some((LOAD_OPTION_ACTIVE, String::from("UEFI PXEv4")))
+    );
+    assert_eq!(parse_load_option(&data[..3]), None);
+}
@@ -1,17 +1,27 @@
-use super::{println, str::ToCString16};
-use alloc::vec::Vec;
+use super::{gpt, println, str::ToCString16};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{ffi::c_void, mem::size_of_val, slice};
 use uefi::{
     prelude::*,
     proto::{
-        device_path::text::{AllowShortcuts, DevicePathToText, DisplayOnly, PoolString},
+        device_path::{
+            text::{AllowShortcuts, DevicePathFromText, DevicePathToText, DisplayOnly, PoolString},
+            DevicePath,
+        },
         loaded_image::LoadedImage,
         media::{
-            file::{File, FileAttribute, FileInfo, FileMode, FileType, RegularFile},
+            file::{
+                File, FileAttribute, FileHandle, FileInfo, FileMode, FileSystemVolumeLabel,
+                FileType, RegularFile,
+            },
             fs::SimpleFileSystem,
         },
     },
     table::boot::{OpenProtocolAttributes, OpenProtocolParams},
-    Error,
+    Error, Guid, Identify,
 };
 
 pub fn get<'a>(image_handle: Handle) -> &'a mut SimpleFileSystem {
@@ -24,8 +34,98 @@ pub fn get<'a>(image_handle: Handle) -> &'a mut SimpleFileSystem {
     unsafe { &mut *file_system.interface.get() }
 }
 
+/// Every volume the firmware exposes a file system for.
+pub fn volumes() -> Vec<Handle> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    boot_services
+        .find_handles::<SimpleFileSystem>()
+        .unwrap_or_default()
+}
+
+pub fn get_volume<'a>(
+    image_handle: Handle,
+    volume: Handle,
+) -> Result<&'a mut SimpleFileSystem, Error> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let file_system = system_table
+        .boot_services()
+        .open_protocol::<SimpleFileSystem>(
+            OpenProtocolParams {
+                handle: volume,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?;
+    Ok(unsafe { &mut *file_system.interface.get() })
+}
+
+/// The label of the file system on `volume`.
+pub fn volume_label(image_handle: Handle, volume: Handle) -> Option<String> {
+    let mut root = get_volume(image_handle, volume).ok()?.open_volume().ok()?;
+    let info = root.get_boxed_info::<FileSystemVolumeLabel>().ok()?;
+    Some(info.volume_label().to_string())
+}
+
+/// Splits a `device:path` path into the file system of the volume that
+/// `device` names and the path on it, with backslashes as separators. The
+/// device is a device path, the `PARTUUID=` or `PARTLABEL=` of a GPT
+/// partition or the `LABEL=` of a file system. Paths without a device are on
+/// the loader's own volume.
+pub fn locate<'a>(
+    image_handle: Handle,
+    path: &str,
+) -> Result<(&'a mut SimpleFileSystem, String), Error> {
+    let (device, path) = match split_device(path) {
+        Some(split) => split,
+        None => return Ok((get(image_handle), path.replace('/', "\\"))),
+    };
+    let volume = match gpt::find(image_handle, device) {
+        Some(volume) => volume?,
+        None => {
+            let system_table = uefi_services::system_table();
+            let system_table = unsafe { system_table.as_ref() };
+            let boot_services = system_table.boot_services();
+            let device_path_from_text = boot_services.locate_protocol::<DevicePathFromText>()?;
+            let device_path_from_text = unsafe { &*device_path_from_text.get() };
+            let device_path = device_path_from_text
+                .convert_text_to_device_path(&device.to_cstring16())
+                .ok_or(Status::NOT_FOUND)?;
+            let volume = boot_services.locate_device_path::<SimpleFileSystem>(&mut &*device_path);
+            let _ = boot_services.free_pool(device_path.as_ffi_ptr() as *mut u8);
+            volume?
+        }
+    };
+    Ok((get_volume(image_handle, volume)?, path.replace('/', "\\")))
+}
+
+/// Splits `path` at the colon after its device. Device path text has colons
+/// of its own, as in `IPv4(...)` or `Uri(http://...)`, but only within the
+/// parentheses of a node.
+fn split_device(path: &str) -> Option<(&str, &str)> {
+    let mut depth = 0usize;
+    let index = path.char_indices().find_map(|(index, c)| {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ':' if depth == 0 => return Some(index),
+            _ => {}
+        }
+        None
+    })?;
+    Some((&path[..index], &path[index + 1..]))
+}
+
 pub trait BootServicesExt {
     fn get_image_file_path(&self, image_handle: Handle) -> Option<PoolString>;
+
+    /// The device path of the file at `path`, which the caller frees from pool.
+    fn get_file_device_path(&self, image_handle: Handle, path: &str) -> Option<&DevicePath>;
+
+    fn get_device_path_text(&self, image_handle: Handle, device: Handle) -> Option<String>;
 }
 
 impl BootServicesExt for BootServices {
@@ -55,10 +155,63 @@ impl BootServicesExt for BootServices {
             AllowShortcuts(false),
         )
     }
+
+    fn get_file_device_path(&self, image_handle: Handle, path: &str) -> Option<&DevicePath> {
+        let (device_path, path) = match split_device(path) {
+            Some((device, path)) => match gpt::find(image_handle, device) {
+                Some(volume) => (self.get_device_path_text(image_handle, volume.ok()?)?, path),
+                None => (device.to_string(), path),
+            },
+            None => {
+                let loaded_image = self
+                    .open_protocol::<LoadedImage>(
+                        OpenProtocolParams {
+                            handle: image_handle,
+                            agent: image_handle,
+                            controller: None,
+                        },
+                        OpenProtocolAttributes::GetProtocol,
+                    )
+                    .ok()?;
+                let device = unsafe { &*loaded_image.interface.get() }.device();
+                (self.get_device_path_text(image_handle, device)?, path)
+            }
+        };
+        let path = path.trim_start_matches(['\\', '/']).replace('/', "\\");
+        let device_path = format!("{device_path}/\\{path}").to_cstring16();
+        let device_path_from_text = self.locate_protocol::<DevicePathFromText>().ok()?;
+        let device_path_from_text = unsafe { &*device_path_from_text.get() };
+        device_path_from_text.convert_text_to_device_path(&device_path)
+    }
+
+    fn get_device_path_text(&self, image_handle: Handle, device: Handle) -> Option<String> {
+        let device_path = self
+            .open_protocol::<DevicePath>(
+                OpenProtocolParams {
+                    handle: device,
+                    agent: image_handle,
+                    controller: None,
+                },
+                OpenProtocolAttributes::GetProtocol,
+            )
+            .ok()?;
+        let device_path = unsafe { &*device_path.interface.get() };
+        let device_path_to_text = self.locate_protocol::<DevicePathToText>().ok()?;
+        let device_path_to_text = unsafe { &*device_path_to_text.get() };
+        let device_path = device_path_to_text.convert_device_path_to_text(
+            self,
+            device_path,
+            DisplayOnly(false),
+            AllowShortcuts(false),
+        )?;
+        Some(device_path.to_string())
+    }
 }
 
 pub trait FileSystem {
     fn open(&mut self, path: &str, mode: FileMode) -> Result<RegularFile, Error>;
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<String>, Error>;
 }
 
 impl FileSystem for SimpleFileSystem {
@@ -76,12 +229,49 @@ impl FileSystem for SimpleFileSystem {
             }
         }
     }
+
+    /// Lists the names of the regular files in the directory at `path`.
+    fn read_dir(&mut self, path: &str) -> Result<Vec<String>, Error> {
+        let path = path.to_cstring16();
+        let mut directory = match self
+            .open_volume()?
+            .open(&path, FileMode::Read, FileAttribute::empty())?
+            .into_type()?
+        {
+            FileType::Dir(directory) => directory,
+            FileType::Regular(_) => return Err(Error::from(Status::INVALID_PARAMETER)),
+        };
+        let mut names = Vec::new();
+        let mut buffer = vec![0u64; 128];
+        loop {
+            let bytes = unsafe {
+                slice::from_raw_parts_mut(buffer.as_mut_ptr().cast::<u8>(), buffer.len() * 8)
+            };
+            match directory.read_entry(bytes) {
+                Ok(Some(info)) => {
+                    if !info.attribute().contains(FileAttribute::DIRECTORY) {
+                        names.push(info.file_name().to_string());
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => match *err.data() {
+                    Some(size) => buffer.resize((size + 7) / 8, 0),
+                    None => return Err(Error::from(err.status())),
+                },
+            }
+        }
+        Ok(names)
+    }
 }
 
 pub trait FileExt {
     fn load(&mut self) -> Result<Vec<u8>, Error>;
 
+    fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Error>;
+
     fn replace(&mut self, buffer: &[u8]) -> Result<(), Error<usize>>;
+
+    fn rename(&mut self, name: &str) -> Result<(), Error>;
 }
 
 impl FileExt for RegularFile {
@@ -92,6 +282,17 @@ impl FileExt for RegularFile {
         Ok(buffer)
     }
 
+    /// Reads up to `len` bytes at `offset`, fewer near the end of the file.
+    fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        self.set_position(offset)?;
+        let mut buffer = vec![0; len];
+        let len = self
+            .read(&mut buffer)
+            .map_err(|err| Error::from(err.status()))?;
+        buffer.truncate(len);
+        Ok(buffer)
+    }
+
     fn replace(&mut self, buffer: &[u8]) -> Result<(), Error<usize>> {
         let len = self
             .get_boxed_info::<FileInfo>()
@@ -106,4 +307,66 @@ impl FileExt for RegularFile {
         }
         Ok(())
     }
+
+    /// Renames the file within its directory.
+    fn rename(&mut self, name: &str) -> Result<(), Error> {
+        let info = self.get_boxed_info::<FileInfo>()?;
+        let name = name.to_cstring16();
+        let mut buffer = vec![0u64; (FILE_INFO_SIZE + name.num_bytes() + 7) / 8];
+        let storage = unsafe {
+            slice::from_raw_parts_mut(buffer.as_mut_ptr().cast::<u8>(), buffer.len() * 8)
+        };
+        let info = FileInfo::new(
+            storage,
+            info.file_size(),
+            info.physical_size(),
+            *info.create_time(),
+            *info.last_access_time(),
+            *info.modification_time(),
+            info.attribute(),
+            &name,
+        )
+        .or(Err(Status::BUFFER_TOO_SMALL))?;
+        let protocol = unsafe { *(self.handle() as *mut FileHandle).cast::<*mut FileProtocol>() };
+        unsafe {
+            ((*protocol).set_info)(
+                protocol,
+                &FileInfo::GUID,
+                size_of_val(info),
+                (info as *const FileInfo).cast(),
+            )
+        }
+        .into()
+    }
+}
+
+#[test_case]
+fn device_paths() {
+    assert_eq!(
+        split_device("PARTUUID=1234:/boot/vmlinuz"),
+        Some(("PARTUUID=1234", "/boot/vmlinuz"))
+    );
+    let device = "PciRoot(0x0)/MAC(525400123456,0x1)/IPv4(0.0.0.0:0)/Uri(http://a:80/b)";
+    assert_eq!(
+        split_device(&format!("{device}:\\EFI\\a:b.efi")),
+        Some((device, "\\EFI\\a:b.efi"))
+    );
+    assert_eq!(split_device("\\EFI\\boot(1).efi"), None);
+}
+
+/// The size of `EFI_FILE_INFO` without its file name.
+pub const FILE_INFO_SIZE: usize = 80;
+
+/// `EFI_FILE_PROTOCOL` up to `SetInfo`, which `File::set_info` passes the
+/// size of a reference to instead of the size of the information.
+#[repr(C)]
+struct FileProtocol {
+    revision: u64,
+    services: [usize; 8],
+    set_info: unsafe extern "efiapi" fn(
+        this: *mut FileProtocol,
+        information_type: *const Guid,
+        buffer_size: usize,
+        buffer: *const c_void,
+    ) -> Status,
 }
@@ -1,4 +1,8 @@
-use alloc::{string::ToString, vec::Vec};
+use super::input::{self, LineEditor};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
 use core::{
     fmt::{self, Display, Formatter},
     ops::{Deref, DerefMut},
@@ -14,10 +18,11 @@ use embedded_graphics::{
 use serde::{Deserialize, Serialize};
 use uefi::{
     proto::console::{
-        gop::GraphicsOutput,
+        gop::{GraphicsOutput, PixelBitmask, PixelFormat},
         text::{Key, ScanCode},
     },
-    Error, Status,
+    table::boot::{EventType, TimerTrigger, Tpl},
+    Error, Handle, Status,
 };
 
 pub const BACKGROUND_COLOR: Rgb888 = Rgb888::new(168, 154, 132);
@@ -33,6 +38,77 @@ pub fn get<'a>() -> &'a mut GraphicsOutput<'a> {
     unsafe { &mut *graphics_output.get() }
 }
 
+/// The linear frame buffer as described to kernels: `stride` is in pixels
+/// and each mask selects a color channel within a 32-bit pixel.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FrameBufferInfo {
+    pub base: u64,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub bpp: u32,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub reserved_mask: u32,
+}
+
+impl FrameBufferInfo {
+    pub fn pitch(&self) -> u32 {
+        self.stride * self.bpp / 8
+    }
+}
+
+impl TryFrom<&mut GraphicsOutput<'_>> for FrameBufferInfo {
+    type Error = Error;
+
+    fn try_from(graphics_output: &mut GraphicsOutput) -> Result<Self, Error> {
+        let mode_info = graphics_output.current_mode_info();
+        let bitmask = match mode_info.pixel_format() {
+            PixelFormat::Rgb => PixelBitmask {
+                red: 0x0000ff,
+                green: 0x00ff00,
+                blue: 0xff0000,
+                reserved: 0xff000000,
+            },
+            PixelFormat::Bgr => PixelBitmask {
+                red: 0xff0000,
+                green: 0x00ff00,
+                blue: 0x0000ff,
+                reserved: 0xff000000,
+            },
+            PixelFormat::Bitmask => mode_info
+                .pixel_bitmask()
+                .ok_or_else(|| Error::from(Status::UNSUPPORTED))?,
+            PixelFormat::BltOnly => return Ok(Self::default()),
+        };
+        let (width, height) = mode_info.resolution();
+        let mut frame_buffer = graphics_output.frame_buffer();
+        Ok(Self {
+            base: frame_buffer.as_mut_ptr() as u64,
+            size: frame_buffer.size() as u64,
+            width: width as u32,
+            height: height as u32,
+            stride: mode_info.stride() as u32,
+            bpp: 32,
+            red_mask: bitmask.red,
+            green_mask: bitmask.green,
+            blue_mask: bitmask.blue,
+            reserved_mask: bitmask.reserved,
+        })
+    }
+}
+
+/// Splits a channel mask into its width and position in bits.
+pub fn mask_size_shift(mask: u32) -> (u8, u8) {
+    match mask {
+        0 => (0, 0),
+        mask => (mask.count_ones() as u8, mask.trailing_zeros() as u8),
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Color {
     pub r: u8,
@@ -87,6 +163,16 @@ impl From<Resolution> for (usize, usize) {
 
 pub trait Interaction {
     fn set_resolution(&mut self) -> uefi::Result;
+
+    fn select(&mut self, title: &str, items: &[&str]) -> uefi::Result<usize>;
+
+    fn edit(&mut self, image_handle: Handle, title: &str, text: &str) -> uefi::Result<String>;
+
+    fn countdown(&mut self, title: &str, seconds: u64) -> uefi::Result;
+
+    fn alert(&mut self, title: &str, lines: &[&str]) -> uefi::Result;
+
+    fn progress(&mut self, title: &str, done: u64, total: Option<u64>) -> uefi::Result;
 }
 
 impl Interaction for GraphicsOutput<'_> {
@@ -94,40 +180,12 @@ impl Interaction for GraphicsOutput<'_> {
         let mut frame_buffer = FrameBuffer::from(&mut *self);
         let (x, y) = self.current_mode_info().resolution();
         let center = Point::new(x as i32 >> 1, y as i32 >> 1);
-        Rectangle::new(
-            Point::new(center.x - 100, center.y - 150),
-            Size::new(200, 300),
-        )
-        .into_styled(PrimitiveStyle::with_fill(BACKGROUND_COLOR))
-        .draw(&mut frame_buffer)?;
-        Rectangle::new(
-            Point::new(center.x - 90, center.y - 140),
-            Size::new(180, 280),
-        )
-        .into_styled(
-            PrimitiveStyleBuilder::new()
-                .stroke_color(STROKE_COLOR)
-                .stroke_width(1)
-                .build(),
-        )
-        .draw(&mut frame_buffer)?;
-        let mut character_style = MonoTextStyle::new(&FONT_10X20, Rgb888::RED);
-        character_style.background_color = Some(BACKGROUND_COLOR);
-        Text::with_alignment(
+        let mut character_style = draw_dialog(
+            &mut frame_buffer,
+            Rectangle::with_center(center, Size::new(200, 300)),
             "Resolution",
-            Point::new(center.x, center.y - 135),
-            character_style,
-            Alignment::Center,
-        )
-        .draw(&mut frame_buffer)?;
-        character_style.text_color = Some(Rgb888::BLUE);
-        Text::with_alignment(
             "<Enter>",
-            Point::new(center.x, center.y + 120),
-            character_style,
-            Alignment::Center,
-        )
-        .draw(&mut frame_buffer)?;
+        )?;
         let dialog_box = Rectangle::new(
             Point::new(center.x - 80, center.y - 100),
             Size::new(160, 200),
@@ -183,6 +241,339 @@ impl Interaction for GraphicsOutput<'_> {
             }
         }
     }
+
+    fn select(&mut self, title: &str, items: &[&str]) -> uefi::Result<usize> {
+        if items.is_empty() {
+            return Err(Error::from(Status::NOT_FOUND));
+        }
+        let mut frame_buffer = FrameBuffer::from(&mut *self);
+        let (x, y) = self.current_mode_info().resolution();
+        let center = Point::new(x as i32 >> 1, y as i32 >> 1);
+        let columns = items
+            .iter()
+            .map(|item| item.chars().count())
+            .max()
+            .unwrap_or_default()
+            .clamp(15, (x / 10).saturating_sub(5).max(15));
+        let half = columns as i32 * 5;
+        let mut character_style = draw_dialog(
+            &mut frame_buffer,
+            Rectangle::with_center(center, Size::new(half as u32 * 2 + 50, 300)),
+            title,
+            "<Enter>",
+        )?;
+        let dialog_box = Rectangle::new(
+            Point::new(center.x - half - 5, center.y - 100),
+            Size::new(half as u32 * 2 + 10, 200),
+        )
+        .into_styled(PrimitiveStyle::with_fill(BACKGROUND_COLOR));
+        let mut system_table = uefi_services::system_table();
+        let system_table = unsafe { system_table.as_mut() };
+        let key_event = system_table.stdin().wait_for_key_event();
+        let key_event = unsafe { key_event.unsafe_clone() };
+        let mut events = [key_event];
+        let bound = items.len().min(5);
+        let position = Point::new(center.x, center.y - 35 * (bound >> 1) as i32);
+        let mut index = items.len() - (bound >> 1);
+        loop {
+            dialog_box.draw(&mut frame_buffer)?;
+            let mut position = position;
+            (0..bound).into_iter().try_for_each(|i| {
+                character_style.text_color = Some(match i == bound >> 1 {
+                    false => Rgb888::BLACK,
+                    true => Rgb888::BLUE,
+                });
+                let text = items[(index + i) % items.len()];
+                let text = match text.char_indices().nth(columns) {
+                    Some((end, _)) => &text[..end],
+                    None => text,
+                };
+                Text::with_alignment(text, position, character_style, Alignment::Center)
+                    .draw(&mut frame_buffer)
+                    .map(|_| position.y += 30)
+            })?;
+            while system_table
+                .boot_services()
+                .wait_for_event(&mut events)
+                .is_ok()
+            {
+                if let Some(key) = system_table.stdin().read_key()? {
+                    match key {
+                        Key::Printable(c) if '\r' == c.into() => {
+                            return Ok(((bound >> 1) + index) % items.len());
+                        }
+                        Key::Special(c) => match c {
+                            ScanCode::ESCAPE => return Err(Error::from(Status::ABORTED)),
+                            ScanCode::UP => {
+                                index += items.len() - 1;
+                                index %= items.len();
+                                break;
+                            }
+                            ScanCode::DOWN => {
+                                index += 1;
+                                break;
+                            }
+                            _ => (),
+                        },
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Edits one line of text in a dialog as wide as the screen allows, with
+    /// Control and the arrows jumping between words.
+    fn edit(&mut self, image_handle: Handle, title: &str, text: &str) -> uefi::Result<String> {
+        let mut frame_buffer = FrameBuffer::from(&mut *self);
+        let (x, y) = self.current_mode_info().resolution();
+        let center = Point::new(x as i32 >> 1, y as i32 >> 1);
+        let width = (x as u32).saturating_sub(40).min(1000);
+        let mut character_style = draw_dialog(
+            &mut frame_buffer,
+            Rectangle::with_center(center, Size::new(width, 120)),
+            title,
+            "<Enter> accept  <Esc> cancel",
+        )?;
+        let line = Rectangle::new(
+            Point::new(center.x - (width as i32 >> 1) + 20, center.y - 15),
+            Size::new(width.saturating_sub(40), 25),
+        );
+        let columns = (line.size.width as usize / 10).max(1);
+        let mut system_table = uefi_services::system_table();
+        let system_table = unsafe { system_table.as_mut() };
+        let key_event = system_table.stdin().wait_for_key_event();
+        let key_event = unsafe { key_event.unsafe_clone() };
+        let mut events = [key_event];
+        let mut editor = LineEditor::new(text);
+        let mut first = 0;
+        loop {
+            first = first.min(editor.cursor());
+            first = first.max((editor.cursor() + 1).saturating_sub(columns));
+            line.into_styled(PrimitiveStyle::with_fill(BACKGROUND_COLOR))
+                .draw(&mut frame_buffer)?;
+            let chars = editor.chars().iter().chain([&' ']);
+            let mut position = line.top_left + Point::new(0, 15);
+            for (i, &c) in chars.enumerate().skip(first).take(columns) {
+                let is_cursor = i == editor.cursor();
+                character_style.text_color = Some(match is_cursor {
+                    false => Rgb888::BLACK,
+                    true => BACKGROUND_COLOR,
+                });
+                character_style.background_color = Some(match is_cursor {
+                    false => BACKGROUND_COLOR,
+                    true => Rgb888::BLUE,
+                });
+                let mut buffer = [0; 4];
+                Text::new(c.encode_utf8(&mut buffer), position, character_style)
+                    .draw(&mut frame_buffer)?;
+                position.x += 10;
+            }
+            while system_table
+                .boot_services()
+                .wait_for_event(&mut events)
+                .is_ok()
+            {
+                if let Some(key_stroke) = input::read_key(image_handle)? {
+                    match key_stroke.key {
+                        Key::Printable(c) if '\r' == c.into() => return Ok(editor.text()),
+                        Key::Special(ScanCode::ESCAPE) => return Err(Error::from(Status::ABORTED)),
+                        _ if editor.handle(&key_stroke) => break,
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Counts `seconds` down in a dialog, failing with `ABORTED` as soon as a
+    /// key is pressed.
+    fn countdown(&mut self, title: &str, seconds: u64) -> uefi::Result {
+        let mut frame_buffer = FrameBuffer::from(&mut *self);
+        let (x, y) = self.current_mode_info().resolution();
+        let center = Point::new(x as i32 >> 1, y as i32 >> 1);
+        let character_style = draw_dialog(
+            &mut frame_buffer,
+            Rectangle::with_center(center, Size::new(400, 120)),
+            title,
+            "<Any key> cancel",
+        )?;
+        let line = Rectangle::new(
+            Point::new(center.x - 180, center.y - 15),
+            Size::new(360, 25),
+        )
+        .into_styled(PrimitiveStyle::with_fill(BACKGROUND_COLOR));
+        let mut system_table = uefi_services::system_table();
+        let system_table = unsafe { system_table.as_mut() };
+        let boot_services = system_table.boot_services();
+        let timer =
+            unsafe { boot_services.create_event(EventType::TIMER, Tpl::APPLICATION, None, None)? };
+        boot_services.set_timer(&timer, TimerTrigger::Periodic(10_000_000))?;
+        let key_event = system_table.stdin().wait_for_key_event();
+        let mut events = unsafe { [key_event.unsafe_clone(), timer.unsafe_clone()] };
+        let mut count_down = || {
+            for remaining in (1..=seconds).rev() {
+                line.draw(&mut frame_buffer)?;
+                let text = format!("{remaining} s");
+                Text::with_alignment(
+                    &text,
+                    Point::new(center.x, center.y + 5),
+                    character_style,
+                    Alignment::Center,
+                )
+                .draw(&mut frame_buffer)?;
+                let index = system_table
+                    .boot_services()
+                    .wait_for_event(&mut events)
+                    .map_err(|err| Error::from(err.status()))?;
+                if index == 0 {
+                    system_table.stdin().read_key()?;
+                    return Err(Error::from(Status::ABORTED));
+                }
+            }
+            Ok(())
+        };
+        let result = count_down();
+        system_table.boot_services().close_event(timer)?;
+        result
+    }
+
+    /// Shows `lines` in a dialog as wide as the longest of them and waits for
+    /// a key.
+    fn alert(&mut self, title: &str, lines: &[&str]) -> uefi::Result {
+        let mut frame_buffer = FrameBuffer::from(&mut *self);
+        let (x, y) = self.current_mode_info().resolution();
+        let center = Point::new(x as i32 >> 1, y as i32 >> 1);
+        let columns = lines
+            .iter()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or_default()
+            .clamp(15, (x / 10).saturating_sub(5).max(15));
+        let half = columns as i32 * 5;
+        let half_height = 15 * lines.len() as i32 + 45;
+        let character_style = draw_dialog(
+            &mut frame_buffer,
+            Rectangle::with_center(
+                center,
+                Size::new(half as u32 * 2 + 50, half_height as u32 * 2 + 30),
+            ),
+            title,
+            "<Any key> continue",
+        )?;
+        let mut position = Point::new(center.x, center.y - half_height + 50);
+        for line in lines {
+            let line = match line.char_indices().nth(columns) {
+                Some((end, _)) => &line[..end],
+                None => line,
+            };
+            Text::with_alignment(line, position, character_style, Alignment::Center)
+                .draw(&mut frame_buffer)?;
+            position.y += 30;
+        }
+        let mut system_table = uefi_services::system_table();
+        let system_table = unsafe { system_table.as_mut() };
+        let key_event = system_table.stdin().wait_for_key_event();
+        let mut events = unsafe { [key_event.unsafe_clone()] };
+        system_table
+            .boot_services()
+            .wait_for_event(&mut events)
+            .map_err(|err| Error::from(err.status()))?;
+        system_table.stdin().read_key().map(|_| ())
+    }
+
+    /// Shows how much of `total` bytes is done in a dialog with a progress
+    /// bar, or only the byte count if the total is unknown.
+    fn progress(&mut self, title: &str, done: u64, total: Option<u64>) -> uefi::Result {
+        let mut frame_buffer = FrameBuffer::from(&mut *self);
+        let (x, y) = self.current_mode_info().resolution();
+        let center = Point::new(x as i32 >> 1, y as i32 >> 1);
+        let title = match title.char_indices().nth(36) {
+            Some((end, _)) => &title[..end],
+            None => title,
+        };
+        let character_style = draw_dialog(
+            &mut frame_buffer,
+            Rectangle::with_center(center, Size::new(400, 120)),
+            title,
+            "",
+        )?;
+        let bar = Rectangle::new(
+            Point::new(center.x - 170, center.y - 10),
+            Size::new(340, 20),
+        );
+        bar.into_styled(
+            PrimitiveStyleBuilder::new()
+                .stroke_color(STROKE_COLOR)
+                .stroke_width(1)
+                .build(),
+        )
+        .draw(&mut frame_buffer)?;
+        if let Some(total) = total.filter(|&total| total > 0) {
+            let width = (done.min(total) * (bar.size.width as u64 - 4) / total) as u32;
+            Rectangle::new(bar.top_left + Point::new(2, 2), Size::new(width, 16))
+                .into_styled(PrimitiveStyle::with_fill(Rgb888::BLUE))
+                .draw(&mut frame_buffer)?;
+        }
+        // Tenths of a mebibyte, without floats that some targets only emulate.
+        let mebibytes = |bytes: u64| {
+            let tenths = bytes / (1 << 20) * 10 + bytes % (1 << 20) * 10 / (1 << 20);
+            format!("{}.{}", tenths / 10, tenths % 10)
+        };
+        let text = match total {
+            Some(total) => format!("{} of {} MiB", mebibytes(done), mebibytes(total)),
+            None => format!("{} MiB", mebibytes(done)),
+        };
+        Text::with_alignment(
+            &text,
+            Point::new(center.x, center.y + 35),
+            character_style,
+            Alignment::Center,
+        )
+        .draw(&mut frame_buffer)?;
+        Ok(())
+    }
+}
+
+/// Draws the box every dialog shares within `rect`: a border, `title` at the
+/// top and `hint` about the keys at the bottom. Returns the style for the
+/// text in between.
+fn draw_dialog(
+    frame_buffer: &mut FrameBuffer,
+    rect: Rectangle,
+    title: &str,
+    hint: &str,
+) -> uefi::Result<MonoTextStyle<'static, Rgb888>> {
+    rect.into_styled(PrimitiveStyle::with_fill(BACKGROUND_COLOR))
+        .draw(frame_buffer)?;
+    rect.offset(-10)
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .stroke_color(STROKE_COLOR)
+                .stroke_width(1)
+                .build(),
+        )
+        .draw(frame_buffer)?;
+    let center = rect.center().x;
+    let mut character_style = MonoTextStyle::new(&FONT_10X20, Rgb888::RED);
+    character_style.background_color = Some(BACKGROUND_COLOR);
+    Text::with_alignment(
+        title,
+        Point::new(center, rect.top_left.y + 15),
+        character_style,
+        Alignment::Center,
+    )
+    .draw(frame_buffer)?;
+    character_style.text_color = Some(Rgb888::BLUE);
+    Text::with_alignment(
+        hint,
+        Point::new(center, rect.top_left.y + rect.size.height as i32 - 20),
+        character_style,
+        Alignment::Center,
+    )
+    .draw(frame_buffer)?;
+    character_style.text_color = Some(Rgb888::BLACK);
+    Ok(character_style)
 }
 
 pub trait DrawMasked: DrawTarget + Sized {
@@ -0,0 +1,300 @@
+use super::{
+    bytes::{le_u32, le_u64},
+    fs,
+    vfs::{BlockDevice, Disk as _},
+};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use uefi::{
+    prelude::*,
+    proto::{
+        device_path::{DevicePath, DeviceSubType, DeviceType},
+        media::block::BlockIO,
+    },
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams},
+    Guid, Result,
+};
+
+const SIGNATURE: &[u8] = b"EFI PART";
+const HEADER_SIZE: usize = 92;
+const ENTRY_SIZE: usize = 128;
+/// More entries and bigger ones than any table has, so that a corrupt header
+/// doesn't make the loader read the whole disk.
+const MAX_ENTRIES: u32 = 1024;
+const MAX_ENTRY_SIZE: u32 = 4096;
+
+static PARTITION_TABLES: AtomicPtr<Vec<PartitionTable>> = AtomicPtr::new(ptr::null_mut());
+
+/// The GUID partition table of a disk.
+#[derive(Debug, Clone)]
+pub struct PartitionTable {
+    /// The GUID of the disk.
+    pub guid: Guid,
+    pub partitions: Vec<Partition>,
+}
+
+/// A used entry of a GUID partition table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub type_guid: Guid,
+    pub guid: Guid,
+    pub label: String,
+}
+
+struct Header {
+    guid: Guid,
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: u32,
+    entries_crc32: u32,
+}
+
+/// The partition table of every disk that has one, read on the first call
+/// only since disks don't come and go while the loader runs.
+fn partition_tables(image_handle: Handle) -> &'static [PartitionTable] {
+    let mut tables = PARTITION_TABLES.load(Ordering::Acquire);
+    if tables.is_null() {
+        tables = Box::into_raw(Box::new(read_tables(image_handle)));
+        PARTITION_TABLES.store(tables, Ordering::Release);
+    }
+    unsafe { &*tables }
+}
+
+/// Reads the partition tables, falling back to the backup table at the end of
+/// the disk when the primary one is damaged.
+fn read_tables(image_handle: Handle) -> Vec<PartitionTable> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let handles = boot_services.find_handles::<BlockIO>().unwrap_or_default();
+    handles
+        .into_iter()
+        .filter_map(|handle| {
+            let device = BlockDevice::open(image_handle, handle)?;
+            if device.is_partition {
+                return None;
+            }
+            [1, device.last_block]
+                .into_iter()
+                .find_map(|lba| read_table(&device, lba))
+        })
+        .collect()
+}
+
+/// Finds the volume that `device` names by the `PARTUUID=` or `PARTLABEL=` of
+/// its GUID partition table entry, or by the `LABEL=` of its file system.
+/// Returns `None` for other devices, which are device paths.
+pub fn find(image_handle: Handle, device: &str) -> Option<Result<Handle>> {
+    let partition = match device.split_once('=')? {
+        ("LABEL", label) => {
+            let volume = fs::volumes()
+                .into_iter()
+                .find(|&volume| fs::volume_label(image_handle, volume).as_deref() == Some(label));
+            return Some(volume.ok_or_else(|| Status::NOT_FOUND.into()));
+        }
+        ("PARTUUID", guid) => parse_guid(guid),
+        ("PARTLABEL", label) => partition_tables(image_handle)
+            .iter()
+            .flat_map(|table| &table.partitions)
+            .find(|partition| partition.label == label)
+            .map(|partition| partition.guid),
+        _ => return None,
+    };
+    let partition = match partition {
+        Some(partition) => partition,
+        None => return Some(Err(Status::NOT_FOUND.into())),
+    };
+    let volume = fs::volumes()
+        .into_iter()
+        .find(|&volume| partition_guid(image_handle, volume) == Some(partition));
+    Some(volume.ok_or_else(|| Status::NOT_FOUND.into()))
+}
+
+/// The GUID in the hard drive node of the device path of `volume`, which the
+/// firmware copies from the partition table.
+fn partition_guid(image_handle: Handle, volume: Handle) -> Option<Guid> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let device_path = system_table
+        .boot_services()
+        .open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle: volume,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+        .ok()?;
+    let device_path = unsafe { &*device_path.interface.get() };
+    device_path.node_iter().find_map(|node| {
+        if node.full_type() != (DeviceType::MEDIA, DeviceSubType::MEDIA_HARD_DRIVE)
+            || node.length() < 42
+        {
+            return None;
+        }
+        let bytes = unsafe { core::slice::from_raw_parts(node.as_ffi_ptr().cast::<u8>(), 42) };
+        // A signature type of 2 means a GUID rather than an MBR disk signature.
+        (bytes[41] == 2).then(|| guid(&bytes[24..40]))
+    })
+}
+
+/// Reads the header at `lba` and the entries it points to.
+fn read_table(device: &BlockDevice, lba: u64) -> Option<PartitionTable> {
+    let block_size = device.block_size as u64;
+    let mut block = vec![0; device.block_size as usize];
+    device.read(lba * block_size, &mut block).ok()?;
+    let header = parse_header(&block, lba)?;
+    let mut entries = vec![0; header.entry_count as usize * header.entry_size as usize];
+    device
+        .read(header.entries_lba * block_size, &mut entries)
+        .ok()?;
+    if crc32(&entries) != header.entries_crc32 {
+        return None;
+    }
+    let partitions = entries
+        .chunks_exact(header.entry_size as usize)
+        .filter_map(parse_entry)
+        .collect();
+    Some(PartitionTable {
+        guid: header.guid,
+        partitions,
+    })
+}
+
+fn parse_header(block: &[u8], lba: u64) -> Option<Header> {
+    if block.len() < HEADER_SIZE || &block[..8] != SIGNATURE {
+        return None;
+    }
+    let size = le_u32(block, 12)? as usize;
+    if !(HEADER_SIZE..=block.len()).contains(&size) || le_u64(block, 24)? != lba {
+        return None;
+    }
+    let mut header = block[..size].to_vec();
+    header[16..20].fill(0);
+    if crc32(&header) != le_u32(block, 16)? {
+        return None;
+    }
+    let entry_count = le_u32(block, 80)?;
+    let entry_size = le_u32(block, 84)?;
+    if !(ENTRY_SIZE as u32..=MAX_ENTRY_SIZE).contains(&entry_size) || entry_count > MAX_ENTRIES {
+        return None;
+    }
+    Some(Header {
+        guid: guid(&block[56..72]),
+        entries_lba: le_u64(block, 72)?,
+        entry_count,
+        entry_size,
+        entries_crc32: le_u32(block, 88)?,
+    })
+}
+
+/// Parses an entry of the table, which is unused if its type is all zeros.
+fn parse_entry(entry: &[u8]) -> Option<Partition> {
+    let type_guid = guid(&entry[..16]);
+    if type_guid == Guid::default() {
+        return None;
+    }
+    let name = entry[56..ENTRY_SIZE]
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0);
+    Some(Partition {
+        type_guid,
+        guid: guid(&entry[16..32]),
+        label: char::decode_utf16(name)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect(),
+    })
+}
+
+/// Reads a GUID stored like UEFI stores them, with its first three fields
+/// little-endian.
+fn guid(bytes: &[u8]) -> Guid {
+    Guid::from_values(
+        u32::from_le_bytes(bytes[..4].try_into().expect("4 bytes")),
+        u16::from_le_bytes(bytes[4..6].try_into().expect("2 bytes")),
+        u16::from_le_bytes(bytes[6..8].try_into().expect("2 bytes")),
+        u16::from_be_bytes(bytes[8..10].try_into().expect("2 bytes")),
+        bytes[10..16]
+            .iter()
+            .fold(0, |node, &byte| node << 8 | byte as u64),
+    )
+}
+
+/// Parses a GUID in its canonical text form, in either case.
+fn parse_guid(text: &str) -> Option<Guid> {
+    let fields = text.split('-').collect::<Vec<_>>();
+    let lengths = fields.iter().map(|field| field.len()).collect::<Vec<_>>();
+    if lengths != [8, 4, 4, 4, 12] || !text.chars().all(|c| c == '-' || c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let field = |index: usize| u64::from_str_radix(fields[index], 16).expect("hex digits");
+    Some(Guid::from_values(
+        field(0) as u32,
+        field(1) as u16,
+        field(2) as u16,
+        field(3) as u16,
+        field(4),
+    ))
+}
+
+/// The CRC-32 the partition table checks its header and entries with.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[test_case]
+fn partition_table() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..16].copy_from_slice(&[
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ]);
+    entry[16..32].copy_from_slice(&[
+        0x78, 0x56, 0x34, 0x12, 0xbc, 0x9a, 0xf0, 0xde, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+        0xef,
+    ]);
+    for (index, unit) in "boot".encode_utf16().enumerate() {
+        entry[56 + 2 * index..58 + 2 * index].copy_from_slice(&unit.to_le_bytes());
+    }
+    let partition = parse_entry(&entry).unwrap();
+    assert_eq!(
+        format!("{}", partition.type_guid),
+        "c12a7328-f81f-11d2-ba4b-00a0c93ec93b"
+    );
+    assert_eq!(
+        partition.guid,
+        parse_guid("12345678-9ABC-DEF0-0123-456789abcdef").unwrap()
+    );
+    assert_eq!(partition.label, "boot");
+    assert!(parse_entry(&[0; ENTRY_SIZE]).is_none());
+    assert!(parse_guid("12345678-9abc").is_none());
+
+    let mut block = [0u8; 512];
+    block[..8].copy_from_slice(SIGNATURE);
+    block[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+    block[24..32].copy_from_slice(&1u64.to_le_bytes());
+    block[56..72].copy_from_slice(&entry[16..32]);
+    block[72..80].copy_from_slice(&2u64.to_le_bytes());
+    block[80..84].copy_from_slice(&128u32.to_le_bytes());
+    block[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+    let crc = crc32(&block[..HEADER_SIZE]);
+    block[16..20].copy_from_slice(&crc.to_le_bytes());
+    let header = parse_header(&block, 1).unwrap();
+    assert_eq!(header.guid, partition.guid);
+    assert_eq!((header.entries_lba, header.entry_count), (2, 128));
+    assert!(parse_header(&block, 2).is_none());
+    block[100] = 1;
+    assert!(parse_header(&block, 1).is_some());
+    block[60] ^= 1;
+    assert!(parse_header(&block, 1).is_none());
+}
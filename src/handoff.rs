@@ -0,0 +1,124 @@
+use super::{
+    boot,
+    cfg::BootEntry,
+    elf::{Elf, EM_X86_64},
+    gop::{self, FrameBufferInfo},
+    map::{self, MemoryRegion, PAGE_SIZE},
+    paging::{PageTable, HHDM_OFFSET},
+};
+use core::{arch::asm, mem::size_of, ptr};
+use uefi::{
+    prelude::*,
+    table::boot::{AllocateType, MemoryType},
+    Error, Result,
+};
+
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"UEFIBOOT");
+pub const BOOT_INFO_VERSION: u32 = 1;
+
+const STACK_SIZE: usize = 0x10000;
+
+/// What an ELF kernel receives in `rdi` when it is entered with the System V
+/// calling convention.
+///
+/// The kernel runs on the loader's page tables: physical memory is identity
+/// mapped and mirrored at `hhdm_offset`, and higher-half kernels are also
+/// mapped at their link address. Interrupts are disabled, the firmware's GDT
+/// is still loaded and `rsp` points to a 64 KiB stack. All addresses in here
+/// are physical. The memory map, the stack, the page tables and this struct
+/// live in `BootloaderReclaimable` memory, which is only free once the
+/// kernel no longer uses them.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    pub _reserved: u32,
+    pub memory_map: u64,
+    pub memory_map_len: u64,
+    pub frame_buffer: FrameBufferInfo,
+    pub rsdp: u64,
+    pub cmdline: u64,
+    pub cmdline_len: u64,
+    pub kernel_physical_base: u64,
+    pub kernel_virtual_base: u64,
+    pub kernel_size: u64,
+    pub hhdm_offset: u64,
+    pub system_table: u64,
+}
+
+/// Loads `entry` as an x86_64 ELF kernel and jumps to it after
+/// `ExitBootServices`.
+pub fn boot(image_handle: Handle, entry: &BootEntry) -> Result {
+    let image = boot::load_kernel(image_handle, entry)?;
+    let elf = Elf::parse(&image, EM_X86_64).ok_or_else(|| Error::from(Status::LOAD_ERROR))?;
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let kernel = elf.load(boot_services)?;
+    let frame_buffer = FrameBufferInfo::try_from(gop::get())?;
+    let page_table = PageTable::for_kernel(boot_services, &kernel, &frame_buffer)?;
+    let cmdline = entry.options.as_bytes();
+    let cmdline_addr = allocate(boot_services, cmdline.len() + 1)?;
+    unsafe {
+        let cmdline_addr = cmdline_addr as *mut u8;
+        ptr::copy_nonoverlapping(cmdline.as_ptr(), cmdline_addr, cmdline.len());
+        cmdline_addr.add(cmdline.len()).write(0);
+    }
+    let stack = allocate(boot_services, STACK_SIZE)?;
+    let boot_info = allocate(boot_services, size_of::<BootInfo>())?;
+    let boot_info = unsafe { &mut *(boot_info as *mut BootInfo) };
+    *boot_info = BootInfo {
+        magic: BOOT_INFO_MAGIC,
+        version: BOOT_INFO_VERSION,
+        _reserved: 0,
+        memory_map: 0,
+        memory_map_len: 0,
+        frame_buffer,
+        rsdp: boot::acpi_rsdp().unwrap_or(0),
+        cmdline: cmdline_addr,
+        cmdline_len: cmdline.len() as u64,
+        kernel_physical_base: kernel.physical_base,
+        kernel_virtual_base: kernel.virtual_base,
+        kernel_size: kernel.size,
+        hhdm_offset: HHDM_OFFSET,
+        system_table: 0,
+    };
+    let (system_table, memory_map) = map::exit_boot_services(image_handle)?;
+    let regions = memory_map.regions();
+    boot_info.memory_map = regions.as_ptr() as u64;
+    boot_info.memory_map_len = regions.len() as u64;
+    boot_info.system_table = system_table.get_current_system_table_addr();
+    unsafe {
+        enter(
+            kernel.entry,
+            page_table.address(),
+            stack + STACK_SIZE as u64,
+            boot_info,
+        )
+    }
+}
+
+fn allocate(boot_services: &BootServices, size: usize) -> Result<u64> {
+    let count = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    boot_services.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, count)
+}
+
+/// Switches to the kernel's page tables and stack, leaving a null return
+/// address so that `rsp` is aligned as on any other function entry.
+unsafe fn enter(entry: u64, page_table: u64, stack: u64, boot_info: &mut BootInfo) -> ! {
+    asm!(
+        "cli",
+        "mov cr3, rdx",
+        "mov rsp, rcx",
+        "push 0",
+        "jmp rsi",
+        in("rsi") entry,
+        in("rdx") page_table,
+        in("rcx") stack,
+        in("rdi") boot_info,
+        options(noreturn),
+    )
+}
+
+const _: () = assert!(size_of::<MemoryRegion>() == 24);
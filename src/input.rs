@@ -0,0 +1,220 @@
+use alloc::{string::String, vec::Vec};
+use core::mem::MaybeUninit;
+use uefi::{
+    prelude::*,
+    proto::{
+        console::text::{Input, Key, ScanCode},
+        Protocol,
+    },
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams},
+    unsafe_guid, Char16, Event, Result,
+};
+
+const SHIFT_STATE_VALID: u32 = 0x8000_0000;
+const RIGHT_CONTROL_PRESSED: u32 = 0x04;
+const LEFT_CONTROL_PRESSED: u32 = 0x08;
+
+/// A keystroke with whether Control was held, which only the extended text
+/// input protocol reports.
+pub struct KeyStroke {
+    pub key: Key,
+    pub control: bool,
+}
+
+/// Reads a keystroke from the console, through the extended text input
+/// protocol when the console has it.
+pub fn read_key(image_handle: Handle) -> Result<Option<KeyStroke>> {
+    let system_table = uefi_services::system_table();
+    let boot_services = unsafe { system_table.as_ref() }.boot_services();
+    let input = console_in(image_handle).and_then(|console_in| {
+        let input = boot_services.open_protocol::<SimpleTextInputEx>(
+            OpenProtocolParams {
+                handle: console_in,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        );
+        input.ok()
+    });
+    let input = match input {
+        Some(input) => input,
+        None => {
+            let mut system_table = uefi_services::system_table();
+            let key = unsafe { system_table.as_mut() }.stdin().read_key()?;
+            return Ok(key.map(|key| KeyStroke {
+                key,
+                control: false,
+            }));
+        }
+    };
+    let input = unsafe { &mut *input.interface.get() };
+    let mut key_data = MaybeUninit::<KeyData>::uninit();
+    match unsafe { (input.read_key_stroke_ex)(input, key_data.as_mut_ptr()) } {
+        Status::NOT_READY => Ok(None),
+        status => status.into_with_val(|| {
+            let key_data = unsafe { key_data.assume_init() };
+            let shift_state = match key_data.shift_state & SHIFT_STATE_VALID {
+                0 => 0,
+                _ => key_data.shift_state,
+            };
+            let key = match Char16::try_from(key_data.unicode_char) {
+                Ok(c) if key_data.scan_code == ScanCode::NULL => Key::Printable(c),
+                _ => Key::Special(key_data.scan_code),
+            };
+            Some(KeyStroke {
+                key,
+                control: shift_state & (LEFT_CONTROL_PRESSED | RIGHT_CONTROL_PRESSED) != 0,
+            })
+        }),
+    }
+}
+
+/// The handle of the console input, found as the one its simple text input
+/// protocol is installed on since `SystemTable` doesn't expose
+/// `ConsoleInHandle`.
+fn console_in(image_handle: Handle) -> Option<Handle> {
+    let mut system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_mut() };
+    let stdin = system_table.stdin() as *mut Input;
+    let boot_services = system_table.boot_services();
+    let handles = boot_services.find_handles::<Input>().ok()?;
+    handles.into_iter().find(|&handle| {
+        let input = boot_services.open_protocol::<Input>(
+            OpenProtocolParams {
+                handle,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        );
+        input.map_or(false, |input| input.interface.get() == stdin)
+    })
+}
+
+#[repr(C)]
+struct KeyData {
+    scan_code: ScanCode,
+    unicode_char: u16,
+    shift_state: u32,
+    _toggle_state: u8,
+}
+
+#[repr(C)]
+#[unsafe_guid("dd9e7534-7762-4698-8c14-f58517a625aa")]
+#[derive(Protocol)]
+struct SimpleTextInputEx {
+    _reset: usize,
+    read_key_stroke_ex:
+        unsafe extern "efiapi" fn(this: &mut SimpleTextInputEx, key_data: *mut KeyData) -> Status,
+    _wait_for_key_ex: Event,
+}
+
+/// A single line of text being edited, with a cursor between characters.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LineEditor {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl LineEditor {
+    pub fn new(text: &str) -> Self {
+        let chars = text.chars().collect::<Vec<_>>();
+        Self {
+            cursor: chars.len(),
+            chars,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    pub fn chars(&self) -> &[char] {
+        &self.chars
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Applies an editing key, returning whether it was one.
+    pub fn handle(&mut self, key_stroke: &KeyStroke) -> bool {
+        match (&key_stroke.key, key_stroke.control) {
+            (Key::Special(ScanCode::LEFT), false) => self.cursor = self.cursor.saturating_sub(1),
+            (Key::Special(ScanCode::RIGHT), false) => {
+                self.cursor = (self.cursor + 1).min(self.chars.len())
+            }
+            (Key::Special(ScanCode::LEFT), true) => self.cursor = self.word_start(),
+            (Key::Special(ScanCode::RIGHT), true) => self.cursor = self.word_end(),
+            (Key::Special(ScanCode::HOME), _) => self.cursor = 0,
+            (Key::Special(ScanCode::END), _) => self.cursor = self.chars.len(),
+            (Key::Special(ScanCode::DELETE), _) => {
+                if self.cursor < self.chars.len() {
+                    self.chars.remove(self.cursor);
+                }
+            }
+            (&Key::Printable(c), _) => match char::from(c) {
+                '\u{8}' => {
+                    if self.cursor > 0 {
+                        self.cursor -= 1;
+                        self.chars.remove(self.cursor);
+                    }
+                }
+                c if !c.is_control() => {
+                    self.chars.insert(self.cursor, c);
+                    self.cursor += 1;
+                }
+                _ => return false,
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// The start of the word before the cursor.
+    fn word_start(&self) -> usize {
+        let is_space = |&i: &usize| self.chars[i - 1].is_whitespace();
+        let mut cursor = self.cursor;
+        cursor -= (1..=cursor).rev().take_while(is_space).count();
+        cursor - (1..=cursor).rev().take_while(|i| !is_space(i)).count()
+    }
+
+    /// The end of the word after the cursor.
+    fn word_end(&self) -> usize {
+        let is_space = |&i: &usize| self.chars[i].is_whitespace();
+        let len = self.chars.len();
+        let mut cursor = self.cursor;
+        cursor += (cursor..len).take_while(is_space).count();
+        cursor + (cursor..len).take_while(|i| !is_space(i)).count()
+    }
+}
+
+#[test_case]
+fn line_editor() {
+    let key = |key| KeyStroke {
+        key,
+        control: false,
+    };
+    let printable = |c| key(Key::Printable(Char16::try_from(c).unwrap()));
+    let word = |scan_code| KeyStroke {
+        key: Key::Special(scan_code),
+        control: true,
+    };
+    let mut editor = LineEditor::new("root=/dev/sda1 quiet");
+    editor.handle(&word(ScanCode::LEFT));
+    assert_eq!(editor.cursor(), 15);
+    editor.handle(&word(ScanCode::LEFT));
+    assert_eq!(editor.cursor(), 0);
+    editor.handle(&word(ScanCode::RIGHT));
+    assert_eq!(editor.cursor(), 14);
+    " nomodeset".chars().for_each(|c| {
+        editor.handle(&printable(c));
+    });
+    assert_eq!(editor.cursor(), 24);
+    editor.handle(&key(Key::Special(ScanCode::END)));
+    editor.handle(&printable('\u{8}'));
+    editor.handle(&key(Key::Special(ScanCode::HOME)));
+    editor.handle(&key(Key::Special(ScanCode::DELETE)));
+    assert_eq!(editor.text(), "oot=/dev/sda1 nomodeset quie");
+}
@@ -0,0 +1,585 @@
+use super::{
+    boot,
+    cfg::BootEntry,
+    elf::{Elf, Kernel, EM_X86_64},
+    gop::{self, FrameBufferInfo},
+    map::{self, MemoryKind, KERNEL_AND_MODULES, PAGE_SIZE},
+    paging::{PageTable, HHDM_OFFSET},
+    smp::{self, ApStartup, Cpu, GDT},
+};
+use alloc::vec::Vec;
+use core::{
+    arch::asm,
+    mem::{size_of, size_of_val},
+    ptr, slice,
+};
+use uefi::{
+    prelude::*,
+    table::boot::{AllocateType, MemoryType},
+    Error, Result,
+};
+
+const COMMON_MAGIC: [u64; 2] = [0xc7b1_dd30_df4c_8b88, 0x0a82_e883_a194_f07b];
+const BASE_REVISION_MAGIC: [u64; 2] = [0xf956_2b2d_5c95_a6c8, 0x6a7b_3849_4453_6bdc];
+
+const FRAMEBUFFER_REQUEST: [u64; 2] = [0x9d58_27dc_d881_dd75, 0xa314_8604_f6fa_b11b];
+const MEMMAP_REQUEST: [u64; 2] = [0x67cf_3d9d_378a_806f, 0xe304_acdf_c50c_3c62];
+const HHDM_REQUEST: [u64; 2] = [0x48dc_f1cb_8ad2_b852, 0x6398_4e95_9a98_244b];
+const RSDP_REQUEST: [u64; 2] = [0xc5e7_7b6b_397e_7b43, 0x2763_7845_accd_cf3c];
+const MODULE_REQUEST: [u64; 2] = [0x3e7e_2797_02be_32af, 0xca1c_4f3b_d128_0cee];
+const SMP_REQUEST: [u64; 2] = [0x95a6_7b81_9a1b_857e, 0xa0b6_1b72_3b6a_73e0];
+const KERNEL_ADDRESS_REQUEST: [u64; 2] = [0x71ba_7686_3cc5_5f63, 0xb264_4a48_c516_a487];
+const STACK_SIZE_REQUEST: [u64; 2] = [0x224e_f046_0a8e_8926, 0xe1cb_0fc2_5f46_ea3d];
+
+/// The newest base revision whose guarantees this loader keeps.
+const BASE_REVISION: u64 = 1;
+const STACK_SIZE: u64 = 0x10000;
+const SLACK_DESCRIPTORS: usize = 64;
+
+const MEMMAP_USABLE: u64 = 0;
+const MEMMAP_RESERVED: u64 = 1;
+const MEMMAP_ACPI_RECLAIMABLE: u64 = 2;
+const MEMMAP_ACPI_NVS: u64 = 3;
+const MEMMAP_BAD_MEMORY: u64 = 4;
+const MEMMAP_BOOTLOADER_RECLAIMABLE: u64 = 5;
+const MEMMAP_KERNEL_AND_MODULES: u64 = 6;
+
+const FRAMEBUFFER_RGB: u8 = 1;
+const SMP_X2APIC: u32 = 1 << 0;
+
+/// Boots a kernel written for the Limine protocol: every request found in
+/// its loaded image gets a response before it is entered in long mode on
+/// the loader's page tables, with Limine's GDT and all application
+/// processors parked.
+///
+/// Each `initrd` of the entry is a module, optionally followed by a space
+/// and the module's command line. Pointers in responses are HHDM addresses.
+pub fn boot(image_handle: Handle, entry: &BootEntry) -> Result {
+    let image = boot::load_kernel(image_handle, entry)?;
+    let elf = Elf::parse(&image, EM_X86_64).ok_or_else(|| Error::from(Status::LOAD_ERROR))?;
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let kernel = elf.load(boot_services)?;
+    let requests = unsafe { Requests::scan(&kernel) };
+    if let Some(base_revision) = requests.base_revision {
+        let revision = (base_revision + 16) as *mut u64;
+        unsafe {
+            if revision.read() <= BASE_REVISION {
+                revision.write(0);
+            }
+        }
+    }
+    let frame_buffer = FrameBufferInfo::try_from(gop::get())?;
+    let page_table = PageTable::for_kernel(boot_services, &kernel, &frame_buffer)?;
+    let rsdp = boot::acpi_rsdp();
+    let mut modules = Vec::new();
+    for module in &entry.initrd {
+        let (path, cmdline) = module.split_once(' ').unwrap_or((module, ""));
+        let data = boot::load_initrd(image_handle, entry, path)?;
+        let address = allocate(boot_services, KERNEL_AND_MODULES, data.len().max(1))?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), address as *mut u8, data.len()) };
+        modules.push((address, data.len(), path, cmdline));
+    }
+    let bsp_lapic_id = smp::bsp_lapic_id();
+    let mut cpus = match (requests.smp, rsdp) {
+        (Some(_), Some(rsdp)) => smp::cpus(rsdp),
+        _ => Vec::new(),
+    };
+    if !cpus.iter().any(|cpu| cpu.lapic_id == bsp_lapic_id) {
+        cpus.push(Cpu {
+            processor_id: 0,
+            lapic_id: bsp_lapic_id,
+        });
+    }
+    let stack_size = match requests.stack_size {
+        Some(request) => unsafe { ((request + 48) as *const u64).read() }.max(STACK_SIZE),
+        None => STACK_SIZE,
+    };
+    let memory_map_size = boot_services.memory_map_size();
+    let memmap_capacity = memory_map_size.map_size / memory_map_size.entry_size + SLACK_DESCRIPTORS;
+    let strings = modules
+        .iter()
+        .map(|module| module.2.len() + module.3.len() + 16)
+        .sum::<usize>();
+    let mut arena = Arena::new(
+        boot_services,
+        PAGE_SIZE as usize
+            + memmap_capacity * (size_of::<MemmapEntry>() + 8)
+            + cpus.len() * (size_of::<SmpInfo>() + 8)
+            + modules.len() * (size_of::<File>() + 8)
+            + strings,
+    )?;
+    let gdt = arena.push(GDT);
+    let gdtr = arena.push(DescriptorTablePointer {
+        limit: size_of_val(&GDT) as u16 - 1,
+        base: virt(gdt),
+    });
+    let response = arena.push(HhdmResponse {
+        revision: 0,
+        offset: HHDM_OFFSET,
+    });
+    respond(requests.hhdm, response);
+    let response = arena.push(KernelAddressResponse {
+        revision: 0,
+        physical_base: kernel.physical_base,
+        virtual_base: kernel.virtual_base,
+    });
+    respond(requests.kernel_address, response);
+    if let Some(rsdp) = rsdp {
+        let response = arena.push(RsdpResponse {
+            revision: 0,
+            address: virt(rsdp),
+        });
+        respond(requests.rsdp, response);
+    }
+    if frame_buffer.base != 0 {
+        let mask = gop::mask_size_shift;
+        let ((red_mask_size, red_mask_shift), (green_mask_size, green_mask_shift)) =
+            (mask(frame_buffer.red_mask), mask(frame_buffer.green_mask));
+        let (blue_mask_size, blue_mask_shift) = mask(frame_buffer.blue_mask);
+        let framebuffer = arena.push(Framebuffer {
+            address: virt(frame_buffer.base),
+            width: frame_buffer.width as u64,
+            height: frame_buffer.height as u64,
+            pitch: frame_buffer.pitch() as u64,
+            bpp: frame_buffer.bpp as u16,
+            memory_model: FRAMEBUFFER_RGB,
+            red_mask_size,
+            red_mask_shift,
+            green_mask_size,
+            green_mask_shift,
+            blue_mask_size,
+            blue_mask_shift,
+            unused: [0; 7],
+            edid_size: 0,
+            edid: 0,
+        });
+        let framebuffers = arena.push(virt(framebuffer));
+        let response = arena.push(FramebufferResponse {
+            revision: 0,
+            framebuffer_count: 1,
+            framebuffers: virt(framebuffers),
+        });
+        respond(requests.framebuffer, response);
+    }
+    let files = modules
+        .iter()
+        .map(|&(address, size, path, cmdline)| {
+            let path = arena.string(path);
+            let cmdline = arena.string(cmdline);
+            virt(arena.push(File {
+                revision: 0,
+                address: virt(address),
+                size: size as u64,
+                path: virt(path),
+                cmdline: virt(cmdline),
+                ..File::default()
+            }))
+        })
+        .collect::<Vec<_>>();
+    let files = arena.slice(&files);
+    let response = arena.push(ModuleResponse {
+        revision: 0,
+        module_count: modules.len() as u64,
+        modules: virt(files),
+    });
+    respond(requests.module, response);
+    if let Some(request) = requests.stack_size {
+        respond(Some(request), arena.push(0u64));
+    }
+    let memmap_entries = arena.alloc(memmap_capacity * size_of::<MemmapEntry>());
+    let memmap_list = arena.alloc(memmap_capacity * 8);
+    let memmap = arena.push(MemmapResponse {
+        revision: 0,
+        entry_count: 0,
+        entries: virt(memmap_list),
+    });
+    respond(requests.memmap, memmap);
+    let smp = match requests.smp {
+        Some(request) => {
+            let ap_startup = ApStartup::new(boot_services, page_table.address()).ok();
+            let mut aps = Vec::new();
+            for cpu in &cpus {
+                let info = arena.push(SmpInfo {
+                    processor_id: cpu.processor_id,
+                    lapic_id: cpu.lapic_id,
+                    ..SmpInfo::default()
+                });
+                let stack = match cpu.lapic_id == bsp_lapic_id {
+                    true => 0,
+                    false => allocate(boot_services, MemoryType::LOADER_DATA, stack_size as usize)?,
+                };
+                aps.push((cpu.lapic_id, info, stack));
+            }
+            let list = arena.alloc(cpus.len() * 8);
+            let response = arena.push(SmpResponse {
+                revision: 0,
+                flags: match smp::x2apic_enabled() {
+                    true => SMP_X2APIC,
+                    false => 0,
+                },
+                bsp_lapic_id,
+                cpu_count: 0,
+                cpus: virt(list),
+            });
+            respond(Some(request), response);
+            Some((ap_startup, aps, list, response))
+        }
+        None => None,
+    };
+    let stack = allocate(boot_services, MemoryType::LOADER_DATA, stack_size as usize)?;
+    let (_, memory_map) = map::exit_boot_services(image_handle)?;
+    let regions = memory_map.regions();
+    let count = regions.len().min(memmap_capacity);
+    for (index, region) in regions[..count].iter().enumerate() {
+        let entry = memmap_entries + (index * size_of::<MemmapEntry>()) as u64;
+        unsafe {
+            (entry as *mut MemmapEntry).write(MemmapEntry {
+                base: region.base,
+                length: region.length,
+                ty: memory_type(region.kind),
+            });
+            (memmap_list as *mut u64).add(index).write(virt(entry));
+        }
+    }
+    unsafe { (*(memmap as *mut MemmapResponse)).entry_count = count as u64 };
+    if let Some((ap_startup, aps, list, response)) = smp {
+        let mut count = 0;
+        for (lapic_id, info, stack) in aps {
+            let started = lapic_id == bsp_lapic_id
+                || ap_startup.as_ref().map_or(false, |ap_startup| unsafe {
+                    ap_startup.start(lapic_id, virt(stack + stack_size), virt(info))
+                });
+            if started {
+                unsafe { (list as *mut u64).add(count).write(virt(info)) };
+                count += 1;
+            }
+        }
+        unsafe { (*(response as *mut SmpResponse)).cpu_count = count as u64 };
+    }
+    unsafe {
+        enter(
+            kernel.entry,
+            page_table.address(),
+            virt(stack + stack_size),
+            gdtr,
+        )
+    }
+}
+
+fn virt(phys: u64) -> u64 {
+    phys + HHDM_OFFSET
+}
+
+fn respond(request: Option<u64>, response: u64) {
+    if let Some(request) = request {
+        let request = unsafe { &mut *(request as *mut Request) };
+        request.response = virt(response);
+    }
+}
+
+fn allocate(boot_services: &BootServices, ty: MemoryType, size: usize) -> Result<u64> {
+    let count = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    boot_services.allocate_pages(AllocateType::AnyPages, ty, count)
+}
+
+fn memory_type(kind: MemoryKind) -> u64 {
+    match kind {
+        MemoryKind::Usable => MEMMAP_USABLE,
+        MemoryKind::AcpiReclaimable => MEMMAP_ACPI_RECLAIMABLE,
+        MemoryKind::AcpiNvs => MEMMAP_ACPI_NVS,
+        MemoryKind::BadMemory => MEMMAP_BAD_MEMORY,
+        MemoryKind::BootloaderReclaimable => MEMMAP_BOOTLOADER_RECLAIMABLE,
+        MemoryKind::KernelAndModules => MEMMAP_KERNEL_AND_MODULES,
+        MemoryKind::Reserved | MemoryKind::RuntimeServices | MemoryKind::Persistent => {
+            MEMMAP_RESERVED
+        }
+    }
+}
+
+/// Loads Limine's GDT with its 64-bit code (0x28) and data (0x30)
+/// selectors, switches to the kernel's page tables and stack and
+/// jumps to `entry` with a null return address.
+unsafe fn enter(entry: u64, page_table: u64, stack: u64, gdtr: u64) -> ! {
+    asm!(
+        "cli",
+        "mov cr3, rdx",
+        "lgdt [rdi]",
+        "mov rsp, rcx",
+        "push 0x28",
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "retfq",
+        "2:",
+        "mov ax, 0x30",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "mov ss, ax",
+        "xor eax, eax",
+        "xor edi, edi",
+        "push 0",
+        "jmp rsi",
+        in("rsi") entry,
+        in("rdx") page_table,
+        in("rcx") stack,
+        in("rdi") virt(gdtr),
+        options(noreturn),
+    )
+}
+
+/// Where the requests of a kernel are, found by their magic numbers.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Requests {
+    base_revision: Option<u64>,
+    framebuffer: Option<u64>,
+    memmap: Option<u64>,
+    hhdm: Option<u64>,
+    rsdp: Option<u64>,
+    module: Option<u64>,
+    smp: Option<u64>,
+    kernel_address: Option<u64>,
+    stack_size: Option<u64>,
+}
+
+impl Requests {
+    /// Scans the loaded kernel for 8-byte aligned request markers.
+    unsafe fn scan(kernel: &Kernel) -> Self {
+        let words = (kernel.size / 8) as usize;
+        let memory = slice::from_raw_parts(kernel.physical_base as *const u64, words);
+        Self::find(memory, kernel.physical_base)
+    }
+
+    fn find(memory: &[u64], base: u64) -> Self {
+        let mut requests = Self::default();
+        for index in 0..memory.len() {
+            let address = Some(base + index as u64 * 8);
+            let window = &memory[index..];
+            if window.len() >= 3 && window.starts_with(&BASE_REVISION_MAGIC) {
+                requests.base_revision = address;
+                continue;
+            }
+            if window.len() < 4 || !window.starts_with(&COMMON_MAGIC) {
+                continue;
+            }
+            let request = match [window[2], window[3]] {
+                FRAMEBUFFER_REQUEST => &mut requests.framebuffer,
+                MEMMAP_REQUEST => &mut requests.memmap,
+                HHDM_REQUEST => &mut requests.hhdm,
+                RSDP_REQUEST => &mut requests.rsdp,
+                MODULE_REQUEST => &mut requests.module,
+                SMP_REQUEST => &mut requests.smp,
+                KERNEL_ADDRESS_REQUEST => &mut requests.kernel_address,
+                STACK_SIZE_REQUEST => &mut requests.stack_size,
+                _ => continue,
+            };
+            *request = address;
+        }
+        requests
+    }
+}
+
+/// Bump allocation for responses, in pages the kernel sees as bootloader
+/// reclaimable.
+struct Arena {
+    next: u64,
+    end: u64,
+}
+
+impl Arena {
+    fn new(boot_services: &BootServices, size: usize) -> Result<Self> {
+        let base = allocate(boot_services, MemoryType::LOADER_DATA, size)?;
+        unsafe { (base as *mut u8).write_bytes(0, size) };
+        Ok(Self {
+            next: base,
+            end: base + size as u64,
+        })
+    }
+
+    fn alloc(&mut self, size: usize) -> u64 {
+        let address = (self.next + 7) & !7;
+        self.next = address + size as u64;
+        assert!(self.next <= self.end, "Arena::alloc out of space");
+        address
+    }
+
+    fn push<T>(&mut self, value: T) -> u64 {
+        let address = self.alloc(size_of::<T>());
+        unsafe { (address as *mut T).write(value) };
+        address
+    }
+
+    fn slice<T: Copy>(&mut self, values: &[T]) -> u64 {
+        let address = self.alloc(size_of_val(values));
+        unsafe { ptr::copy_nonoverlapping(values.as_ptr(), address as *mut T, values.len()) };
+        address
+    }
+
+    fn string(&mut self, string: &str) -> u64 {
+        let address = self.alloc(string.len() + 1);
+        unsafe {
+            ptr::copy_nonoverlapping(string.as_ptr(), address as *mut u8, string.len());
+            (address as *mut u8).add(string.len()).write(0);
+        }
+        address
+    }
+}
+
+#[repr(C)]
+struct Request {
+    id: [u64; 4],
+    revision: u64,
+    response: u64,
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+#[repr(C)]
+struct HhdmResponse {
+    revision: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+struct KernelAddressResponse {
+    revision: u64,
+    physical_base: u64,
+    virtual_base: u64,
+}
+
+#[repr(C)]
+struct RsdpResponse {
+    revision: u64,
+    address: u64,
+}
+
+#[repr(C)]
+struct FramebufferResponse {
+    revision: u64,
+    framebuffer_count: u64,
+    framebuffers: u64,
+}
+
+#[repr(C)]
+struct Framebuffer {
+    address: u64,
+    width: u64,
+    height: u64,
+    pitch: u64,
+    bpp: u16,
+    memory_model: u8,
+    red_mask_size: u8,
+    red_mask_shift: u8,
+    green_mask_size: u8,
+    green_mask_shift: u8,
+    blue_mask_size: u8,
+    blue_mask_shift: u8,
+    unused: [u8; 7],
+    edid_size: u64,
+    edid: u64,
+}
+
+#[repr(C)]
+struct ModuleResponse {
+    revision: u64,
+    module_count: u64,
+    modules: u64,
+}
+
+#[derive(Default)]
+#[repr(C)]
+struct File {
+    revision: u64,
+    address: u64,
+    size: u64,
+    path: u64,
+    cmdline: u64,
+    media_type: u32,
+    unused: u32,
+    tftp_ip: u32,
+    tftp_port: u32,
+    partition_index: u32,
+    mbr_disk_id: u32,
+    gpt_disk_uuid: [u8; 16],
+    gpt_part_uuid: [u8; 16],
+    part_uuid: [u8; 16],
+}
+
+#[repr(C)]
+struct MemmapResponse {
+    revision: u64,
+    entry_count: u64,
+    entries: u64,
+}
+
+#[repr(C)]
+struct MemmapEntry {
+    base: u64,
+    length: u64,
+    ty: u64,
+}
+
+#[repr(C)]
+struct SmpResponse {
+    revision: u64,
+    flags: u32,
+    bsp_lapic_id: u32,
+    cpu_count: u64,
+    cpus: u64,
+}
+
+/// Per-processor info, whose `goto_address` the parked processor polls.
+#[derive(Default)]
+#[repr(C)]
+struct SmpInfo {
+    processor_id: u32,
+    lapic_id: u32,
+    reserved: u64,
+    goto_address: u64,
+    extra_argument: u64,
+}
+
+const _: () = assert!(size_of::<Framebuffer>() == 64 && size_of::<File>() == 112);
+
+#[test_case]
+fn find_requests() {
+    let mut memory = [0u64; 17];
+    memory[1..5].copy_from_slice(&[
+        COMMON_MAGIC[0],
+        COMMON_MAGIC[1],
+        HHDM_REQUEST[0],
+        HHDM_REQUEST[1],
+    ]);
+    memory[7..11].copy_from_slice(&[
+        COMMON_MAGIC[0],
+        COMMON_MAGIC[1],
+        SMP_REQUEST[0],
+        SMP_REQUEST[1],
+    ]);
+    memory[13..17].copy_from_slice(&[
+        COMMON_MAGIC[0],
+        COMMON_MAGIC[1],
+        RSDP_REQUEST[0],
+        RSDP_REQUEST[1],
+    ]);
+    let requests = Requests::find(&memory, 0x1000);
+    assert_eq!(
+        requests,
+        Requests {
+            hhdm: Some(0x1008),
+            smp: Some(0x1038),
+            rsdp: Some(0x1068),
+            ..Requests::default()
+        }
+    );
+    memory[14..17].copy_from_slice(&[BASE_REVISION_MAGIC[0], BASE_REVISION_MAGIC[1], 1]);
+    let requests = Requests::find(&memory, 0x1000);
+    assert_eq!(requests.base_revision, Some(0x1070));
+    assert_eq!(requests.rsdp, None);
+}
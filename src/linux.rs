@@ -0,0 +1,147 @@
+use super::{boot, cfg::BootEntry};
+use alloc::{boxed::Box, vec::Vec};
+use core::{ffi::c_void, mem::size_of};
+use uefi::{
+    prelude::*,
+    proto::{
+        device_path::{DevicePath, DevicePathHeader, DeviceSubType, DeviceType},
+        Protocol,
+    },
+    table::Header,
+    unsafe_guid, Guid, Identify, Result,
+};
+
+pub const LINUX_EFI_INITRD_MEDIA_GUID: Guid =
+    Guid::from_values(0x5568e427, 0x68fc, 0x4f3d, 0xac74, 0xca555231cc68);
+
+const EFI_NATIVE_INTERFACE: u32 = 0;
+
+pub fn boot(image_handle: Handle, entry: &BootEntry) -> Result {
+    // The EFI stub measures what it reads through `LoadFile2` itself.
+    let mut initrd = Vec::new();
+    for path in &entry.initrd {
+        initrd.extend(boot::load(image_handle, entry, path)?);
+        initrd.resize((initrd.len() + 3) & !3, 0);
+    }
+    if initrd.is_empty() {
+        return boot::chainload(image_handle, entry);
+    }
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let services = ProtocolServices::get(system_table.boot_services());
+    let initrd_device_path = Box::<InitrdDevicePath>::default();
+    let device_path = &*initrd_device_path as *const InitrdDevicePath as *const c_void;
+    let initrd_load_file = Box::new(LoadFile2 { load_file, initrd });
+    let load_file = &*initrd_load_file as *const LoadFile2 as *const c_void;
+    let mut handle = None;
+    services.install(&mut handle, &DevicePath::GUID, device_path)?;
+    let handle = handle.expect("InstallProtocolInterface returned no handle");
+    if let Err(err) = services.install(&mut Some(handle), &LoadFile2::GUID, load_file) {
+        services.uninstall(handle, &DevicePath::GUID, device_path)?;
+        return Err(err);
+    }
+    let result = boot::chainload(image_handle, entry);
+    services.uninstall(handle, &LoadFile2::GUID, load_file)?;
+    services.uninstall(handle, &DevicePath::GUID, device_path)?;
+    result
+}
+
+#[repr(C)]
+#[unsafe_guid("4006c0c1-fcb3-403e-996d-4a6c8724e06d")]
+#[derive(Protocol)]
+struct LoadFile2 {
+    load_file: unsafe extern "efiapi" fn(
+        this: &LoadFile2,
+        file_path: *const c_void,
+        boot_policy: bool,
+        buffer_size: &mut usize,
+        buffer: *mut u8,
+    ) -> Status,
+    initrd: Vec<u8>,
+}
+
+unsafe extern "efiapi" fn load_file(
+    this: &LoadFile2,
+    _file_path: *const c_void,
+    boot_policy: bool,
+    buffer_size: &mut usize,
+    buffer: *mut u8,
+) -> Status {
+    if boot_policy {
+        return Status::UNSUPPORTED;
+    }
+    let initrd = &this.initrd;
+    if buffer.is_null() || *buffer_size < initrd.len() {
+        *buffer_size = initrd.len();
+        return Status::BUFFER_TOO_SMALL;
+    }
+    buffer.copy_from_nonoverlapping(initrd.as_ptr(), initrd.len());
+    *buffer_size = initrd.len();
+    Status::SUCCESS
+}
+
+#[repr(C, packed)]
+struct InitrdDevicePath {
+    vendor: DevicePathHeader,
+    vendor_guid: Guid,
+    end: DevicePathHeader,
+}
+
+impl Default for InitrdDevicePath {
+    fn default() -> Self {
+        Self {
+            vendor: DevicePathHeader {
+                device_type: DeviceType::MEDIA,
+                sub_type: DeviceSubType::MEDIA_VENDOR,
+                length: (size_of::<DevicePathHeader>() + size_of::<Guid>()) as u16,
+            },
+            vendor_guid: LINUX_EFI_INITRD_MEDIA_GUID,
+            end: DevicePathHeader {
+                device_type: DeviceType::END,
+                sub_type: DeviceSubType::END_ENTIRE,
+                length: size_of::<DevicePathHeader>() as u16,
+            },
+        }
+    }
+}
+
+/// The protocol handler services that `uefi` leaves opaque in `BootServices`.
+#[repr(C)]
+pub struct ProtocolServices {
+    header: Header,
+    _services: [usize; 13],
+    install_protocol_interface: unsafe extern "efiapi" fn(
+        handle: &mut Option<Handle>,
+        protocol: &Guid,
+        interface_type: u32,
+        interface: *const c_void,
+    ) -> Status,
+    _reinstall_protocol_interface: usize,
+    uninstall_protocol_interface: unsafe extern "efiapi" fn(
+        handle: Handle,
+        protocol: &Guid,
+        interface: *const c_void,
+    ) -> Status,
+}
+
+impl ProtocolServices {
+    pub fn get(boot_services: &BootServices) -> &Self {
+        unsafe { &*(boot_services as *const BootServices).cast::<Self>() }
+    }
+
+    pub fn install(
+        &self,
+        handle: &mut Option<Handle>,
+        protocol: &Guid,
+        interface: *const c_void,
+    ) -> Result {
+        unsafe {
+            (self.install_protocol_interface)(handle, protocol, EFI_NATIVE_INTERFACE, interface)
+        }
+        .into()
+    }
+
+    pub fn uninstall(&self, handle: Handle, protocol: &Guid, interface: *const c_void) -> Result {
+        unsafe { (self.uninstall_protocol_interface)(handle, protocol, interface) }.into()
+    }
+}
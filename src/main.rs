@@ -1,48 +1,81 @@
-#![feature(abi_efiapi, custom_test_frameworks, format_args_nl)]
+#![feature(abi_efiapi, custom_test_frameworks, format_args_nl, negative_impls)]
 #![no_main]
 #![no_std]
 #![reexport_test_harness_main = "test_main"]
 #![test_runner(test::test_runner)]
 
+mod bli;
+mod bls;
+mod boot;
+mod btrfs;
+mod bytes;
+#[cfg(target_arch = "x86_64")]
+mod bzimage;
 mod cfg;
+mod detect;
+mod elf;
+mod ext4;
+mod firmware;
 mod fs;
 mod gop;
+mod gpt;
+#[cfg(target_arch = "x86_64")]
+mod handoff;
+mod input;
 mod io;
+#[cfg(target_arch = "x86_64")]
+mod limine;
+mod linux;
 mod map;
+mod menu;
+#[cfg(target_arch = "x86_64")]
+mod multiboot2;
+mod net;
+#[cfg(target_arch = "x86_64")]
+mod paging;
+mod pe;
+mod rtc;
+mod secureboot;
+mod sha256;
+#[cfg(target_arch = "x86_64")]
+mod smp;
+mod snapshot;
 mod str;
 mod test;
+mod tpm;
+mod uki;
+mod var;
+mod vfs;
 
 #[macro_use]
 extern crate alloc;
 
-use alloc::string::ToString;
-use cfg::{Config, ConfigData, DEFAULT_LOGO};
-use embedded_graphics::{
-    mono_font::{ascii::FONT_10X20, MonoTextStyle},
-    pixelcolor::Rgb888,
-    prelude::*,
-    primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle},
-    text::{Alignment, Text},
-};
+use alloc::{string::ToString, vec::Vec};
+use boot::Launch;
+use cfg::{BootEntry, Config, ConfigData, DEFAULT_LOGO, SAVED};
+use embedded_graphics::pixelcolor::Rgb888;
 use fs::{BootServicesExt, FileExt, FileSystem};
-use gop::{DrawMasked, FrameBuffer, Interaction, BACKGROUND_COLOR, STROKE_COLOR};
+use gop::Interaction;
+use menu::{Choice, Menu};
 use tinybmp::Bmp;
 use uefi::{
     prelude::*,
-    proto::{
-        console::{
-            gop::GraphicsOutput,
-            text::{Key, ScanCode},
-        },
-        media::file::FileMode,
-    },
+    proto::{console::gop::GraphicsOutput, media::file::FileMode},
     table::runtime::ResetType,
-    Result,
+    Error, Result,
 };
 
+const FIRMWARE_SETUP: &str = "Firmware Setup";
+const FIRMWARE_BOOT_OPTION: &str = "Reboot to firmware boot option...";
+const EXIT_TO_FIRMWARE: &str = "Exit to firmware";
+const WAKE_AT: &str = "Shut down and wake at...";
+const REBOOT_IN: &str = "Reboot in N seconds...";
+
 #[entry]
 fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     uefi_services::init(&mut system_table)?;
+    let init_usec = bli::time_usec();
+    vfs::mount_all(image_handle);
     let graphics_output = gop::get();
     let file_system = fs::get(image_handle);
     let config_path = system_table
@@ -53,7 +86,8 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     let config_path = config_path
         .rsplit_once('.')
         .expect("String::rsplit_once failed");
-    let config_path = config_path.0.to_string() + ".json";
+    let config_stem = config_path.0.to_string();
+    let config_path = config_stem.clone() + ".json";
     let config_file = file_system.open(&config_path, FileMode::CreateReadWrite)?;
     let mut config_data = ConfigData::default();
     if let Ok(mut config) = Config::new(config_file) {
@@ -69,132 +103,272 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         }
         config_data = config.clone();
     }
-    let mut frame_buffer = FrameBuffer::from(&mut *graphics_output);
-    let mut draw_logo = || {
-        frame_buffer.clear(config_data.background.into())?;
-        let mut draw_logo = |bytes: &[u8]| {
-            let logo = Bmp::<Rgb888>::from_slice(&bytes).or(Err(Status::UNSUPPORTED))?;
-            let offset = Point::new(
-                frame_buffer.size().width as i32 - logo.size().width as i32 >> 1,
-                frame_buffer.size().height as i32 - logo.size().height as i32 >> 1,
-            );
-            frame_buffer.draw_masked(logo.pixels(), Rgb888::BLACK, offset)
-        };
-        match file_system.open(&config_data.logo_path, FileMode::Read) {
-            Ok(mut bitmap) => draw_logo(&bitmap.load()?),
-            Err(_) => draw_logo(DEFAULT_LOGO),
+    if let Some(url) = config_data.remote_config.clone() {
+        let cache_path = config_stem + ".remote.json";
+        config_data = remote_config(image_handle, &config_data, &url, &cache_path);
+    }
+    let measured = serde_json::to_vec(&config_data).expect("serde_json::to_vec failed");
+    if let Err(err) = tpm::measure(tpm::PCR_BOOT_LOADER_CONFIG, &measured, "ConfigData") {
+        println!("Failed to measure the configuration: {:?}", err.status());
+    }
+    let mut boot_entries = config_data.boot_entries.clone();
+    boot_entries.extend(bls::entries(image_handle).iter().map(BootEntry::from));
+    for entry in detect::entries(image_handle) {
+        if !boot_entries.iter().any(|known| known.path == entry.path) {
+            boot_entries.push(entry);
         }
+    }
+    bli::set_loader_info(image_handle, init_usec);
+    bli::set_entries(&boot_entries);
+    let logo = match file_system.open(&config_data.logo_path, FileMode::Read) {
+        Ok(mut bitmap) => bitmap.load()?,
+        Err(_) => DEFAULT_LOGO.to_vec(),
+    };
+    let logo = Bmp::<Rgb888>::from_slice(&logo)
+        .or_else(|_| Bmp::<Rgb888>::from_slice(DEFAULT_LOGO))
+        .map_err(|_| Error::from(Status::UNSUPPORTED))?;
+    let requested_entry = bli::default_entry();
+    let is_saved = [
+        requested_entry.as_deref(),
+        config_data.default_entry.as_deref(),
+    ]
+    .contains(&Some(SAVED));
+    let saved_entry = match is_saved {
+        true => var::get_string(var::SAVED_ENTRY, &var::LOADER_VENDOR),
+        false => None,
+    };
+    let default_entry = match requested_entry {
+        Some(entry) if entry != SAVED => Some(entry),
+        Some(_) => saved_entry.clone(),
+        None if is_saved => saved_entry.clone(),
+        None => config_data.default_entry.clone(),
     };
-    draw_logo()?;
+    let timeout = bli::timeout_one_shot().unwrap_or(config_data.timeout);
+    let mut menu = Menu::new(
+        &boot_entries,
+        default_entry.as_deref(),
+        timeout,
+        config_data.background.into(),
+        logo,
+    );
     #[cfg(test)]
     test_main();
-    let key_event = system_table.stdin().wait_for_key_event();
-    let key_event = unsafe { key_event.unsafe_clone() };
-    let mut events = [key_event];
-    while let Ok(_) = system_table.boot_services().wait_for_event(&mut events) {
-        if let Some(Key::Special(ScanCode::ESCAPE)) = system_table.stdin().read_key()? {
-            power_options(graphics_output)?;
-            draw_logo()?;
+    loop {
+        let entry = match menu.run(graphics_output)? {
+            Choice::Boot(index) => boot_entries[index].clone(),
+            Choice::Edit(index) => {
+                let mut entry = boot_entries[index].clone();
+                match graphics_output.edit(image_handle, &entry.title, &entry.options) {
+                    Ok(options) => entry.options = options,
+                    Err(err) if err.status() == Status::ABORTED => continue,
+                    Err(err) => return err.status(),
+                }
+                entry
+            }
+            Choice::Snapshots => match snapshots(graphics_output, image_handle)? {
+                Some(entry) => entry,
+                None => continue,
+            },
+            Choice::PowerOptions => match power_options(graphics_output, image_handle)? {
+                true => return Status::SUCCESS,
+                false => continue,
+            },
+        };
+        if is_saved {
+            bli::set_last_booted(&entry);
+        }
+        if is_saved && saved_entry.as_deref() != Some(entry.id()) {
+            let id = entry.id();
+            let attributes = var::non_volatile();
+            let vendor = &var::LOADER_VENDOR;
+            if let Err(err) = var::set_string(var::SAVED_ENTRY, vendor, attributes, id) {
+                println!("Failed to save {id}: {:?}", err.status());
+            }
+        }
+        if let Some(counter) = &entry.counter {
+            if let Err(err) = counter.count_attempt(image_handle) {
+                println!("Failed to count boot of {}: {:?}", entry.id(), err.status());
+            }
+        }
+        bli::set_selected(&entry);
+        if let Err(err) = entry.launch(image_handle) {
+            let status = format!("{:?}", err.status());
+            let _ = graphics_output.alert("Failed to boot", &[&entry.title, &status]);
         }
     }
-    Status::ABORTED
 }
 
-fn power_options(graphics_output: &mut GraphicsOutput) -> Result {
-    let mut frame_buffer = FrameBuffer::from(&mut *graphics_output);
-    let (x, y) = graphics_output.current_mode_info().resolution();
-    let center = Point::new(x as i32 >> 1, y as i32 >> 1);
-    Rectangle::new(
-        Point::new(center.x - 100, center.y - 150),
-        Size::new(200, 300),
-    )
-    .into_styled(PrimitiveStyle::with_fill(BACKGROUND_COLOR))
-    .draw(&mut frame_buffer)?;
-    Rectangle::new(
-        Point::new(center.x - 90, center.y - 140),
-        Size::new(180, 280),
-    )
-    .into_styled(
-        PrimitiveStyleBuilder::new()
-            .stroke_color(STROKE_COLOR)
-            .stroke_width(1)
-            .build(),
-    )
-    .draw(&mut frame_buffer)?;
-    let mut character_style = MonoTextStyle::new(&FONT_10X20, Rgb888::RED);
-    character_style.background_color = Some(BACKGROUND_COLOR);
-    Text::with_alignment(
-        "Options",
-        Point::new(center.x, center.y - 135),
-        character_style,
-        Alignment::Center,
-    )
-    .draw(&mut frame_buffer)?;
-    character_style.text_color = Some(Rgb888::BLUE);
-    Text::with_alignment(
-        "<Enter>",
-        Point::new(center.x, center.y + 120),
-        character_style,
-        Alignment::Center,
-    )
-    .draw(&mut frame_buffer)?;
-    let dialog_box = Rectangle::new(
-        Point::new(center.x - 80, center.y - 100),
-        Size::new(160, 200),
-    )
-    .into_styled(PrimitiveStyle::with_fill(BACKGROUND_COLOR));
-    let mut system_table = uefi_services::system_table();
-    let system_table = unsafe { system_table.as_mut() };
-    let key_event = system_table.stdin().wait_for_key_event();
-    let key_event = unsafe { key_event.unsafe_clone() };
-    let mut events = [key_event];
-    let position = Point::new(center.x, center.y - 35);
-    let texts = ["Continue", "Reboot", "Shutdown"];
-    let mut index = 0;
-    let index = 'outer: loop {
-        dialog_box.draw(&mut frame_buffer)?;
-        let mut position = position;
-        texts.iter().enumerate().try_for_each(|(i, text)| {
-            character_style.text_color = Some(match i == index % texts.len() {
-                false => Rgb888::BLACK,
-                true => Rgb888::BLUE,
-            });
-            Text::with_alignment(&text, position, character_style, Alignment::Center)
-                .draw(&mut frame_buffer)
-                .and_then(|_| Ok(position.y += 30))
-        })?;
-        while let Ok(_) = system_table.boot_services().wait_for_event(&mut events) {
-            if let Some(key) = system_table.stdin().read_key()? {
-                match key {
-                    Key::Printable(c) if '\r' == c.into() => {
-                        let index = index % texts.len();
-                        break 'outer index;
-                    }
-                    Key::Special(c) => match c {
-                        ScanCode::ESCAPE => break 'outer 0,
-                        ScanCode::UP => {
-                            index += texts.len() - 1;
-                            index %= texts.len();
-                            break;
-                        }
-                        ScanCode::DOWN => {
-                            index += 1;
-                            break;
-                        }
-                        _ => (),
-                    },
-                    _ => (),
+/// Merges the document at `url` over `config_data`, keeping a copy of it at
+/// `cache_path` for when the server can't be reached or there's no network.
+fn remote_config(
+    image_handle: Handle,
+    config_data: &ConfigData,
+    url: &str,
+    cache_path: &str,
+) -> ConfigData {
+    let file_system = fs::get(image_handle);
+    let cached = file_system
+        .open(cache_path, FileMode::Read)
+        .and_then(|mut cache_file| cache_file.load())
+        .ok();
+    let remote = match net::has_interface(url).then(|| net::fetch(image_handle, url)) {
+        Some(Ok(remote)) if cfg::merge(config_data, &remote).is_some() => {
+            let parse = |json: &[u8]| serde_json::from_slice::<serde_json::Value>(json).ok();
+            if cached.as_deref().and_then(parse) != parse(&remote) {
+                let result = file_system
+                    .open(cache_path, FileMode::CreateReadWrite)
+                    .and_then(|mut cache_file| {
+                        cache_file
+                            .replace(&remote)
+                            .map_err(|err| Error::from(err.status()))
+                    });
+                if let Err(err) = result {
+                    println!("Failed to cache {url}: {:?}", err.status());
                 }
             }
+            Some(remote)
+        }
+        Some(Ok(_)) => {
+            println!("Ignoring the invalid configuration at {url}");
+            cached
         }
+        Some(Err(err)) => {
+            println!("Failed to fetch {url}: {:?}", err.status());
+            cached
+        }
+        None => cached,
     };
-    let runtime_services = system_table.runtime_services();
-    let reset = |rt: ResetType| {
-        runtime_services.reset(rt, Status::SUCCESS, None);
+    remote
+        .and_then(|remote| cfg::merge(config_data, &remote))
+        .unwrap_or_else(|| config_data.clone())
+}
+
+/// Lets the user pick a kernel from a subvolume or snapshot to boot.
+fn snapshots(
+    graphics_output: &mut GraphicsOutput,
+    image_handle: Handle,
+) -> Result<Option<BootEntry>> {
+    let entries = snapshot::entries(image_handle);
+    if entries.is_empty() {
+        graphics_output.alert("Snapshots", &["No kernels found in /boot of any snapshot"])?;
+        return Ok(None);
+    }
+    let titles = entries
+        .iter()
+        .map(|entry| entry.title.as_str())
+        .collect::<Vec<_>>();
+    match graphics_output.select("Snapshots", &titles) {
+        Ok(index) => Ok(Some(entries[index].clone())),
+        Err(err) if err.status() == Status::ABORTED => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Shows the power options, returning whether to exit to the firmware.
+fn power_options(graphics_output: &mut GraphicsOutput, image_handle: Handle) -> Result<bool> {
+    let mut items = vec!["Continue", "Reboot", "Shutdown"];
+    if firmware::supports_setup() {
+        items.push(FIRMWARE_SETUP);
+    }
+    let boot_options = firmware::boot_options();
+    if !boot_options.is_empty() {
+        items.push(FIRMWARE_BOOT_OPTION);
+    }
+    items.extend([WAKE_AT, REBOOT_IN, EXIT_TO_FIRMWARE]);
+    let index = match graphics_output.select("Options", &items) {
+        Ok(index) => index,
+        Err(err) if err.status() == Status::ABORTED => return Ok(false),
+        Err(err) => return Err(err),
     };
-    match index {
-        1 => reset(ResetType::Cold),
-        2 => reset(ResetType::Shutdown),
-        _ => Ok(()),
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let runtime_services = system_table.runtime_services();
+    match items[index] {
+        "Reboot" => runtime_services.reset(ResetType::Cold, Status::SUCCESS, None),
+        "Shutdown" => runtime_services.reset(ResetType::Shutdown, Status::SUCCESS, None),
+        FIRMWARE_SETUP => {
+            if let Err(err) = firmware::reboot_to_setup() {
+                let status = format!("{:?}", err.status());
+                let _ =
+                    graphics_output.alert(FIRMWARE_SETUP, &["Failed to request setup", &status]);
+            }
+            Ok(false)
+        }
+        FIRMWARE_BOOT_OPTION => {
+            let descriptions = boot_options
+                .iter()
+                .map(|option| option.description.as_str())
+                .collect::<Vec<_>>();
+            let index = match graphics_output.select("Boot Option", &descriptions) {
+                Ok(index) => index,
+                Err(err) if err.status() == Status::ABORTED => return Ok(false),
+                Err(err) => return Err(err),
+            };
+            if let Err(err) = firmware::reboot_to(&boot_options[index]) {
+                let status = format!("{:?}", err.status());
+                let lines = [descriptions[index], "Failed to set BootNext", &status];
+                let _ = graphics_output.alert("Boot Option", &lines);
+            }
+            Ok(false)
+        }
+        WAKE_AT => {
+            let now = match rtc::now() {
+                Ok(now) => now,
+                Err(err) => {
+                    let status = format!("{:?}", err.status());
+                    let _ = graphics_output.alert(WAKE_AT, &["Failed to read the clock", &status]);
+                    return Ok(false);
+                }
+            };
+            let mut text = rtc::format(&now);
+            loop {
+                text = match graphics_output.edit(image_handle, WAKE_AT, &text) {
+                    Ok(text) => text,
+                    Err(err) if err.status() == Status::ABORTED => return Ok(false),
+                    Err(err) => return Err(err),
+                };
+                let time = match rtc::parse(&now, &text) {
+                    Some(time) if rtc::is_after(&time, &now) => time,
+                    Some(_) => {
+                        let _ = graphics_output.alert(WAKE_AT, &[&text, "is in the past"]);
+                        continue;
+                    }
+                    None => {
+                        let lines = [&text, "is not YYYY-MM-DD HH:MM or HH:MM"];
+                        let _ = graphics_output.alert(WAKE_AT, &lines);
+                        continue;
+                    }
+                };
+                if let Err(err) = rtc::set_wakeup_time(&time) {
+                    let status = format!("{:?}", err.status());
+                    let lines = ["Failed to set the wakeup time", &status];
+                    let _ = graphics_output.alert(WAKE_AT, &lines);
+                    return Ok(false);
+                }
+                runtime_services.reset(ResetType::Shutdown, Status::SUCCESS, None);
+            }
+        }
+        REBOOT_IN => {
+            let text = match graphics_output.edit(image_handle, REBOOT_IN, "10") {
+                Ok(text) => text,
+                Err(err) if err.status() == Status::ABORTED => return Ok(false),
+                Err(err) => return Err(err),
+            };
+            let seconds = match text.trim().parse() {
+                Ok(seconds) if seconds > 0 => seconds,
+                _ => {
+                    let lines = [&text, "is not a number of seconds above 0"];
+                    let _ = graphics_output.alert(REBOOT_IN, &lines);
+                    return Ok(false);
+                }
+            };
+            match graphics_output.countdown("Rebooting", seconds) {
+                Ok(()) => runtime_services.reset(ResetType::Cold, Status::SUCCESS, None),
+                Err(err) if err.status() == Status::ABORTED => Ok(false),
+                Err(err) => Err(err),
+            }
+        }
+        EXIT_TO_FIRMWARE => Ok(true),
+        _ => Ok(false),
     }
 }
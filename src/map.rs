@@ -1 +1,388 @@
+use core::{
+    mem::{align_of, size_of},
+    slice,
+};
+use uefi::{
+    prelude::*,
+    table::{
+        boot::{AllocateType, MemoryDescriptor, MemoryType},
+        Header, Runtime,
+    },
+    Error, Result,
+};
 
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Memory type for kernel images and boot modules, so that they can be told
+/// apart from the loader's own allocations once boot services are gone.
+pub const KERNEL_AND_MODULES: MemoryType = MemoryType::custom(0x8000_0000);
+
+const SLACK_DESCRIPTORS: usize = 16;
+
+#[cfg(target_arch = "x86_64")]
+pub const E820_RAM: u32 = 1;
+#[cfg(target_arch = "x86_64")]
+pub const E820_RESERVED: u32 = 2;
+#[cfg(target_arch = "x86_64")]
+pub const E820_ACPI: u32 = 3;
+#[cfg(target_arch = "x86_64")]
+pub const E820_NVS: u32 = 4;
+#[cfg(target_arch = "x86_64")]
+pub const E820_UNUSABLE: u32 = 5;
+#[cfg(target_arch = "x86_64")]
+pub const E820_PMEM: u32 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MemoryKind {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    BadMemory,
+    BootloaderReclaimable,
+    KernelAndModules,
+    RuntimeServices,
+    Persistent,
+}
+
+impl From<MemoryType> for MemoryKind {
+    fn from(ty: MemoryType) -> Self {
+        match ty {
+            MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA
+            | MemoryType::CONVENTIONAL => Self::Usable,
+            MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => Self::BootloaderReclaimable,
+            KERNEL_AND_MODULES => Self::KernelAndModules,
+            MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => {
+                Self::RuntimeServices
+            }
+            MemoryType::ACPI_RECLAIM => Self::AcpiReclaimable,
+            MemoryType::ACPI_NON_VOLATILE => Self::AcpiNvs,
+            MemoryType::UNUSABLE => Self::BadMemory,
+            MemoryType::PERSISTENT_MEMORY => Self::Persistent,
+            _ => Self::Reserved,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryRegion {
+    pub base: u64,
+    pub length: u64,
+    pub kind: MemoryKind,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct E820Entry {
+    pub addr: u64,
+    pub size: u64,
+    pub ty: u32,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl From<MemoryKind> for u32 {
+    fn from(kind: MemoryKind) -> Self {
+        match kind {
+            MemoryKind::Usable
+            | MemoryKind::BootloaderReclaimable
+            | MemoryKind::KernelAndModules => E820_RAM,
+            MemoryKind::AcpiReclaimable => E820_ACPI,
+            MemoryKind::AcpiNvs => E820_NVS,
+            MemoryKind::BadMemory => E820_UNUSABLE,
+            MemoryKind::Persistent => E820_PMEM,
+            MemoryKind::Reserved | MemoryKind::RuntimeServices => E820_RESERVED,
+        }
+    }
+}
+
+/// A snapshot of the UEFI memory map that lives in pages allocated up front,
+/// so it stays valid and can be refreshed after `ExitBootServices`.
+pub struct MemoryMap {
+    buffer: &'static mut [u8],
+    entry_size: usize,
+    entry_version: u32,
+    len: usize,
+    regions: &'static mut [MemoryRegion],
+    count: usize,
+}
+
+impl MemoryMap {
+    pub fn new(boot_services: &BootServices) -> Result<Self> {
+        let memory_map_size = boot_services.memory_map_size();
+        let entry_size = memory_map_size.entry_size;
+        let capacity = memory_map_size.map_size / entry_size + SLACK_DESCRIPTORS;
+        let allocate = |size: usize| {
+            let count = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+            boot_services
+                .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, count)
+                .map(|addr| addr as *mut u8)
+        };
+        let buffer = allocate(capacity * entry_size)?;
+        let regions = allocate(capacity * size_of::<MemoryRegion>())?;
+        let mut memory_map = unsafe {
+            Self {
+                buffer: slice::from_raw_parts_mut(buffer, capacity * entry_size),
+                entry_size,
+                entry_version: 0,
+                len: 0,
+                regions: slice::from_raw_parts_mut(regions.cast(), capacity),
+                count: 0,
+            }
+        };
+        memory_map.refresh(boot_services)?;
+        Ok(memory_map)
+    }
+
+    /// Fetches the current memory map without allocating and returns its key.
+    pub fn refresh(&mut self, boot_services: &BootServices) -> Result<usize> {
+        let services = MemoryServices::get(boot_services);
+        let mut map_size = self.buffer.len();
+        let mut map_key = 0;
+        let status = unsafe {
+            (services.get_memory_map)(
+                &mut map_size,
+                self.buffer.as_mut_ptr().cast(),
+                &mut map_key,
+                &mut self.entry_size,
+                &mut self.entry_version,
+            )
+        };
+        if status.is_error() {
+            return Err(Error::from(status));
+        }
+        self.len = map_size / self.entry_size;
+        let descriptors = descriptors(&self.buffer[..map_size], self.entry_size);
+        self.count = classify(descriptors, self.regions);
+        Ok(map_key)
+    }
+
+    /// Gives the pages of a map that is only needed while boot services last
+    /// back to the firmware.
+    pub fn free(self, boot_services: &BootServices) -> Result {
+        let pages = |bytes: usize| (bytes + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+        let regions = self.regions.len() * size_of::<MemoryRegion>();
+        boot_services.free_pages(self.buffer.as_ptr() as u64, pages(self.buffer.len()))?;
+        boot_services.free_pages(self.regions.as_ptr() as u64, pages(regions))
+    }
+
+    /// The raw descriptors with their size and version, for kernels that
+    /// want the UEFI memory map itself.
+    pub fn efi_memory_map(&self) -> (&[u8], usize, u32) {
+        (
+            &self.buffer[..self.len * self.entry_size],
+            self.entry_size,
+            self.entry_version,
+        )
+    }
+
+    /// Sorted, merged regions classified by what a kernel may do with them.
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.count]
+    }
+
+    /// The end of the highest region, for sizing identity mappings.
+    pub fn end(&self) -> u64 {
+        self.regions()
+            .last()
+            .map_or(0, |region| region.base + region.length)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn to_e820(&self, table: &mut [E820Entry]) -> Option<usize> {
+        to_e820(self.regions(), table)
+    }
+}
+
+/// Exits boot services, refetching the memory map whenever the firmware
+/// rejects its key because the map changed in between.
+pub fn exit_boot_services(image_handle: Handle) -> Result<(SystemTable<Runtime>, MemoryMap)> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref().unsafe_clone() };
+    let mut memory_map = MemoryMap::new(system_table.boot_services())?;
+    let MemoryMap {
+        buffer, regions, ..
+    } = &mut memory_map;
+    let (system_table, descriptors) = system_table.exit_boot_services(image_handle, buffer)?;
+    let len = descriptors.len();
+    let count = classify(descriptors, regions);
+    memory_map.len = len;
+    memory_map.count = count;
+    Ok((system_table, memory_map))
+}
+
+fn descriptors(
+    buffer: &[u8],
+    entry_size: usize,
+) -> impl ExactSizeIterator<Item = &MemoryDescriptor> + Clone {
+    debug_assert_eq!(buffer.as_ptr() as usize % align_of::<MemoryDescriptor>(), 0);
+    buffer
+        .chunks_exact(entry_size)
+        .map(|chunk| unsafe { &*chunk.as_ptr().cast::<MemoryDescriptor>() })
+}
+
+fn classify<'a, I>(descriptors: I, regions: &mut [MemoryRegion]) -> usize
+where
+    I: IntoIterator<Item = &'a MemoryDescriptor>,
+{
+    let mut len = 0;
+    for (region, descriptor) in regions.iter_mut().zip(descriptors) {
+        *region = MemoryRegion {
+            base: descriptor.phys_start,
+            length: descriptor.page_count * PAGE_SIZE,
+            kind: MemoryKind::from(descriptor.ty),
+        };
+        len += 1;
+    }
+    regions[..len].sort_unstable_by_key(|region| region.base);
+    let mut count: usize = 0;
+    for index in 0..len {
+        let region = regions[index];
+        match count.checked_sub(1).map(|last| &mut regions[last]) {
+            Some(last) if last.kind == region.kind && last.base + last.length == region.base => {
+                last.length += region.length;
+            }
+            _ => {
+                regions[count] = region;
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+/// Fills `table` with `regions` as E820 entries, merging neighbours of the
+/// same type, or returns `None` if they don't fit.
+#[cfg(target_arch = "x86_64")]
+fn to_e820(regions: &[MemoryRegion], table: &mut [E820Entry]) -> Option<usize> {
+    let mut len: usize = 0;
+    for region in regions {
+        let ty = u32::from(region.kind);
+        if let Some(last) = len.checked_sub(1).map(|last| &mut table[last]) {
+            if last.ty == ty && last.addr + last.size == region.base {
+                last.size += region.length;
+                continue;
+            }
+        }
+        if len == table.len() {
+            return None;
+        }
+        table[len] = E820Entry {
+            addr: region.base,
+            size: region.length,
+            ty,
+        };
+        len += 1;
+    }
+    Some(len)
+}
+
+/// The memory services that `uefi` either hides or only exposes through
+/// allocating wrappers in `BootServices`.
+#[repr(C)]
+struct MemoryServices {
+    header: Header,
+    _tpl_and_page_services: [usize; 4],
+    get_memory_map: unsafe extern "efiapi" fn(
+        size: &mut usize,
+        map: *mut MemoryDescriptor,
+        key: &mut usize,
+        desc_size: &mut usize,
+        desc_version: &mut u32,
+    ) -> Status,
+}
+
+impl MemoryServices {
+    fn get(boot_services: &BootServices) -> &Self {
+        unsafe { &*(boot_services as *const BootServices).cast::<Self>() }
+    }
+}
+
+#[test_case]
+fn classify() {
+    let descriptor = |ty, phys_start, page_count| {
+        let mut descriptor = MemoryDescriptor::default();
+        descriptor.ty = ty;
+        descriptor.phys_start = phys_start;
+        descriptor.page_count = page_count;
+        descriptor
+    };
+    let descriptors = [
+        descriptor(MemoryType::CONVENTIONAL, 0x2000, 2),
+        descriptor(MemoryType::ACPI_RECLAIM, 0x4000, 1),
+        descriptor(MemoryType::BOOT_SERVICES_DATA, 0x0, 1),
+        descriptor(MemoryType::LOADER_CODE, 0x1000, 1),
+        descriptor(MemoryType::BOOT_SERVICES_CODE, 0x3000, 1),
+    ];
+    let mut regions = [MemoryRegion {
+        base: 0,
+        length: 0,
+        kind: MemoryKind::Reserved,
+    }; 5];
+    let count = classify(&descriptors, &mut regions);
+    assert_eq!(
+        regions[..count],
+        [
+            MemoryRegion {
+                base: 0x0,
+                length: 0x1000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                base: 0x1000,
+                length: 0x1000,
+                kind: MemoryKind::BootloaderReclaimable,
+            },
+            MemoryRegion {
+                base: 0x2000,
+                length: 0x2000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                base: 0x4000,
+                length: 0x1000,
+                kind: MemoryKind::AcpiReclaimable,
+            },
+        ]
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test_case]
+fn to_e820() {
+    let region = |base, length, kind| MemoryRegion { base, length, kind };
+    let regions = [
+        region(0x0, 0x1000, MemoryKind::Usable),
+        region(0x1000, 0x1000, MemoryKind::BootloaderReclaimable),
+        region(0x2000, 0x2000, MemoryKind::Usable),
+        region(0x4000, 0x1000, MemoryKind::AcpiReclaimable),
+        region(0x8000, 0x1000, MemoryKind::Usable),
+    ];
+    let mut table = [E820Entry::default(); 3];
+    let len = to_e820(&regions, &mut table);
+    assert_eq!(
+        table[..3],
+        [
+            E820Entry {
+                addr: 0x0,
+                size: 0x4000,
+                ty: E820_RAM,
+            },
+            E820Entry {
+                addr: 0x4000,
+                size: 0x1000,
+                ty: E820_ACPI,
+            },
+            E820Entry {
+                addr: 0x8000,
+                size: 0x1000,
+                ty: E820_RAM,
+            },
+        ]
+    );
+    assert_eq!(len, Some(3));
+    assert_eq!(to_e820(&regions, &mut table[..2]), None);
+}
@@ -0,0 +1,274 @@
+use super::{
+    cfg::BootEntry,
+    gop::{DrawMasked, FrameBuffer, BACKGROUND_COLOR, STROKE_COLOR},
+    secureboot,
+};
+use alloc::{string::ToString, vec::Vec};
+use embedded_graphics::{
+    mono_font::{ascii::FONT_10X20, MonoTextStyle},
+    pixelcolor::Rgb888,
+    prelude::*,
+    primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle},
+    text::{Alignment, Text},
+};
+use tinybmp::Bmp;
+use uefi::{
+    proto::console::{
+        gop::GraphicsOutput,
+        text::{Key, ScanCode},
+    },
+    table::boot::{EventType, TimerTrigger, Tpl},
+    Error, Result,
+};
+
+const ROW_HEIGHT: i32 = 30;
+const MAX_ROWS: usize = 8;
+const ONE_SECOND: u64 = 10_000_000;
+
+/// What the user picked in the menu.
+pub enum Choice {
+    Boot(usize),
+    Edit(usize),
+    Snapshots,
+    PowerOptions,
+}
+
+pub struct Menu<'a> {
+    titles: Vec<&'a str>,
+    selected: usize,
+    timeout: Option<u64>,
+    background: Rgb888,
+    logo: Bmp<'a, Rgb888>,
+    secure_boot: secureboot::State,
+}
+
+impl<'a> Menu<'a> {
+    /// Selects the entry with the id or title `default_entry`, or the first
+    /// one, falling back to the next entry that has tries left, and counts
+    /// down `timeout` seconds before booting it.
+    pub fn new(
+        boot_entries: &'a [BootEntry],
+        default_entry: Option<&str>,
+        timeout: Option<u64>,
+        background: Rgb888,
+        logo: Bmp<'a, Rgb888>,
+    ) -> Self {
+        let selected = default_entry
+            .and_then(|default_entry| {
+                boot_entries
+                    .iter()
+                    .position(|entry| entry.id() == default_entry || entry.title == default_entry)
+            })
+            .unwrap_or_default();
+        let len = boot_entries.len().max(1);
+        let selected = (selected..selected + len)
+            .map(|index| index % len)
+            .find(|&index| {
+                boot_entries
+                    .get(index)
+                    .map_or(false, |entry| !entry.is_bad())
+            })
+            .unwrap_or(selected);
+        Self {
+            titles: boot_entries
+                .iter()
+                .map(|entry| entry.title.as_str())
+                .collect(),
+            selected,
+            timeout: timeout.filter(|_| !boot_entries.is_empty()),
+            background,
+            logo,
+            secure_boot: secureboot::State::current(),
+        }
+    }
+
+    /// Shows the menu until an entry is chosen, the countdown runs out or the
+    /// power options are asked for. The countdown only runs the first time
+    /// and stops for good at the first keypress.
+    pub fn run(&mut self, graphics_output: &mut GraphicsOutput) -> Result<Choice> {
+        let mut frame_buffer = FrameBuffer::from(&mut *graphics_output);
+        frame_buffer.clear(self.background)?;
+        let panel = self.panel(frame_buffer.size());
+        let offset = Point::new(
+            (frame_buffer.size().width as i32 - self.logo.size().width as i32) >> 1,
+            (panel.top_left.y - self.logo.size().height as i32) >> 1,
+        );
+        frame_buffer.draw_masked(self.logo.pixels(), Rgb888::BLACK, offset)?;
+        let mut character_style = MonoTextStyle::new(&FONT_10X20, STROKE_COLOR);
+        character_style.background_color = Some(self.background);
+        Text::with_alignment(
+            &self.secure_boot.to_string(),
+            Point::new(frame_buffer.size().width as i32 - 10, 25),
+            character_style,
+            Alignment::Right,
+        )
+        .draw(&mut frame_buffer)?;
+        let mut system_table = uefi_services::system_table();
+        let system_table = unsafe { system_table.as_mut() };
+        let key_event = system_table.stdin().wait_for_key_event();
+        let key_event = unsafe { key_event.unsafe_clone() };
+        let mut events = Vec::from([key_event]);
+        if self.timeout == Some(0) {
+            self.timeout = None;
+            return Ok(Choice::Boot(self.selected));
+        }
+        let boot_services = system_table.boot_services();
+        let timer = match self.timeout {
+            Some(_) => unsafe {
+                let timer =
+                    boot_services.create_event(EventType::TIMER, Tpl::APPLICATION, None, None)?;
+                boot_services.set_timer(&timer, TimerTrigger::Periodic(ONE_SECOND))?;
+                events.push(timer.unsafe_clone());
+                Some(timer)
+            },
+            None => None,
+        };
+        let choice = loop {
+            self.draw(&mut frame_buffer, panel)?;
+            let index = system_table
+                .boot_services()
+                .wait_for_event(&mut events)
+                .map_err(|err| Error::from(err.status()))?;
+            if index == 1 {
+                match self.timeout.as_mut() {
+                    Some(1) => break Choice::Boot(self.selected),
+                    Some(timeout) => *timeout -= 1,
+                    None => (),
+                }
+                continue;
+            }
+            let key = match system_table.stdin().read_key()? {
+                Some(key) => key,
+                None => continue,
+            };
+            if let (Some(_), Some(timer)) = (self.timeout.take(), &timer) {
+                system_table
+                    .boot_services()
+                    .set_timer(timer, TimerTrigger::Cancel)?;
+            }
+            match key {
+                Key::Printable(c) if '\r' == c.into() && !self.titles.is_empty() => {
+                    break Choice::Boot(self.selected)
+                }
+                Key::Printable(c) if 'e' == c.into() && !self.titles.is_empty() => {
+                    break Choice::Edit(self.selected)
+                }
+                Key::Printable(c) if 's' == c.into() => break Choice::Snapshots,
+                Key::Special(ScanCode::ESCAPE) => break Choice::PowerOptions,
+                Key::Special(ScanCode::UP) if !self.titles.is_empty() => {
+                    self.selected += self.titles.len() - 1;
+                    self.selected %= self.titles.len();
+                }
+                Key::Special(ScanCode::DOWN) if !self.titles.is_empty() => {
+                    self.selected += 1;
+                    self.selected %= self.titles.len();
+                }
+                Key::Special(ScanCode::HOME) => self.selected = 0,
+                Key::Special(ScanCode::END) => self.selected = self.titles.len().saturating_sub(1),
+                _ => (),
+            }
+        };
+        self.timeout = None;
+        if let Some(timer) = timer {
+            system_table.boot_services().close_event(timer)?;
+        }
+        Ok(choice)
+    }
+
+    /// The box holding the entries, in the lower part of the screen.
+    fn panel(&self, size: Size) -> Rectangle {
+        let rows = self.titles.len().clamp(1, MAX_ROWS) as i32;
+        let width = size.width.min(480);
+        let height = (rows * ROW_HEIGHT + 70) as u32;
+        let top = (size.height as i32 * 2 / 5).min(size.height as i32 - height as i32 - 40);
+        Rectangle::new(
+            Point::new((size.width - width) as i32 >> 1, top.max(0)),
+            Size::new(width, height),
+        )
+    }
+
+    fn draw(&self, frame_buffer: &mut FrameBuffer, panel: Rectangle) -> Result {
+        panel
+            .into_styled(PrimitiveStyle::with_fill(BACKGROUND_COLOR))
+            .draw(frame_buffer)?;
+        panel
+            .offset(-10)
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .stroke_color(STROKE_COLOR)
+                    .stroke_width(1)
+                    .build(),
+            )
+            .draw(frame_buffer)?;
+        let center = panel.center().x;
+        let mut character_style = MonoTextStyle::new(&FONT_10X20, Rgb888::RED);
+        character_style.background_color = Some(BACKGROUND_COLOR);
+        let mut position = Point::new(center, panel.top_left.y + 30);
+        Text::with_alignment("Boot", position, character_style, Alignment::Center)
+            .draw(frame_buffer)?;
+        if self.titles.is_empty() {
+            character_style.text_color = Some(Rgb888::BLACK);
+            position.y += ROW_HEIGHT;
+            Text::with_alignment(
+                "No boot entries",
+                position,
+                character_style,
+                Alignment::Center,
+            )
+            .draw(frame_buffer)?;
+        }
+        let rows = self.titles.len().min(MAX_ROWS);
+        let first = first_row(self.selected, self.titles.len(), rows);
+        let width = (panel.size.width as usize).saturating_sub(40) / 10;
+        for (i, title) in self.titles.iter().enumerate().skip(first).take(rows) {
+            position.y += ROW_HEIGHT;
+            character_style.text_color = Some(match i == self.selected {
+                false => Rgb888::BLACK,
+                true => Rgb888::BLUE,
+            });
+            let title = match title.char_indices().nth(width) {
+                Some((end, _)) => &title[..end],
+                None => title,
+            };
+            Text::with_alignment(title, position, character_style, Alignment::Center)
+                .draw(frame_buffer)?;
+        }
+        let footer = Rectangle::new(
+            Point::new(0, panel.bottom_right().unwrap_or(panel.top_left).y + 1),
+            Size::new(frame_buffer.size().width, 40),
+        );
+        footer
+            .into_styled(PrimitiveStyle::with_fill(self.background))
+            .draw(frame_buffer)?;
+        let text = match (self.timeout, self.titles.get(self.selected)) {
+            (Some(timeout), Some(title)) => format!("Booting {title} in {timeout}s"),
+            _ => "<Enter> boot  <e> edit  <s> snapshots  <Esc> options".into(),
+        };
+        character_style.text_color = Some(STROKE_COLOR);
+        character_style.background_color = Some(self.background);
+        Text::with_alignment(
+            &text,
+            Point::new(center, footer.top_left.y + 25),
+            character_style,
+            Alignment::Center,
+        )
+        .draw(frame_buffer)?;
+        Ok(())
+    }
+}
+
+/// The first of `rows` visible rows that keeps `selected` in view, scrolling
+/// as little as possible.
+fn first_row(selected: usize, len: usize, rows: usize) -> usize {
+    (selected + 1)
+        .saturating_sub(rows)
+        .min(len.saturating_sub(rows))
+}
+
+#[test_case]
+fn scrolling() {
+    assert_eq!(first_row(0, 3, 3), 0);
+    assert_eq!(first_row(7, 20, 8), 0);
+    assert_eq!(first_row(8, 20, 8), 1);
+    assert_eq!(first_row(19, 20, 8), 12);
+}
@@ -0,0 +1,518 @@
+use super::{
+    boot,
+    cfg::BootEntry,
+    elf::{Elf, EM_X86_64},
+    gop::{self, FrameBufferInfo},
+    map::{self, MemoryKind, MemoryMap, MemoryRegion, KERNEL_AND_MODULES, PAGE_SIZE},
+};
+use alloc::vec::Vec;
+use core::{
+    arch::{asm, global_asm},
+    mem::{size_of, transmute},
+    ptr, slice,
+};
+use uefi::{
+    prelude::*,
+    table::boot::{AllocateType, MemoryType},
+    Error, Result,
+};
+
+pub const HEADER_MAGIC: u32 = 0xe852_50d6;
+pub const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+const SEARCH_LIMIT: usize = 32768;
+const ARCHITECTURE_I386: u32 = 0;
+const TAG_OPTIONAL: u16 = 1 << 0;
+
+const HEADER_TAG_END: u16 = 0;
+const HEADER_TAG_INFORMATION_REQUEST: u16 = 1;
+const HEADER_TAG_ADDRESS: u16 = 2;
+const HEADER_TAG_ENTRY_ADDRESS: u16 = 3;
+const HEADER_TAG_CONSOLE_FLAGS: u16 = 4;
+const HEADER_TAG_FRAMEBUFFER: u16 = 5;
+const HEADER_TAG_MODULE_ALIGN: u16 = 6;
+const HEADER_TAG_EFI_BS: u16 = 7;
+const HEADER_TAG_ENTRY_ADDRESS_EFI64: u16 = 9;
+const HEADER_TAG_RELOCATABLE: u16 = 10;
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOT_LOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_EFI64: u32 = 12;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+const TAG_EFI_MMAP: u32 = 17;
+const TAG_EFI_BS: u32 = 18;
+const TAG_EFI64_IH: u32 = 20;
+const TAG_LOAD_BASE_ADDR: u32 = 21;
+
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_RESERVED: u32 = 2;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_NVS: u32 = 4;
+const MEMORY_BADRAM: u32 = 5;
+
+const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+const BOOT_LOADER_NAME: &str = "uefi_bootloader";
+const SLACK_DESCRIPTORS: usize = 64;
+
+/// Boots `entry` with the Multiboot2 protocol. Kernels asking for EFI boot
+/// services through the EFI64 entry tag are entered in long mode before
+/// `ExitBootServices`, all others in 32-bit protected mode after it.
+///
+/// Each `initrd` of the entry is a module, optionally followed by a space
+/// and the module's command line.
+pub fn boot(image_handle: Handle, entry: &BootEntry) -> Result {
+    let image = boot::load_kernel(image_handle, entry)?;
+    let header = Header::find(&image).ok_or_else(|| Error::from(Status::LOAD_ERROR))?;
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let efi64_entry = header.efi_amd64_entry.filter(|_| header.efi_boot_services);
+    let (load_base, elf_entry) = match header.address {
+        Some(address) => (load_address(boot_services, &image, &header, address)?, None),
+        None => {
+            let elf =
+                Elf::parse(&image, EM_X86_64).ok_or_else(|| Error::from(Status::LOAD_ERROR))?;
+            let kernel = elf.load_physical(boot_services)?;
+            (kernel.physical_base, Some(kernel.entry))
+        }
+    };
+    let kernel_entry = efi64_entry
+        .or(header.entry)
+        .map(u64::from)
+        .or(elf_entry)
+        .ok_or_else(|| Error::from(Status::LOAD_ERROR))?;
+    let mut modules = Vec::new();
+    for module in &entry.initrd {
+        let (path, cmdline) = module.split_once(' ').unwrap_or((module, ""));
+        let data = boot::load_initrd(image_handle, entry, path)?;
+        let start = allocate(boot_services, KERNEL_AND_MODULES, data.len().max(1))?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), start as *mut u8, data.len()) };
+        modules.push((start as u32, (start as usize + data.len()) as u32, cmdline));
+    }
+    let strings = modules
+        .iter()
+        .map(|module| module.2.len() + 16)
+        .sum::<usize>();
+    let memory_map_size = boot_services.memory_map_size();
+    let capacity = PAGE_SIZE as usize
+        + entry.options.len()
+        + strings
+        + 2 * (memory_map_size.map_size + SLACK_DESCRIPTORS * memory_map_size.entry_size);
+    let mut info = InfoBuilder::new(boot_services, capacity)?;
+    info.string(TAG_CMDLINE, &entry.options);
+    info.string(TAG_BOOT_LOADER_NAME, BOOT_LOADER_NAME);
+    for (start, end, cmdline) in modules {
+        let tag = info.begin(TAG_MODULE);
+        info.write(&start.to_le_bytes());
+        info.write(&end.to_le_bytes());
+        info.write(cmdline.as_bytes());
+        info.write(&[0]);
+        info.end(tag);
+    }
+    info.tag(TAG_LOAD_BASE_ADDR, &(load_base as u32).to_le_bytes());
+    if let Some(rsdp) = boot::acpi_rsdp() {
+        let rsdp = rsdp as *const u8;
+        let (ty, len) = match unsafe { rsdp.add(15).read() } {
+            0 | 1 => (TAG_ACPI_OLD, 20),
+            _ => (TAG_ACPI_NEW, unsafe {
+                rsdp.add(20).cast::<u32>().read_unaligned() as usize
+            }),
+        };
+        info.tag(ty, unsafe { slice::from_raw_parts(rsdp, len) });
+    }
+    let system_table_address = boot::system_table_address() as u64;
+    info.tag(TAG_EFI64, &system_table_address.to_le_bytes());
+    let image_handle_address = unsafe { transmute::<Handle, u64>(image_handle) };
+    info.tag(TAG_EFI64_IH, &image_handle_address.to_le_bytes());
+    let graphics_output = gop::get();
+    if let Some((width, height)) = header.framebuffer {
+        let mode = graphics_output
+            .modes()
+            .find(|mode| mode.info().resolution() == (width as usize, height as usize));
+        if let Some(mode) = mode {
+            graphics_output.set_mode(&mode)?;
+        }
+    }
+    info.framebuffer(&FrameBufferInfo::try_from(graphics_output)?);
+    if let Some(entry) = efi64_entry {
+        info.tag(TAG_EFI_BS, &[]);
+        info.memory_map(&MemoryMap::new(boot_services)?);
+        let info = info.finish();
+        unsafe {
+            asm!(
+                "mov rbx, rsi",
+                "jmp rdi",
+                in("eax") BOOTLOADER_MAGIC,
+                in("rsi") info,
+                in("rdi") entry as u64,
+                options(noreturn),
+            )
+        }
+    }
+    let trampoline = Trampoline::new(boot_services)?;
+    let (_, memory_map) = map::exit_boot_services(image_handle)?;
+    info.memory_map(&memory_map);
+    let info = info.finish();
+    unsafe { trampoline.enter(kernel_entry as u32, info as u32) }
+}
+
+/// Loads the image as described by the address tag, for kernels that are
+/// not ELF files or that want to be loaded differently.
+fn load_address(
+    boot_services: &BootServices,
+    image: &[u8],
+    header: &Header,
+    address: AddressTag,
+) -> Result<u64> {
+    let load_offset = (header.offset as u32)
+        .checked_sub(address.header_addr.wrapping_sub(address.load_addr))
+        .ok_or_else(|| Error::from(Status::LOAD_ERROR))? as usize;
+    let load_end_addr = match address.load_end_addr {
+        0 => address.load_addr + image.len().saturating_sub(load_offset) as u32,
+        load_end_addr => load_end_addr,
+    };
+    let bss_end_addr = address.bss_end_addr.max(load_end_addr);
+    let data = load_end_addr
+        .checked_sub(address.load_addr)
+        .and_then(|len| image.get(load_offset..load_offset + len as usize))
+        .ok_or_else(|| Error::from(Status::LOAD_ERROR))?;
+    let base = address.load_addr as u64 & !(PAGE_SIZE - 1);
+    let pages = (bss_end_addr as u64 - base + PAGE_SIZE - 1) / PAGE_SIZE;
+    let allocate_type = AllocateType::Address(base as usize);
+    boot_services.allocate_pages(allocate_type, KERNEL_AND_MODULES, pages as usize)?;
+    unsafe {
+        let load_addr = address.load_addr as *mut u8;
+        ptr::copy_nonoverlapping(data.as_ptr(), load_addr, data.len());
+        load_addr
+            .add(data.len())
+            .write_bytes(0, (bss_end_addr - load_end_addr) as usize);
+    }
+    Ok(address.load_addr as u64)
+}
+
+fn allocate(boot_services: &BootServices, ty: MemoryType, size: usize) -> Result<u64> {
+    let count = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    let below_4g = AllocateType::MaxAddress(u32::MAX as usize);
+    boot_services.allocate_pages(below_4g, ty, count)
+}
+
+fn memory_type(kind: MemoryKind) -> u32 {
+    match kind {
+        MemoryKind::Usable => MEMORY_AVAILABLE,
+        MemoryKind::AcpiReclaimable => MEMORY_ACPI_RECLAIMABLE,
+        MemoryKind::AcpiNvs => MEMORY_NVS,
+        MemoryKind::BadMemory => MEMORY_BADRAM,
+        // The information structure, the kernel and its modules live here,
+        // so they must not look free before the kernel has read them.
+        _ => MEMORY_RESERVED,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct HeaderTag {
+    ty: u16,
+    flags: u16,
+    size: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+struct AddressTag {
+    header_addr: u32,
+    load_addr: u32,
+    load_end_addr: u32,
+    bss_end_addr: u32,
+}
+
+/// The header tags this loader understands.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Header {
+    offset: usize,
+    address: Option<AddressTag>,
+    entry: Option<u32>,
+    efi_amd64_entry: Option<u32>,
+    efi_boot_services: bool,
+    framebuffer: Option<(u32, u32)>,
+}
+
+impl Header {
+    /// Finds the first valid header in the first 32 KiB of `image`. Fails if
+    /// it has a required tag this loader does not support.
+    fn find(image: &[u8]) -> Option<Self> {
+        let search = &image[..image.len().min(SEARCH_LIMIT)];
+        let offset = (0..search.len().saturating_sub(16))
+            .step_by(8)
+            .find(|&offset| {
+                let words = [0, 4, 8, 12].map(|index| read_u32(search, offset + index));
+                words[0] == HEADER_MAGIC
+                    && words[1] == ARCHITECTURE_I386
+                    && words.iter().fold(0u32, |sum, word| sum.wrapping_add(*word)) == 0
+            })?;
+        let header_length = read_u32(image, offset + 8) as usize;
+        let tags = image.get(offset..offset + header_length)?;
+        let mut header = Self {
+            offset,
+            ..Self::default()
+        };
+        let mut position = 16;
+        while position + size_of::<HeaderTag>() <= tags.len() {
+            let tag = unsafe {
+                tags[position..]
+                    .as_ptr()
+                    .cast::<HeaderTag>()
+                    .read_unaligned()
+            };
+            let body = tags.get(position + size_of::<HeaderTag>()..position + tag.size as usize)?;
+            match tag.ty {
+                HEADER_TAG_END => break,
+                HEADER_TAG_ADDRESS if body.len() >= size_of::<AddressTag>() => {
+                    header.address =
+                        Some(unsafe { body.as_ptr().cast::<AddressTag>().read_unaligned() });
+                }
+                HEADER_TAG_ENTRY_ADDRESS => header.entry = Some(read_u32(body, 0)),
+                HEADER_TAG_ENTRY_ADDRESS_EFI64 => header.efi_amd64_entry = Some(read_u32(body, 0)),
+                HEADER_TAG_EFI_BS => header.efi_boot_services = true,
+                HEADER_TAG_FRAMEBUFFER => {
+                    header.framebuffer = Some((read_u32(body, 0), read_u32(body, 4)))
+                        .filter(|&(width, height)| width != 0 && height != 0);
+                }
+                HEADER_TAG_INFORMATION_REQUEST
+                | HEADER_TAG_CONSOLE_FLAGS
+                | HEADER_TAG_MODULE_ALIGN
+                | HEADER_TAG_RELOCATABLE => (),
+                _ if tag.flags & TAG_OPTIONAL != 0 => (),
+                _ => return None,
+            }
+            position += (tag.size as usize + 7) & !7;
+        }
+        Some(header)
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes.get(offset..offset + 4).map_or(0, |bytes| {
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    })
+}
+
+/// Writes the boot information into memory allocated up front, so that the
+/// memory map can still be added after `ExitBootServices`.
+struct InfoBuilder {
+    buffer: &'static mut [u8],
+    len: usize,
+}
+
+impl InfoBuilder {
+    fn new(boot_services: &BootServices, capacity: usize) -> Result<Self> {
+        let buffer = allocate(boot_services, MemoryType::LOADER_DATA, capacity)?;
+        let buffer = unsafe { slice::from_raw_parts_mut(buffer as *mut u8, capacity) };
+        buffer.fill(0);
+        Ok(Self { buffer, len: 8 })
+    }
+
+    fn begin(&mut self, ty: u32) -> usize {
+        let start = self.len;
+        self.write(&ty.to_le_bytes());
+        self.write(&0u32.to_le_bytes());
+        start
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn end(&mut self, start: usize) {
+        let size = (self.len - start) as u32;
+        self.buffer[start + 4..start + 8].copy_from_slice(&size.to_le_bytes());
+        self.len = (self.len + 7) & !7;
+    }
+
+    fn tag(&mut self, ty: u32, body: &[u8]) {
+        let tag = self.begin(ty);
+        self.write(body);
+        self.end(tag);
+    }
+
+    fn string(&mut self, ty: u32, string: &str) {
+        let tag = self.begin(ty);
+        self.write(string.as_bytes());
+        self.write(&[0]);
+        self.end(tag);
+    }
+
+    fn framebuffer(&mut self, frame_buffer: &FrameBufferInfo) {
+        if frame_buffer.base == 0 {
+            return;
+        }
+        let tag = self.begin(TAG_FRAMEBUFFER);
+        self.write(&frame_buffer.base.to_le_bytes());
+        self.write(&frame_buffer.pitch().to_le_bytes());
+        self.write(&frame_buffer.width.to_le_bytes());
+        self.write(&frame_buffer.height.to_le_bytes());
+        self.write(&[frame_buffer.bpp as u8, FRAMEBUFFER_TYPE_RGB, 0, 0]);
+        for mask in [
+            frame_buffer.red_mask,
+            frame_buffer.green_mask,
+            frame_buffer.blue_mask,
+        ] {
+            let (size, shift) = gop::mask_size_shift(mask);
+            self.write(&[shift, size]);
+        }
+        self.end(tag);
+    }
+
+    /// Adds the classified and the raw EFI memory map, dropping entries
+    /// that do not fit rather than failing after `ExitBootServices`.
+    fn memory_map(&mut self, memory_map: &MemoryMap) {
+        let regions = memory_map.regions();
+        let room = self.buffer.len() - self.len - 32;
+        let count = regions.len().min(room / 2 / size_of::<MemoryRegion>());
+        let tag = self.begin(TAG_MMAP);
+        self.write(&(size_of::<MemoryRegion>() as u32).to_le_bytes());
+        self.write(&0u32.to_le_bytes());
+        for region in &regions[..count] {
+            self.write(&region.base.to_le_bytes());
+            self.write(&region.length.to_le_bytes());
+            self.write(&memory_type(region.kind).to_le_bytes());
+            self.write(&0u32.to_le_bytes());
+        }
+        self.end(tag);
+        let (efi_memory_map, entry_size, entry_version) = memory_map.efi_memory_map();
+        let room = self.buffer.len() - self.len - 32;
+        let len = efi_memory_map.len().min(room / entry_size * entry_size);
+        let tag = self.begin(TAG_EFI_MMAP);
+        self.write(&(entry_size as u32).to_le_bytes());
+        self.write(&entry_version.to_le_bytes());
+        self.write(&efi_memory_map[..len]);
+        self.end(tag);
+    }
+
+    /// Adds the end tag and returns the address of the structure.
+    fn finish(&mut self) -> u64 {
+        self.tag(TAG_END, &[]);
+        let total_size = self.len as u32;
+        self.buffer[..4].copy_from_slice(&total_size.to_le_bytes());
+        self.buffer.as_ptr() as u64
+    }
+}
+
+global_asm!(
+    ".global multiboot2_trampoline",
+    ".global multiboot2_trampoline32",
+    ".global multiboot2_trampoline_end",
+    "multiboot2_trampoline:",
+    ".code64",
+    "lgdt [rdx]",
+    "push 0x08",
+    "push rcx",
+    "retfq",
+    ".code32",
+    "multiboot2_trampoline32:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ss, ax",
+    "mov eax, cr0",
+    "and eax, 0x7fffffff",
+    "mov cr0, eax",
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "and eax, 0xfffffeff",
+    "wrmsr",
+    "mov eax, 0x36d76289",
+    "mov ebx, esi",
+    "jmp edi",
+    "multiboot2_trampoline_end:",
+    ".code64",
+);
+
+extern "C" {
+    static multiboot2_trampoline: u8;
+    static multiboot2_trampoline32: u8;
+    static multiboot2_trampoline_end: u8;
+}
+
+/// A page below 4 GiB holding a flat 32-bit GDT and the code that drops
+/// from long mode to protected mode, since the loader itself may be
+/// anywhere in memory.
+struct Trampoline {
+    page: u64,
+}
+
+impl Trampoline {
+    const GDT: [u64; 3] = [0, 0x00cf_9a00_0000_ffff, 0x00cf_9200_0000_ffff];
+    const GDTR_OFFSET: u64 = 0x20;
+    const CODE_OFFSET: u64 = 0x40;
+
+    fn new(boot_services: &BootServices) -> Result<Self> {
+        let page = allocate(boot_services, MemoryType::LOADER_CODE, PAGE_SIZE as usize)?;
+        unsafe {
+            let start = &multiboot2_trampoline as *const u8;
+            let end = &multiboot2_trampoline_end as *const u8;
+            let code = (page + Self::CODE_OFFSET) as *mut u8;
+            ptr::copy_nonoverlapping(start, code, end as usize - start as usize);
+            ptr::copy_nonoverlapping(Self::GDT.as_ptr(), page as *mut u64, Self::GDT.len());
+            let gdtr = (page + Self::GDTR_OFFSET) as *mut u8;
+            gdtr.cast::<u16>()
+                .write_unaligned((size_of::<[u64; 3]>() - 1) as u16);
+            gdtr.add(2).cast::<u64>().write_unaligned(page);
+        }
+        Ok(Self { page })
+    }
+
+    unsafe fn enter(&self, entry: u32, info: u32) -> ! {
+        let start = &multiboot2_trampoline as *const u8 as u64;
+        let code32 = &multiboot2_trampoline32 as *const u8 as u64 - start;
+        asm!(
+            "cli",
+            "jmp rax",
+            in("rax") self.page + Self::CODE_OFFSET,
+            in("rcx") self.page + Self::CODE_OFFSET + code32,
+            in("rdx") self.page + Self::GDTR_OFFSET,
+            in("rdi") entry,
+            in("rsi") info,
+            options(noreturn),
+        )
+    }
+}
+
+#[test_case]
+fn find_header() {
+    let mut image = [0u8; 96];
+    let words: [u32; 4] = [
+        HEADER_MAGIC,
+        ARCHITECTURE_I386,
+        56,
+        0u32.wrapping_sub(HEADER_MAGIC.wrapping_add(56)),
+    ];
+    let tags: [u32; 10] = [
+        HEADER_TAG_ENTRY_ADDRESS as u32,
+        12,
+        0x10_0000,
+        0,
+        HEADER_TAG_FRAMEBUFFER as u32 | (TAG_OPTIONAL as u32) << 16,
+        20,
+        1024,
+        768,
+        32,
+        0,
+    ];
+    for (index, word) in words.iter().chain(&tags).enumerate() {
+        image[32 + index * 4..][..4].copy_from_slice(&word.to_le_bytes());
+    }
+    let header = Header::find(&image).expect("Header::find failed");
+    assert_eq!(header.offset, 32);
+    assert_eq!(header.entry, Some(0x10_0000));
+    assert_eq!(header.framebuffer, Some((1024, 768)));
+    image[32 + 16] = 0x7f;
+    assert_eq!(Header::find(&image), None);
+}
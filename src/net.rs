@@ -0,0 +1,548 @@
+use super::{
+    fs::BootServicesExt,
+    gop::{self, Interaction},
+    linux::ProtocolServices,
+    str::ToCString16,
+};
+use alloc::{string::String, vec::Vec};
+use core::{ffi::c_void, ptr, slice};
+use uefi::{
+    prelude::*,
+    proto::{
+        loaded_image::LoadedImage,
+        network::{
+            pxe::{BaseCode, DhcpV4Packet, Packet},
+            IpAddress,
+        },
+        Protocol,
+    },
+    table::boot::{BootServices, EventType, OpenProtocolAttributes, OpenProtocolParams, Tpl},
+    unsafe_guid, CStr8, Char16, Error, Event, Identify, Result,
+};
+
+const HTTP_VERSION_11: u32 = 1;
+const HTTP_METHOD_GET: u32 = 0;
+const HTTP_STATUS_200_OK: u32 = 3;
+const HTTP_TIMEOUT_MS: u32 = 10_000;
+const HTTP_CHUNK_SIZE: usize = 1 << 16;
+/// The most reserved up front for a body, since `Content-Length` is only the
+/// server's word: the rest grows as chunks arrive.
+const HTTP_MAX_RESERVE: u64 = 1 << 26;
+
+const PXE_CALLBACK_CONTINUE: u32 = 0;
+const PXE_CALLBACK_REVISION: u64 = 0x0001_0000;
+const TFTP_HEADER_SIZE: u32 = 4;
+
+/// A file on a TFTP or HTTP server. Without a host, the server is the one
+/// DHCP named when the machine booted from the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Url<'a> {
+    scheme: &'a str,
+    host: &'a str,
+    path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    fn parse(url: &'a str) -> Option<Self> {
+        let (scheme, rest) = url.split_once("://")?;
+        if !["tftp", "http", "https"].contains(&scheme) {
+            return None;
+        }
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        Some(Self { scheme, host, path })
+    }
+
+    fn name(&self) -> &'a str {
+        self.path.rsplit('/').next().unwrap_or(self.path)
+    }
+}
+
+/// Whether `path` names a file on the network rather than on a volume.
+pub fn is_url(path: &str) -> bool {
+    Url::parse(path).is_some()
+}
+
+/// Whether a network interface can fetch `url`, so that callers with a
+/// fallback don't wait on DHCP or show a download that can't start.
+pub fn has_interface(url: &str) -> bool {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let handles = match Url::parse(url).map(|url| url.scheme) {
+        Some("tftp") => boot_services.find_handles::<BaseCode>(),
+        Some(_) => boot_services.find_handles::<HttpServiceBinding>(),
+        None => return false,
+    };
+    handles.map_or(false, |handles| !handles.is_empty())
+}
+
+/// Downloads the file at `url`, showing the progress of the download.
+pub fn fetch(image_handle: Handle, url: &str) -> Result<Vec<u8>> {
+    let url = Url::parse(url).ok_or_else(|| Error::from(Status::INVALID_PARAMETER))?;
+    let mut progress = Progress::new(&url);
+    match url.scheme {
+        "tftp" => tftp(image_handle, &url, &mut progress),
+        _ => http(image_handle, &url, &mut progress),
+    }
+}
+
+/// Reads a file through the PXE Base Code protocol of the first network
+/// interface, asking DHCP for an address first if it has none yet.
+fn tftp(image_handle: Handle, url: &Url, progress: &mut Progress) -> Result<Vec<u8>> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let handle = *boot_services
+        .find_handles::<BaseCode>()?
+        .first()
+        .ok_or_else(|| Error::from(Status::NOT_FOUND))?;
+    let base_code = boot_services.open_protocol::<BaseCode>(
+        OpenProtocolParams {
+            handle,
+            agent: image_handle,
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    )?;
+    let base_code = unsafe { &mut *base_code.interface.get() };
+    if !base_code.mode().started {
+        base_code.start(false)?;
+    }
+    if !base_code.mode().dhcp_ack_received {
+        base_code.dhcp(true)?;
+    }
+    let server = match url.host {
+        "" => dhcp_server(base_code),
+        host => parse_ipv4(host).map(IpAddress::new_v4),
+    };
+    let server = server.ok_or_else(|| Error::from(Status::NOT_FOUND))?;
+    let mut filename = Vec::from(url.path.as_bytes());
+    filename.push(0);
+    let filename = CStr8::from_bytes_with_nul(&filename)
+        .map_err(|_| Error::from(Status::INVALID_PARAMETER))?;
+    let size = base_code.tftp_get_file_size(&server, filename)?;
+    progress.total = Some(size);
+    let mut buffer = vec![0; size as usize];
+    if buffer.is_empty() {
+        return Ok(buffer);
+    }
+    let services = ProtocolServices::get(boot_services);
+    let callback = PxeCallback {
+        revision: PXE_CALLBACK_REVISION,
+        callback: pxe_callback,
+        progress: progress as *mut Progress,
+    };
+    let interface = &callback as *const PxeCallback as *const c_void;
+    let is_counted = services
+        .install(&mut Some(handle), &PxeCallback::GUID, interface)
+        .and_then(|_| base_code.set_parameters(None, None, None, None, Some(true)))
+        .is_ok();
+    let result = base_code.tftp_read_file(&server, filename, Some(&mut buffer));
+    if is_counted {
+        base_code.set_parameters(None, None, None, None, Some(false))?;
+        services.uninstall(handle, &PxeCallback::GUID, interface)?;
+    }
+    result?;
+    Ok(buffer)
+}
+
+/// Reads a file through the HTTP protocol of the first network interface
+/// that has it, which needs the interface to have an address already.
+fn http(image_handle: Handle, url: &Url, progress: &mut Progress) -> Result<Vec<u8>> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let host = match url.host {
+        "" => default_http_host(boot_services, image_handle)
+            .ok_or_else(|| Error::from(Status::NOT_FOUND))?,
+        host => String::from(host),
+    };
+    let handle = *boot_services
+        .find_handles::<HttpServiceBinding>()?
+        .first()
+        .ok_or_else(|| Error::from(Status::NOT_FOUND))?;
+    let service_binding = boot_services.open_protocol::<HttpServiceBinding>(
+        OpenProtocolParams {
+            handle,
+            agent: image_handle,
+            controller: None,
+        },
+        OpenProtocolAttributes::GetProtocol,
+    )?;
+    let service_binding = unsafe { &*service_binding.interface.get() };
+    let mut child = None;
+    unsafe { (service_binding.create_child)(service_binding, &mut child) }.into_with_val(|| ())?;
+    let child = child.ok_or_else(|| Error::from(Status::OUT_OF_RESOURCES))?;
+    let result = boot_services
+        .open_protocol::<Http>(
+            OpenProtocolParams {
+                handle: child,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+        .and_then(|http| {
+            let http = unsafe { &mut *http.interface.get() };
+            let url = format!("{}://{}/{}", url.scheme, host, url.path);
+            get(boot_services, http, &url, &host, progress)
+        });
+    unsafe { (service_binding.destroy_child)(service_binding, child) }.into_with_val(|| ())?;
+    result
+}
+
+/// Sends a GET request and reads the body of the response.
+fn get(
+    boot_services: &BootServices,
+    http: &mut Http,
+    url: &str,
+    host: &str,
+    progress: &mut Progress,
+) -> Result<Vec<u8>> {
+    let access_point = Ipv4AccessPoint {
+        use_default_address: true,
+        local_address: [0; 4],
+        local_subnet: [0; 4],
+        local_port: 0,
+    };
+    let config = HttpConfig {
+        http_version: HTTP_VERSION_11,
+        timeout_millisec: HTTP_TIMEOUT_MS,
+        local_address_is_ipv6: false,
+        access_point: &access_point,
+    };
+    let mut status = unsafe { (http.configure)(http, &config) };
+    for _ in 0..10 {
+        if status != Status::NO_MAPPING {
+            break;
+        }
+        boot_services.stall(1_000_000);
+        status = unsafe { (http.configure)(http, &config) };
+    }
+    status.into_with_val(|| ())?;
+    let event =
+        unsafe { boot_services.create_event(EventType::empty(), Tpl::CALLBACK, None, None)? };
+    let url = url.to_cstring16();
+    let mut request = HttpRequestData {
+        method: HTTP_METHOD_GET,
+        url: url.as_ptr(),
+    };
+    let host = format!("{host}\0");
+    let headers = [
+        HttpHeader {
+            field_name: b"Host\0".as_ptr(),
+            field_value: host.as_ptr(),
+        },
+        HttpHeader {
+            field_name: b"Accept\0".as_ptr(),
+            field_value: b"*/*\0".as_ptr(),
+        },
+    ];
+    let mut message = HttpMessage {
+        data: &mut request as *mut HttpRequestData as *mut c_void,
+        header_count: headers.len(),
+        headers: headers.as_ptr() as *mut HttpHeader,
+        body_length: 0,
+        body: ptr::null_mut(),
+    };
+    let (send, receive) = (http.request, http.response);
+    let result = exchange(boot_services, http, &event, &mut message, send).and_then(|_| {
+        let mut response = HttpResponseData { status_code: 0 };
+        let mut message = HttpMessage {
+            data: &mut response as *mut HttpResponseData as *mut c_void,
+            header_count: 0,
+            headers: ptr::null_mut(),
+            body_length: 0,
+            body: ptr::null_mut(),
+        };
+        exchange(boot_services, http, &event, &mut message, receive)?;
+        progress.total = content_length(boot_services, &message);
+        if response.status_code != HTTP_STATUS_200_OK {
+            return Err(Error::from(Status::HTTP_ERROR));
+        }
+        let reserve = progress.total.unwrap_or_default().min(HTTP_MAX_RESERVE);
+        let mut body = Vec::with_capacity(reserve as usize);
+        let mut chunk = vec![0; HTTP_CHUNK_SIZE];
+        while progress
+            .total
+            .map_or(true, |total| (body.len() as u64) < total)
+        {
+            let mut message = HttpMessage {
+                data: ptr::null_mut(),
+                header_count: 0,
+                headers: ptr::null_mut(),
+                body_length: chunk.len(),
+                body: chunk.as_mut_ptr(),
+            };
+            match exchange(boot_services, http, &event, &mut message, receive) {
+                Ok(()) if message.body_length > 0 => (),
+                Ok(()) => break,
+                Err(_) if progress.total.is_none() && !body.is_empty() => break,
+                Err(err) => return Err(err),
+            }
+            body.extend_from_slice(&chunk[..message.body_length]);
+            progress.advance(message.body_length as u64);
+        }
+        Ok(body)
+    });
+    boot_services.close_event(event)?;
+    result
+}
+
+/// Queues `message` with `queue` and polls until the token completes.
+fn exchange(
+    boot_services: &BootServices,
+    http: &mut Http,
+    event: &Event,
+    message: &mut HttpMessage,
+    queue: unsafe extern "efiapi" fn(this: &mut Http, token: &mut HttpToken) -> Status,
+) -> Result {
+    let mut token = HttpToken {
+        event: unsafe { event.unsafe_clone() },
+        status: Status::SUCCESS,
+        message,
+    };
+    unsafe { queue(http, &mut token) }.into_with_val(|| ())?;
+    while !boot_services.check_event(unsafe { event.unsafe_clone() })? {
+        let _ = unsafe { (http.poll)(http) };
+    }
+    token.status.into()
+}
+
+/// The `Content-Length` of a response, freeing the headers the driver
+/// allocated for it.
+fn content_length(boot_services: &BootServices, message: &HttpMessage) -> Option<u64> {
+    if message.headers.is_null() {
+        return None;
+    }
+    let headers = unsafe { slice::from_raw_parts(message.headers, message.header_count) };
+    let to_str = |text: *const u8| {
+        let len = (0..).take_while(|&i| unsafe { *text.add(i) } != 0).count();
+        core::str::from_utf8(unsafe { slice::from_raw_parts(text, len) }).unwrap_or_default()
+    };
+    let content_length = headers
+        .iter()
+        .find(|header| to_str(header.field_name).eq_ignore_ascii_case("Content-Length"))
+        .and_then(|header| to_str(header.field_value).trim().parse().ok());
+    for header in headers {
+        let _ = boot_services.free_pool(header.field_name as *mut u8);
+        let _ = boot_services.free_pool(header.field_value as *mut u8);
+    }
+    let _ = boot_services.free_pool(message.headers as *mut u8);
+    content_length
+}
+
+/// The boot server DHCP named, preferring a proxy DHCP offer.
+fn dhcp_server(base_code: &BaseCode) -> Option<IpAddress> {
+    let mode = base_code.mode();
+    let packet: &Packet = match mode.proxy_offer_received {
+        true => &mode.proxy_offer,
+        false => &mode.dhcp_ack,
+    };
+    let packet: &DhcpV4Packet = packet.as_ref();
+    let server = packet.bootp_si_addr;
+    (server != [0; 4]).then_some(IpAddress::new_v4(server))
+}
+
+/// The host of the URL the loader was booted from over HTTP, else the boot
+/// server DHCP named for PXE.
+fn default_http_host(boot_services: &BootServices, image_handle: Handle) -> Option<String> {
+    let loaded_image = boot_services
+        .open_protocol::<LoadedImage>(
+            OpenProtocolParams {
+                handle: image_handle,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+        .ok()?;
+    let device = unsafe { &*loaded_image.interface.get() }.device();
+    let device_path = boot_services.get_device_path_text(image_handle, device);
+    let boot_url = device_path.as_deref().and_then(boot_url);
+    if let Some(url) = boot_url.and_then(Url::parse) {
+        return Some(String::from(url.host));
+    }
+    let handle = *boot_services.find_handles::<BaseCode>().ok()?.first()?;
+    let base_code = boot_services
+        .open_protocol::<BaseCode>(
+            OpenProtocolParams {
+                handle,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+        .ok()?;
+    let base_code = unsafe { &*base_code.interface.get() };
+    let server = dhcp_server(base_code)?;
+    let [a, b, c, d, ..] = server.0;
+    Some(format!("{a}.{b}.{c}.{d}"))
+}
+
+/// The URL in the `Uri()` node of a device path in text form.
+fn boot_url(device_path: &str) -> Option<&str> {
+    let (_, url) = device_path.split_once("Uri(")?;
+    url.rsplit_once(')').map(|(url, _)| url)
+}
+
+fn parse_ipv4(host: &str) -> Option<[u8; 4]> {
+    let mut octets = host.split('.').map(str::parse::<u8>);
+    let address = [
+        octets.next()?.ok()?,
+        octets.next()?.ok()?,
+        octets.next()?.ok()?,
+        octets.next()?.ok()?,
+    ];
+    octets.next().is_none().then_some(address)
+}
+
+/// The progress of a download, redrawn each percent, or each MiB when the
+/// size is unknown.
+struct Progress {
+    title: String,
+    done: u64,
+    total: Option<u64>,
+    shown: Option<u64>,
+}
+
+impl Progress {
+    fn new(url: &Url) -> Self {
+        Self {
+            title: format!("Downloading {}", url.name()),
+            done: 0,
+            total: None,
+            shown: None,
+        }
+    }
+
+    fn advance(&mut self, len: u64) {
+        self.done += len;
+        let step = match self.total {
+            Some(total) => self.done * 100 / total.max(1),
+            None => self.done >> 20,
+        };
+        if self.shown != Some(step) {
+            self.shown = Some(step);
+            let _ = gop::get().progress(&self.title, self.done, self.total);
+        }
+    }
+}
+
+#[repr(C)]
+#[unsafe_guid("245dca21-fb7b-11d3-8f01-00a0c969723b")]
+#[derive(Protocol)]
+struct PxeCallback {
+    revision: u64,
+    callback: unsafe extern "efiapi" fn(
+        this: &mut PxeCallback,
+        function: u32,
+        received: bool,
+        packet_len: u32,
+        packet: *const Packet,
+    ) -> u32,
+    progress: *mut Progress,
+}
+
+/// Counts the data of the TFTP packets the PXE Base Code receives.
+unsafe extern "efiapi" fn pxe_callback(
+    this: &mut PxeCallback,
+    _function: u32,
+    received: bool,
+    packet_len: u32,
+    _packet: *const Packet,
+) -> u32 {
+    if received && packet_len > TFTP_HEADER_SIZE {
+        (*this.progress).advance((packet_len - TFTP_HEADER_SIZE) as u64);
+    }
+    PXE_CALLBACK_CONTINUE
+}
+
+#[repr(C)]
+#[unsafe_guid("bdc8e6af-d9bc-4379-a72a-e0c4e75dae1c")]
+#[derive(Protocol)]
+struct HttpServiceBinding {
+    create_child:
+        unsafe extern "efiapi" fn(this: &HttpServiceBinding, child: &mut Option<Handle>) -> Status,
+    destroy_child: unsafe extern "efiapi" fn(this: &HttpServiceBinding, child: Handle) -> Status,
+}
+
+#[repr(C)]
+#[unsafe_guid("7a59b29b-910b-4171-8242-a85a0df25b5b")]
+#[derive(Protocol)]
+struct Http {
+    _get_mode_data: usize,
+    configure: unsafe extern "efiapi" fn(this: &mut Http, config: &HttpConfig) -> Status,
+    request: unsafe extern "efiapi" fn(this: &mut Http, token: &mut HttpToken) -> Status,
+    _cancel: usize,
+    response: unsafe extern "efiapi" fn(this: &mut Http, token: &mut HttpToken) -> Status,
+    poll: unsafe extern "efiapi" fn(this: &mut Http) -> Status,
+}
+
+#[repr(C)]
+struct HttpConfig {
+    http_version: u32,
+    timeout_millisec: u32,
+    local_address_is_ipv6: bool,
+    access_point: *const Ipv4AccessPoint,
+}
+
+#[repr(C)]
+struct Ipv4AccessPoint {
+    use_default_address: bool,
+    local_address: [u8; 4],
+    local_subnet: [u8; 4],
+    local_port: u16,
+}
+
+#[repr(C)]
+struct HttpToken<'a> {
+    event: Event,
+    status: Status,
+    message: &'a mut HttpMessage,
+}
+
+#[repr(C)]
+struct HttpMessage {
+    data: *mut c_void,
+    header_count: usize,
+    headers: *mut HttpHeader,
+    body_length: usize,
+    body: *mut u8,
+}
+
+#[repr(C)]
+struct HttpRequestData {
+    method: u32,
+    url: *const Char16,
+}
+
+#[repr(C)]
+struct HttpResponseData {
+    status_code: u32,
+}
+
+#[repr(C)]
+struct HttpHeader {
+    field_name: *const u8,
+    field_value: *const u8,
+}
+
+#[test_case]
+fn urls() {
+    let url = Url::parse("tftp://10.0.2.2/pxe/vmlinuz").unwrap();
+    assert_eq!(url.host, "10.0.2.2");
+    assert_eq!(url.path, "pxe/vmlinuz");
+    assert_eq!(url.name(), "vmlinuz");
+    assert_eq!(Url::parse("http:///initrd.img").unwrap().host, "");
+    assert!(!is_url("\\EFI\\Linux\\vmlinuz"));
+    assert!(!is_url(
+        "PciRoot(0x0)/HD(1,GPT,c12a7328-f81f-11d2-ba4b-00a0c93ec93b):\\vmlinuz"
+    ));
+    assert_eq!(parse_ipv4("10.0.2.2"), Some([10, 0, 2, 2]));
+    assert_eq!(parse_ipv4("boot.example.com"), None);
+    assert_eq!(
+        boot_url("PciRoot(0x0)/Pci(0x3,0x0)/MAC(525400123456,0x1)/IPv4(0.0.0.0)/Uri(http://10.0.2.2/boot.efi)"),
+        Some("http://10.0.2.2/boot.efi")
+    );
+}
@@ -0,0 +1,143 @@
+use super::{
+    elf::Kernel,
+    gop::FrameBufferInfo,
+    map::{MemoryMap, PAGE_SIZE},
+};
+use core::{arch::asm, slice};
+use uefi::{
+    prelude::*,
+    table::boot::{AllocateType, MemoryType},
+    Error, Result,
+};
+
+/// Where all of physical memory is mapped again in the higher half.
+pub const HHDM_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const HUGE_PAGE: u64 = 1 << 7;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const HUGE_PAGE_SIZE: u64 = 0x20_0000;
+const PML4_ENTRY_SPAN: u64 = 1 << 39;
+const ENTRY_COUNT: usize = 512;
+const CR4_LA57: u64 = 1 << 12;
+const MIN_IDENTITY_MAP: u64 = 0x1_0000_0000;
+
+/// Four-level page tables built in boot services memory for a kernel to
+/// start on.
+pub struct PageTable {
+    pml4: u64,
+}
+
+impl PageTable {
+    /// Identity maps `[0, end)` with 2 MiB pages and mirrors the lower half
+    /// at `HHDM_OFFSET`, so both the loader and the kernel keep running
+    /// once the tables are loaded.
+    pub fn new(boot_services: &BootServices, end: u64) -> Result<Self> {
+        let cr4: u64;
+        unsafe { asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack)) };
+        if cr4 & CR4_LA57 != 0 {
+            return Err(Error::from(Status::UNSUPPORTED));
+        }
+        let mut page_table = Self {
+            pml4: allocate_table(boot_services)?,
+        };
+        for phys in (0..end).step_by(HUGE_PAGE_SIZE as usize) {
+            let table = page_table.table(boot_services, phys, 2)?;
+            table[index(phys, 2)] = phys | PRESENT | WRITABLE | HUGE_PAGE;
+        }
+        let pml4 = entries(page_table.pml4);
+        let (lower_half, higher_half) = pml4.split_at_mut(ENTRY_COUNT / 2);
+        higher_half.copy_from_slice(lower_half);
+        Ok(page_table)
+    }
+
+    /// Tables covering all RAM, the frame buffer and the low 4 GiB, with a
+    /// higher-half `kernel` also mapped at its link address, which must not
+    /// fall within the mirror at `HHDM_OFFSET`.
+    pub fn for_kernel(
+        boot_services: &BootServices,
+        kernel: &Kernel,
+        frame_buffer: &FrameBufferInfo,
+    ) -> Result<Self> {
+        let memory_map = MemoryMap::new(boot_services)?;
+        let end = memory_map
+            .end()
+            .max(frame_buffer.base + frame_buffer.size)
+            .max(MIN_IDENTITY_MAP);
+        memory_map.free(boot_services)?;
+        let mut page_table = Self::new(boot_services, end)?;
+        if kernel.is_higher_half() {
+            // The mirror shares its tables with the lower half, so nothing
+            // else may go in the top level entries it uses.
+            let hhdm_end = HHDM_OFFSET + ((end + PML4_ENTRY_SPAN - 1) & !(PML4_ENTRY_SPAN - 1));
+            if kernel.virtual_base < hhdm_end {
+                return Err(Error::from(Status::LOAD_ERROR));
+            }
+            page_table.map(
+                boot_services,
+                kernel.virtual_base,
+                kernel.physical_base,
+                kernel.size,
+            )?;
+        }
+        Ok(page_table)
+    }
+
+    /// Maps `size` bytes at `virt` to `phys` with 4 KiB pages.
+    pub fn map(&mut self, boot_services: &BootServices, virt: u64, phys: u64, size: u64) -> Result {
+        for offset in (0..size).step_by(PAGE_SIZE as usize) {
+            let table = self.table(boot_services, virt + offset, 1)?;
+            let entry = &mut table[index(virt + offset, 1)];
+            if *entry & PRESENT != 0 {
+                return Err(Error::from(Status::INVALID_PARAMETER));
+            }
+            *entry = (phys + offset) | PRESENT | WRITABLE;
+        }
+        Ok(())
+    }
+
+    /// The physical address of the top level table, for `cr3`.
+    pub fn address(&self) -> u64 {
+        self.pml4
+    }
+
+    /// Walks down to the table at `level` for `virt`, allocating the ones
+    /// in between. Fails where a huge page already covers `virt`.
+    fn table(
+        &mut self,
+        boot_services: &BootServices,
+        virt: u64,
+        level: usize,
+    ) -> Result<&'static mut [u64]> {
+        let mut table = self.pml4;
+        for depth in (level + 1..=4).rev() {
+            let entry = &mut entries(table)[index(virt, depth)];
+            if *entry & PRESENT == 0 {
+                *entry = allocate_table(boot_services)? | PRESENT | WRITABLE;
+            } else if *entry & HUGE_PAGE != 0 {
+                return Err(Error::from(Status::INVALID_PARAMETER));
+            }
+            table = *entry & ADDRESS_MASK;
+        }
+        Ok(entries(table))
+    }
+}
+
+fn index(virt: u64, level: usize) -> usize {
+    (virt >> (12 + 9 * (level - 1))) as usize % ENTRY_COUNT
+}
+
+fn entries(table: u64) -> &'static mut [u64] {
+    unsafe { slice::from_raw_parts_mut(table as *mut u64, ENTRY_COUNT) }
+}
+
+/// Tables go below 4 GiB so that application processors can load `cr3`
+/// while still in 32-bit mode.
+fn allocate_table(boot_services: &BootServices) -> Result<u64> {
+    let below_4g = AllocateType::MaxAddress(u32::MAX as usize);
+    let table = boot_services.allocate_pages(below_4g, MemoryType::LOADER_DATA, 1)?;
+    entries(table).fill(0);
+    Ok(table)
+}
@@ -0,0 +1,102 @@
+use core::{mem::size_of, str};
+
+const DOS_MAGIC: &[u8; 2] = b"MZ";
+const PE_MAGIC: &[u8; 4] = b"PE\0\0";
+const PE_OFFSET: usize = 0x3c;
+
+#[cfg(target_arch = "x86_64")]
+pub const MACHINE: u16 = 0x8664;
+#[cfg(target_arch = "x86")]
+pub const MACHINE: u16 = 0x014c;
+#[cfg(target_arch = "aarch64")]
+pub const MACHINE: u16 = 0xaa64;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+pub const MACHINE: u16 = 0;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct CoffHeader {
+    pub machine: u16,
+    pub number_of_sections: u16,
+    pub time_date_stamp: u32,
+    pub pointer_to_symbol_table: u32,
+    pub number_of_symbols: u32,
+    pub size_of_optional_header: u16,
+    pub characteristics: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SectionHeader {
+    pub name: [u8; 8],
+    pub virtual_size: u32,
+    pub virtual_address: u32,
+    pub size_of_raw_data: u32,
+    pub pointer_to_raw_data: u32,
+    pub pointer_to_relocations: u32,
+    pub pointer_to_linenumbers: u32,
+    pub number_of_relocations: u16,
+    pub number_of_linenumbers: u16,
+    pub characteristics: u32,
+}
+
+impl SectionHeader {
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(8);
+        str::from_utf8(&self.name[..len]).unwrap_or_default()
+    }
+
+    /// Where the section's contents are in the file, without the padding
+    /// to the file alignment.
+    pub fn file_range(&self) -> (u64, usize) {
+        let len = match self.virtual_size {
+            0 => self.size_of_raw_data,
+            virtual_size => virtual_size.min(self.size_of_raw_data),
+        };
+        (self.pointer_to_raw_data as u64, len as usize)
+    }
+}
+
+/// The headers of a PE image, parsed from a prefix of the file.
+pub struct Headers<'a> {
+    pub coff: CoffHeader,
+    section_table: &'a [u8],
+}
+
+impl<'a> Headers<'a> {
+    /// Parses the headers, or reports how many bytes of the file are
+    /// needed if `prefix` is too short to hold the section table.
+    pub fn parse(prefix: &'a [u8]) -> Result<Self, Option<usize>> {
+        if prefix.get(..2) != Some(DOS_MAGIC) {
+            return Err(None);
+        }
+        let pe_offset = read::<u32>(prefix, PE_OFFSET).ok_or(None)? as usize;
+        if prefix.get(pe_offset..pe_offset + 4) != Some(PE_MAGIC) {
+            return Err(None);
+        }
+        let coff = read::<CoffHeader>(prefix, pe_offset + 4).ok_or(None)?;
+        let start = pe_offset + 4 + size_of::<CoffHeader>() + coff.size_of_optional_header as usize;
+        let end = start + coff.number_of_sections as usize * size_of::<SectionHeader>();
+        let section_table = prefix.get(start..end).ok_or(Some(end))?;
+        Ok(Self {
+            coff,
+            section_table,
+        })
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = SectionHeader> + 'a {
+        let section_table = self.section_table;
+        section_table
+            .chunks_exact(size_of::<SectionHeader>())
+            .filter_map(|section| read::<SectionHeader>(section, 0))
+    }
+
+    pub fn section(&self, name: &str) -> Option<SectionHeader> {
+        self.sections().find(|section| section.name() == name)
+    }
+}
+
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    let bytes = bytes.get(offset..offset.checked_add(size_of::<T>())?)?;
+    Some(unsafe { bytes.as_ptr().cast::<T>().read_unaligned() })
+}
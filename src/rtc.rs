@@ -0,0 +1,149 @@
+use alloc::string::String;
+use uefi::{
+    table::{
+        runtime::{Time, TimeParams},
+        Header,
+    },
+    Result, Status,
+};
+
+/// Arms the RTC alarm so that the machine powers on at `time`.
+pub fn set_wakeup_time(time: &Time) -> Result {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let services = system_table.runtime_services() as *const _ as *const TimeServices;
+    unsafe { ((*services).set_wakeup_time)(true, time) }.into()
+}
+
+/// The time in the RTC's time zone, for prefilling a time to edit.
+pub fn now() -> Result<Time> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    system_table.runtime_services().get_time()
+}
+
+pub fn format(time: &Time) -> String {
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        time.year(),
+        time.month(),
+        time.day(),
+        time.hour(),
+        time.minute()
+    )
+}
+
+/// Parses `YYYY-MM-DD HH:MM`, or `HH:MM` for the next time the clock shows
+/// it after `now`, in the time zone of `now`.
+pub fn parse(now: &Time, text: &str) -> Option<Time> {
+    let text = text.trim();
+    let (date, time) = text.rsplit_once(' ').unwrap_or(("", text));
+    let (hour, minute) = time.split_once(':')?;
+    let (hour, minute) = (hour.parse().ok()?, minute.parse().ok()?);
+    let (year, month, day) = match date.trim() {
+        "" => {
+            let (year, month, day) = (now.year(), now.month(), now.day());
+            match (hour, minute) > (now.hour(), now.minute()) {
+                true => (year, month, day),
+                false => next_day(year, month, day),
+            }
+        }
+        date => {
+            let mut fields = date.splitn(3, '-');
+            let year = fields.next()?.parse().ok()?;
+            let month = fields.next()?.parse().ok()?;
+            let day = fields.next()?.parse().ok()?;
+            (year, month, day)
+        }
+    };
+    if day == 0 || day > days_in_month(year, month) {
+        return None;
+    }
+    Time::new(TimeParams {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second: 0,
+        nanosecond: 0,
+        time_zone: now.time_zone(),
+        daylight: now.daylight(),
+    })
+    .ok()
+}
+
+/// Whether `time` comes after `now` to the minute, in the same time zone.
+pub fn is_after(time: &Time, now: &Time) -> bool {
+    let minutes = |time: &Time| {
+        (
+            time.year(),
+            time.month(),
+            time.day(),
+            time.hour(),
+            time.minute(),
+        )
+    };
+    minutes(time) > minutes(now)
+}
+
+fn next_day(year: u16, month: u8, day: u8) -> (u16, u8, u8) {
+    match (day < days_in_month(year, month), month) {
+        (true, _) => (year, month, day + 1),
+        (false, 12) => (year + 1, 1, 1),
+        (false, _) => (year, month + 1, 1),
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    let is_leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    match month {
+        2 if is_leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => 0,
+    }
+}
+
+/// The time services that `uefi` leaves opaque in `RuntimeServices`.
+#[repr(C)]
+struct TimeServices {
+    header: Header,
+    _get_time: usize,
+    _set_time: usize,
+    _get_wakeup_time: usize,
+    set_wakeup_time: unsafe extern "efiapi" fn(enable: bool, time: *const Time) -> Status,
+}
+
+#[test_case]
+fn parse_wake_time() {
+    let time = |year, month, day, hour, minute| {
+        Time::new(TimeParams {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second: 0,
+            nanosecond: 0,
+            time_zone: None,
+            daylight: uefi::table::runtime::Daylight::empty(),
+        })
+        .unwrap()
+    };
+    let now = time(2024, 2, 28, 22, 15);
+    assert_eq!(format(&parse(&now, "23:00").unwrap()), "2024-02-28 23:00");
+    assert_eq!(format(&parse(&now, "06:30").unwrap()), "2024-02-29 06:30");
+    let now = time(2023, 12, 31, 22, 15);
+    assert_eq!(format(&parse(&now, "06:30").unwrap()), "2024-01-01 06:30");
+    assert_eq!(
+        format(&parse(&now, "2024-03-01 7:05").unwrap()),
+        "2024-03-01 07:05"
+    );
+    assert!(parse(&now, "2023-02-29 07:00").is_none());
+    assert!(parse(&now, "2024-257-01 07:00").is_none());
+    assert!(parse(&now, "25:00").is_none());
+    assert!(is_after(&parse(&now, "22:15").unwrap(), &now));
+    assert!(!is_after(&parse(&now, "2023-12-31 22:15").unwrap(), &now));
+}
@@ -0,0 +1,173 @@
+use super::var;
+use core::{
+    ffi::c_void,
+    fmt::{self, Display, Formatter},
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use uefi::{
+    proto::Protocol,
+    table::{
+        boot::{BootServices, LoadImageSource},
+        runtime::VariableVendor,
+    },
+    unsafe_guid, Handle, Result, Status,
+};
+
+/// Whether the firmware enforces signatures, as the `SecureBoot` and
+/// `SetupMode` variables tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Disabled,
+    Enabled,
+    /// No platform key is enrolled, so nothing is enforced and the keys can
+    /// be changed without authentication.
+    SetupMode,
+}
+
+impl State {
+    pub fn current() -> Self {
+        let read = |name| {
+            var::get(name, &VariableVendor::GLOBAL_VARIABLE).and_then(|data| data.first().copied())
+        };
+        Self::from_variables(read("SecureBoot"), read("SetupMode"))
+    }
+
+    fn from_variables(secure_boot: Option<u8>, setup_mode: Option<u8>) -> Self {
+        match (secure_boot, setup_mode) {
+            (_, Some(1)) => Self::SetupMode,
+            (Some(1), _) => Self::Enabled,
+            _ => Self::Disabled,
+        }
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disabled => write!(f, "Secure Boot: off"),
+            Self::Enabled => write!(f, "Secure Boot: on"),
+            Self::SetupMode => write!(f, "Secure Boot: setup mode"),
+        }
+    }
+}
+
+/// Checks an image the loader runs itself rather than through `LoadImage`,
+/// with shim when it is there and else by having the firmware load and
+/// unload it. Anything goes while Secure Boot is off.
+pub fn verify(boot_services: &BootServices, image_handle: Handle, buffer: &[u8]) -> Result {
+    if State::current() != State::Enabled {
+        return Ok(());
+    }
+    if let Some(shim_lock) = shim_lock(boot_services) {
+        return unsafe { (shim_lock.verify)(buffer.as_ptr(), buffer.len() as u32) }.into();
+    }
+    let handle = boot_services.load_image(
+        image_handle,
+        LoadImageSource::FromBuffer {
+            buffer,
+            file_path: None,
+        },
+    )?;
+    boot_services.unload_image(handle)
+}
+
+/// Runs `load_image` with the firmware also accepting what shim accepts, so
+/// that images signed with a Machine Owner Key load under Secure Boot.
+pub fn with_shim<T>(boot_services: &BootServices, load_image: impl FnOnce() -> T) -> T {
+    let shim_lock = match State::current() {
+        State::Enabled => shim_lock(boot_services),
+        _ => None,
+    };
+    let security2 = shim_lock.and_then(|_| boot_services.locate_protocol::<Security2>().ok());
+    let (shim_lock, security2) = match (shim_lock, security2) {
+        (Some(shim_lock), Some(security2)) => (shim_lock, unsafe { &mut *security2.get() }),
+        _ => return load_image(),
+    };
+    SHIM_LOCK.store(shim_lock as *const _ as *mut _, Ordering::Relaxed);
+    let original = security2.file_authentication;
+    ORIGINAL_FILE_AUTHENTICATION.store(original as *mut c_void, Ordering::Relaxed);
+    security2.file_authentication = file_authentication;
+    let result = load_image();
+    security2.file_authentication = original;
+    SHIM_LOCK.store(ptr::null_mut(), Ordering::Relaxed);
+    result
+}
+
+/// What a failed verification means, for telling the user.
+pub fn describe(status: Status) -> &'static str {
+    match status {
+        Status::SECURITY_VIOLATION => "The image is not signed by a trusted key",
+        Status::ACCESS_DENIED => "The image or its signer is forbidden",
+        Status::LOAD_ERROR | Status::UNSUPPORTED | Status::INVALID_PARAMETER => {
+            "The image is not a signed EFI image"
+        }
+        _ => "The image could not be verified",
+    }
+}
+
+fn shim_lock(boot_services: &BootServices) -> Option<&ShimLock> {
+    let shim_lock = boot_services.locate_protocol::<ShimLock>().ok()?;
+    Some(unsafe { &*shim_lock.get() })
+}
+
+static SHIM_LOCK: AtomicPtr<ShimLock> = AtomicPtr::new(ptr::null_mut());
+static ORIGINAL_FILE_AUTHENTICATION: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+type FileAuthentication = unsafe extern "efiapi" fn(
+    this: *const Security2,
+    device_path: *const c_void,
+    file_buffer: *const u8,
+    file_size: usize,
+    boot_policy: bool,
+) -> Status;
+
+/// Accepts the images shim accepts and leaves the others to the firmware.
+unsafe extern "efiapi" fn file_authentication(
+    this: *const Security2,
+    device_path: *const c_void,
+    file_buffer: *const u8,
+    file_size: usize,
+    boot_policy: bool,
+) -> Status {
+    let shim_lock = SHIM_LOCK.load(Ordering::Relaxed);
+    if !shim_lock.is_null()
+        && !file_buffer.is_null()
+        && ((*shim_lock).verify)(file_buffer, file_size as u32) == Status::SUCCESS
+    {
+        return Status::SUCCESS;
+    }
+    let original = ORIGINAL_FILE_AUTHENTICATION.load(Ordering::Relaxed);
+    let original = core::mem::transmute::<*mut c_void, FileAuthentication>(original);
+    original(this, device_path, file_buffer, file_size, boot_policy)
+}
+
+/// shim's functions use the System V calling convention even on x86_64.
+#[cfg(target_arch = "x86_64")]
+type ShimVerify = unsafe extern "sysv64" fn(buffer: *const u8, size: u32) -> Status;
+#[cfg(not(target_arch = "x86_64"))]
+type ShimVerify = unsafe extern "C" fn(buffer: *const u8, size: u32) -> Status;
+
+#[repr(C)]
+#[unsafe_guid("605dab50-e046-4300-abb6-3dd810dd8b23")]
+#[derive(Protocol)]
+struct ShimLock {
+    verify: ShimVerify,
+    _hash: usize,
+    _context: usize,
+}
+
+#[repr(C)]
+#[unsafe_guid("94ab2f58-1438-4ef1-9152-18941a3a0e68")]
+#[derive(Protocol)]
+struct Security2 {
+    file_authentication: FileAuthentication,
+}
+
+#[test_case]
+fn state() {
+    assert_eq!(State::from_variables(None, None), State::Disabled);
+    assert_eq!(State::from_variables(Some(0), Some(0)), State::Disabled);
+    assert_eq!(State::from_variables(Some(1), Some(0)), State::Enabled);
+    assert_eq!(State::from_variables(Some(0), Some(1)), State::SetupMode);
+}
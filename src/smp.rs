@@ -0,0 +1,320 @@
+use super::map::PAGE_SIZE;
+use alloc::vec::Vec;
+use core::{
+    arch::{
+        asm, global_asm,
+        x86_64::{__cpuid, _rdtsc},
+    },
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use uefi::{
+    prelude::*,
+    table::boot::{AllocateType, MemoryType},
+    Result,
+};
+
+/// Null, 16-bit, 32-bit and 64-bit code and data descriptors, in the order
+/// the Limine protocol promises and the trampoline relies on.
+pub const GDT: [u64; 7] = [
+    0,
+    0x0000_9a00_0000_ffff,
+    0x0000_9200_0000_ffff,
+    0x00cf_9a00_0000_ffff,
+    0x00cf_9200_0000_ffff,
+    0x00af_9a00_0000_ffff,
+    0x00cf_9200_0000_ffff,
+];
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const IA32_EFER: u32 = 0xc000_0080;
+const X2APIC_ICR: u32 = 0x830;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const EFER_LME: u64 = 1 << 8;
+const EFER_NXE: u64 = 1 << 11;
+
+const XAPIC_ICR_LOW: u64 = 0x300;
+const XAPIC_ICR_HIGH: u64 = 0x310;
+const ICR_INIT: u32 = 0x4500;
+const ICR_STARTUP: u32 = 0x4600;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_X2APIC: u8 = 9;
+const MADT_ENABLED: u32 = 1 << 0;
+
+const GDT_OFFSET: usize = 0xf00;
+const GDTR_OFFSET: usize = 0xf40;
+const CR3_OFFSET: usize = 0xf50;
+const EFER_OFFSET: usize = 0xf54;
+const LONG_MODE_OFFSET: usize = 0xf58;
+const STACK_OFFSET: usize = 0xf60;
+const INFO_OFFSET: usize = 0xf68;
+const BOOTED_OFFSET: usize = 0xf70;
+
+/// A processor listed as enabled in the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu {
+    pub processor_id: u32,
+    pub lapic_id: u32,
+}
+
+pub fn x2apic_enabled() -> bool {
+    unsafe { rdmsr(IA32_APIC_BASE) & APIC_BASE_X2APIC != 0 }
+}
+
+/// The local APIC ID of the processor running the loader.
+pub fn bsp_lapic_id() -> u32 {
+    match x2apic_enabled() {
+        true => unsafe { __cpuid(0xb).edx },
+        false => unsafe { __cpuid(1).ebx >> 24 },
+    }
+}
+
+/// Lists the enabled processors from the MADT that `rsdp` leads to.
+pub fn cpus(rsdp: u64) -> Vec<Cpu> {
+    let mut cpus = Vec::new();
+    let madt = match find_table(rsdp, *b"APIC") {
+        Some(madt) => madt,
+        None => return cpus,
+    };
+    let madt = unsafe { table(madt) };
+    let mut entries = madt.get(44..).unwrap_or_default();
+    while let [ty, len, ..] = *entries {
+        let (entry, rest) = entries.split_at((len as usize).clamp(2, entries.len()));
+        let cpu = match ty {
+            MADT_LOCAL_APIC if entry.len() >= 8 && read_u32(entry, 4) & MADT_ENABLED != 0 => {
+                Some(Cpu {
+                    processor_id: entry[2] as u32,
+                    lapic_id: entry[3] as u32,
+                })
+            }
+            MADT_LOCAL_X2APIC if entry.len() >= 16 && read_u32(entry, 8) & MADT_ENABLED != 0 => {
+                Some(Cpu {
+                    processor_id: read_u32(entry, 12),
+                    lapic_id: read_u32(entry, 4),
+                })
+            }
+            _ => None,
+        };
+        cpus.extend(cpu);
+        entries = rest;
+    }
+    cpus
+}
+
+/// Finds an ACPI table by signature through the XSDT, or the RSDT on
+/// ACPI 1.0 firmware.
+fn find_table(rsdp: u64, signature: [u8; 4]) -> Option<u64> {
+    let rsdp = unsafe { &*ptr::slice_from_raw_parts(rsdp as *const u8, 36) };
+    let (sdt, entry_size) = match rsdp[15] {
+        0 | 1 => (read_u32(rsdp, 16) as u64, 4),
+        _ => (read_u64(rsdp, 24), 8),
+    };
+    let sdt = unsafe { table(sdt) };
+    sdt.get(36..)?
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            4 => read_u32(entry, 0) as u64,
+            _ => read_u64(entry, 0),
+        })
+        .find(|&table| unsafe { *(table as *const [u8; 4]) } == signature)
+}
+
+/// The whole of a system description table, as long as its header says.
+unsafe fn table<'a>(address: u64) -> &'a [u8] {
+    let len = (address as *const u8).add(4).cast::<u32>().read_unaligned();
+    &*ptr::slice_from_raw_parts(address as *const u8, len as usize)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap_or_default())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap_or_default())
+}
+
+global_asm!(
+    ".global smp_trampoline",
+    ".global smp_trampoline_far_jump",
+    ".global smp_trampoline32",
+    ".global smp_trampoline64",
+    ".global smp_trampoline_end",
+    ".code16",
+    "smp_trampoline:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "xor ebx, ebx",
+    "mov bx, ax",
+    "shl ebx, 4",
+    "lgdt [0xf40]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    ".byte 0x66, 0xea",
+    "smp_trampoline_far_jump:",
+    ".long 0",
+    ".word 0x18",
+    ".code32",
+    "smp_trampoline32:",
+    "mov ax, 0x20",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov eax, cr4",
+    "or eax, 0x20",
+    "mov cr4, eax",
+    "mov eax, [ebx + 0xf50]",
+    "mov cr3, eax",
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, [ebx + 0xf54]",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, 0x80000000",
+    "mov cr0, eax",
+    "push 0x28",
+    "push dword ptr [ebx + 0xf58]",
+    "retf",
+    ".code64",
+    "smp_trampoline64:",
+    "mov ebx, ebx",
+    "mov ax, 0x30",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov rsp, [rbx + 0xf60]",
+    "mov rdi, [rbx + 0xf68]",
+    "lock inc dword ptr [rbx + 0xf70]",
+    "2:",
+    "pause",
+    "mov rax, [rdi + 16]",
+    "test rax, rax",
+    "jz 2b",
+    "push 0",
+    "jmp rax",
+    "smp_trampoline_end:",
+);
+
+extern "C" {
+    static smp_trampoline: u8;
+    static smp_trampoline_far_jump: u8;
+    static smp_trampoline32: u8;
+    static smp_trampoline64: u8;
+    static smp_trampoline_end: u8;
+}
+
+/// Brings application processors from reset into long mode on the given
+/// page tables, through a trampoline page below 1 MiB. Each one is handed
+/// a stack and an info pointer in `rdi`, then spins until the `u64` at
+/// `info + 16` becomes non-zero and jumps there, as Limine's `goto_address`.
+pub struct ApStartup {
+    page: u64,
+    ticks_per_us: u64,
+}
+
+impl ApStartup {
+    /// Prepares the trampoline while the firmware still provides memory
+    /// and a timer to calibrate the TSC against. `page_table` must lie
+    /// below 4 GiB.
+    pub fn new(boot_services: &BootServices, page_table: u64) -> Result<Self> {
+        let below_1m = AllocateType::MaxAddress(0xf_ffff);
+        let page = boot_services.allocate_pages(below_1m, MemoryType::LOADER_DATA, 1)?;
+        unsafe {
+            let base = page as *mut u8;
+            base.write_bytes(0, PAGE_SIZE as usize);
+            let start = &smp_trampoline as *const u8;
+            let end = &smp_trampoline_end as *const u8;
+            ptr::copy_nonoverlapping(start, base, end as usize - start as usize);
+            let offset = |symbol: &u8| symbol as *const u8 as usize - start as usize;
+            let far_jump = base.add(offset(&smp_trampoline_far_jump)).cast::<u32>();
+            far_jump.write_unaligned((page as usize + offset(&smp_trampoline32)) as u32);
+            let long_mode = base.add(LONG_MODE_OFFSET).cast::<u32>();
+            long_mode.write((page as usize + offset(&smp_trampoline64)) as u32);
+            let gdt = base.add(GDT_OFFSET).cast::<u64>();
+            ptr::copy_nonoverlapping(GDT.as_ptr(), gdt, GDT.len());
+            let gdtr = base.add(GDTR_OFFSET);
+            gdtr.cast::<u16>()
+                .write_unaligned((GDT.len() * 8 - 1) as u16);
+            gdtr.add(2)
+                .cast::<u32>()
+                .write_unaligned(page as u32 + GDT_OFFSET as u32);
+            base.add(CR3_OFFSET).cast::<u32>().write(page_table as u32);
+            let efer = EFER_LME | rdmsr(IA32_EFER) & EFER_NXE;
+            base.add(EFER_OFFSET).cast::<u32>().write(efer as u32);
+        }
+        let start = unsafe { _rdtsc() };
+        boot_services.stall(1000);
+        let ticks_per_us = (unsafe { _rdtsc() } - start) / 1000;
+        Ok(Self {
+            page,
+            ticks_per_us: ticks_per_us.max(1),
+        })
+    }
+
+    /// Sends INIT and up to two STARTUP IPIs to `lapic_id` and reports
+    /// whether the processor reached long mode.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called once boot services are gone, so the firmware no
+    /// longer owns the processor, and with `stack` and `info` mapped in the
+    /// trampoline's page tables.
+    pub unsafe fn start(&self, lapic_id: u32, stack: u64, info: u64) -> bool {
+        let base = self.page as *mut u8;
+        base.add(STACK_OFFSET).cast::<u64>().write_volatile(stack);
+        base.add(INFO_OFFSET).cast::<u64>().write_volatile(info);
+        let booted = &*base.add(BOOTED_OFFSET).cast::<AtomicU32>();
+        booted.store(0, Ordering::SeqCst);
+        send_ipi(lapic_id, ICR_INIT);
+        self.delay(10_000);
+        let vector = (self.page >> 12) as u32;
+        for timeout in [200, 100_000] {
+            send_ipi(lapic_id, ICR_STARTUP | vector);
+            let deadline = _rdtsc() + timeout * self.ticks_per_us;
+            while _rdtsc() < deadline {
+                if booted.load(Ordering::SeqCst) != 0 {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn delay(&self, us: u64) {
+        let deadline = unsafe { _rdtsc() } + us * self.ticks_per_us;
+        while unsafe { _rdtsc() } < deadline {}
+    }
+}
+
+unsafe fn send_ipi(lapic_id: u32, command: u32) {
+    let apic_base = rdmsr(IA32_APIC_BASE);
+    if apic_base & APIC_BASE_X2APIC != 0 {
+        return wrmsr(X2APIC_ICR, (lapic_id as u64) << 32 | command as u64);
+    }
+    let apic_base = apic_base & !(PAGE_SIZE - 1) & 0x000f_ffff_ffff_ffff;
+    let icr_low = (apic_base + XAPIC_ICR_LOW) as *mut u32;
+    let icr_high = (apic_base + XAPIC_ICR_HIGH) as *mut u32;
+    icr_high.write_volatile(lapic_id << 24);
+    icr_low.write_volatile(command);
+    while icr_low.read_volatile() & ICR_DELIVERY_PENDING != 0 {}
+}
+
+unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+    (high as u64) << 32 | low as u64
+}
+
+unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack),
+    );
+}
@@ -0,0 +1,154 @@
+use super::{
+    bls::version_compare,
+    cfg::{BootEntry, EntryKind},
+    fs::{self, BootServicesExt, FileExt, FileSystem},
+    rtc, vfs,
+};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::cmp::Reverse;
+use uefi::{prelude::*, proto::media::file::FileMode};
+
+/// Makes an entry for every kernel in `/boot` of every subvolume and
+/// snapshot on the volumes the loader reads itself, starting with the
+/// default subvolume and then the newest snapshots.
+pub fn entries(image_handle: Handle) -> Vec<BootEntry> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let mut entries = Vec::new();
+    for volume in fs::volumes() {
+        let reader = match vfs::get_volume(image_handle, volume) {
+            Some(reader) => reader,
+            None => continue,
+        };
+        let mut subvolumes = reader.subvolumes();
+        if subvolumes.is_empty() {
+            continue;
+        }
+        let uuid = vfs::format_uuid(&reader.info().uuid);
+        let file_system = match fs::get_volume(image_handle, volume) {
+            Ok(file_system) => file_system,
+            Err(_) => continue,
+        };
+        let device_path = match boot_services.get_device_path_text(image_handle, volume) {
+            Some(device_path) => device_path,
+            None => continue,
+        };
+        subvolumes.sort_by_key(|subvolume| (!subvolume.is_default, Reverse(subvolume.created)));
+        for subvolume in subvolumes {
+            let root = match subvolume.path.is_empty() {
+                true => String::new(),
+                false => format!("\\{}", subvolume.path.replace('/', "\\")),
+            };
+            let boot = format!("{root}\\boot");
+            let names = match file_system.read_dir(&boot) {
+                Ok(names) => names,
+                Err(_) => continue,
+            };
+            let cmdline = file_system
+                .open(&format!("{root}\\etc\\kernel\\cmdline"), FileMode::Read)
+                .and_then(|mut file| file.load())
+                .map(|cmdline| String::from_utf8_lossy(&cmdline).into_owned())
+                .unwrap_or_default();
+            let options = options(&cmdline, &uuid, &subvolume.path);
+            let name = match (subvolume.path.as_str(), subvolume.is_default) {
+                ("", _) => "Top level".to_string(),
+                (path, true) => format!("{path} (default)"),
+                (path, false) if !subvolume.is_snapshot || subvolume.created == 0 => {
+                    path.to_string()
+                }
+                (path, false) => {
+                    format!("{path} ({})", rtc::format(&vfs::time(subvolume.created)))
+                }
+            };
+            for (kernel, initrd, version) in kernels(&names) {
+                let path = |name| format!("{device_path}:{boot}\\{name}");
+                entries.push(BootEntry {
+                    id: format!("snapshot-{}-{version}", subvolume.id),
+                    title: format!("{name}: {version}"),
+                    kind: EntryKind::Linux,
+                    path: path(kernel),
+                    initrd: initrd.map(path).into_iter().collect(),
+                    options: options.clone(),
+                    ..BootEntry::default()
+                });
+            }
+        }
+    }
+    entries
+}
+
+/// The kernels among the files in `/boot` with their initrds and versions,
+/// newest first.
+fn kernels(names: &[String]) -> Vec<(String, Option<String>, String)> {
+    let mut kernels = names
+        .iter()
+        .filter_map(|name| {
+            let version = ["vmlinuz-", "Image-"]
+                .iter()
+                .find_map(|prefix| name.strip_prefix(prefix))?;
+            let initrd = [
+                format!("initrd-{version}"),
+                format!("initrd.img-{version}"),
+                format!("initramfs-{version}.img"),
+            ]
+            .into_iter()
+            .find(|initrd| names.contains(initrd));
+            Some((name.clone(), initrd, version.to_string()))
+        })
+        .collect::<Vec<_>>();
+    kernels.sort_by(|a, b| version_compare(&b.2, &a.2));
+    kernels
+}
+
+/// Mounts the subvolume at `path` as the root file system, keeping the rest
+/// of the snapshot's own `/etc/kernel/cmdline`.
+fn options(cmdline: &str, uuid: &str, path: &str) -> String {
+    let mut options = Vec::from([format!("root=UUID={uuid}")]);
+    if !path.is_empty() {
+        options.push(format!("rootflags=subvol={path}"));
+    }
+    options.extend(
+        cmdline
+            .split_whitespace()
+            .filter(|option| !option.starts_with("root=") && !option.starts_with("rootflags="))
+            .map(String::from),
+    );
+    options.join(" ")
+}
+
+#[test_case]
+fn snapshot_kernels() {
+    let names = [
+        "config-6.4.0-1-default",
+        "initrd-6.4.0-1-default",
+        "initrd-6.5.1-1-default",
+        "vmlinuz-6.4.0-1-default",
+        "vmlinuz-6.5.1-1-default",
+        "vmlinuz-6.6.0-rc1",
+    ]
+    .map(String::from);
+    let kernels = kernels(&names);
+    let versions = kernels
+        .iter()
+        .map(|(_, _, version)| version.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        versions,
+        ["6.6.0-rc1", "6.5.1-1-default", "6.4.0-1-default"]
+    );
+    assert_eq!(kernels[0].1, None);
+    assert_eq!(kernels[1].1.as_deref(), Some("initrd-6.5.1-1-default"));
+    assert_eq!(
+        options(
+            "root=/dev/sda2 rootflags=ro quiet\n",
+            "0-1",
+            "@/.snapshots/2/snapshot"
+        ),
+        "root=UUID=0-1 rootflags=subvol=@/.snapshots/2/snapshot quiet"
+    );
+    assert_eq!(options("", "0-1", ""), "root=UUID=0-1");
+}
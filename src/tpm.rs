@@ -0,0 +1,119 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use uefi::{prelude::*, proto::Protocol, unsafe_guid, Result};
+
+/// Kernels the loader starts itself, which the firmware measures into the
+/// same PCR when it loads an image.
+pub const PCR_BOOT_LOADER_CODE: u32 = 4;
+/// The loader's configuration.
+pub const PCR_BOOT_LOADER_CONFIG: u32 = 5;
+/// Initrds, as systemd-stub and the Linux EFI stub measure them.
+pub const PCR_KERNEL_INITRD: u32 = 9;
+/// Kernel command lines, as systemd-boot and systemd-stub measure them.
+pub const PCR_KERNEL_CONFIG: u32 = 12;
+
+const EV_IPL: u32 = 0xd;
+const EFI_TCG2_EVENT_HEADER_VERSION: u16 = 1;
+
+/// Extends `pcr` with the digests of `data` in every active bank and logs it
+/// with `description` as a UTF-16 string, like systemd does. Without a TPM
+/// 2.0 there is nothing to do.
+pub fn measure(pcr: u32, data: &[u8], description: &str) -> Result {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let tcg2 = match system_table.boot_services().locate_protocol::<Tcg2>() {
+        Ok(tcg2) => unsafe { &mut *tcg2.get() },
+        Err(_) => return Ok(()),
+    };
+    let mut capability = BootServiceCapability {
+        size: size_of::<BootServiceCapability>() as u8,
+        ..BootServiceCapability::default()
+    };
+    let status = unsafe { (tcg2.get_capability)(tcg2, &mut capability) };
+    if status.is_error() || capability.tpm_present_flag == 0 {
+        return Ok(());
+    }
+    let event = event(pcr, description);
+    unsafe {
+        (tcg2.hash_log_extend_event)(
+            tcg2,
+            0,
+            data.as_ptr() as u64,
+            data.len() as u64,
+            event.as_ptr(),
+        )
+    }
+    .into()
+}
+
+/// The UTF-16 form with a terminating NUL in which systemd measures command
+/// lines.
+pub fn utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+/// An `EFI_TCG2_EVENT` of type `EV_IPL` with `description` as its data.
+fn event(pcr: u32, description: &str) -> Vec<u8> {
+    let data = utf16(description);
+    let header_size = size_of::<u32>() + size_of::<u16>() + 2 * size_of::<u32>();
+    let size = size_of::<u32>() + header_size + data.len();
+    let mut event = Vec::with_capacity(size);
+    event.extend((size as u32).to_le_bytes());
+    event.extend((header_size as u32).to_le_bytes());
+    event.extend(EFI_TCG2_EVENT_HEADER_VERSION.to_le_bytes());
+    event.extend(pcr.to_le_bytes());
+    event.extend(EV_IPL.to_le_bytes());
+    event.extend(data);
+    event
+}
+
+#[repr(C, packed)]
+#[derive(Default)]
+struct BootServiceCapability {
+    size: u8,
+    structure_version: [u8; 2],
+    protocol_version: [u8; 2],
+    hash_algorithm_bitmap: u32,
+    supported_event_logs: u32,
+    tpm_present_flag: u8,
+    max_command_size: u16,
+    max_response_size: u16,
+    manufacturer_id: u32,
+    number_of_pcr_banks: u32,
+    active_pcr_banks: u32,
+}
+
+#[repr(C)]
+#[unsafe_guid("607f766c-7455-42be-930b-e4d76db2720f")]
+#[derive(Protocol)]
+struct Tcg2 {
+    get_capability:
+        unsafe extern "efiapi" fn(this: &Tcg2, capability: &mut BootServiceCapability) -> Status,
+    _get_event_log: usize,
+    hash_log_extend_event: unsafe extern "efiapi" fn(
+        this: &Tcg2,
+        flags: u64,
+        data_to_hash: u64,
+        data_to_hash_len: u64,
+        event: *const u8,
+    ) -> Status,
+    _submit_command: usize,
+    _get_active_pcr_banks: usize,
+    _set_active_pcr_banks: usize,
+    _get_result_of_set_active_pcr_banks: usize,
+}
+
+#[test_case]
+fn ipl_event() {
+    let event = event(PCR_KERNEL_CONFIG, "ro");
+    assert_eq!(event.len(), 4 + 14 + 6);
+    assert_eq!(event[..4], 24u32.to_le_bytes());
+    assert_eq!(event[4..8], 14u32.to_le_bytes());
+    assert_eq!(event[8..10], 1u16.to_le_bytes());
+    assert_eq!(event[10..14], 12u32.to_le_bytes());
+    assert_eq!(event[14..18], 0xdu32.to_le_bytes());
+    assert_eq!(event[18..], *b"r\0o\0\0\0");
+}
@@ -0,0 +1,124 @@
+use super::{
+    bls::BlsEntry,
+    fs::{self, FileExt, FileSystem},
+    pe::{self, Headers},
+};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use uefi::{
+    proto::media::file::{File, FileInfo, FileMode, RegularFile},
+    Handle,
+};
+
+pub const UKI_PATH: &str = "\\EFI\\Linux";
+
+const HEADERS_SIZE: usize = 0x1000;
+/// More than any real image has, so that a corrupt one doesn't make the
+/// loader allocate gigabytes while looking for entries.
+const MAX_HEADERS_SIZE: usize = 0x10000;
+const MAX_SECTION_SIZE: usize = 0x100000;
+
+/// Finds the unified kernel images in `/EFI/Linux` built for this machine.
+pub fn entries(image_handle: Handle) -> Vec<BlsEntry> {
+    let file_system = fs::get(image_handle);
+    let names = file_system.read_dir(UKI_PATH).unwrap_or_default();
+    names
+        .iter()
+        .filter(|name| {
+            let extension = name.rsplit_once('.').map(|(_, extension)| extension);
+            extension.map_or(false, |extension| extension.eq_ignore_ascii_case("efi"))
+        })
+        .filter_map(|name| {
+            let path = format!("{UKI_PATH}\\{name}");
+            let mut file = file_system.open(&path, FileMode::Read).ok()?;
+            entry(name, &path, &mut file)
+        })
+        .collect()
+}
+
+/// Builds an entry from the `.osrel`, `.uname` and `.cmdline` sections,
+/// reading only the headers and those sections.
+fn entry(name: &str, path: &str, file: &mut RegularFile) -> Option<BlsEntry> {
+    let file_size = file.get_boxed_info::<FileInfo>().ok()?.file_size();
+    let mut prefix = file.read_at(0, HEADERS_SIZE).ok()?;
+    let headers = match Headers::parse(&prefix) {
+        Err(Some(len)) if len <= MAX_HEADERS_SIZE && len as u64 <= file_size => {
+            prefix = file.read_at(0, len).ok()?;
+            Headers::parse(&prefix).ok()?
+        }
+        headers => headers.ok()?,
+    };
+    if headers.coff.machine != pe::MACHINE {
+        return None;
+    }
+    let mut section = |name| {
+        let (offset, len) = headers.section(name)?.file_range();
+        if len > MAX_SECTION_SIZE || offset.checked_add(len as u64)? > file_size {
+            return None;
+        }
+        let data = file.read_at(offset, len).ok()?;
+        let data = String::from_utf8_lossy(&data);
+        Some(data.trim_end_matches('\0').trim().to_string())
+    };
+    let os_release = section(".osrel")?;
+    let uname = section(".uname");
+    let cmdline = section(".cmdline");
+    let field = |key| os_release_field(&os_release, key);
+    Some(BlsEntry {
+        id: name.to_string(),
+        title: field("PRETTY_NAME").or_else(|| field("NAME")),
+        version: uname
+            .or_else(|| field("IMAGE_VERSION"))
+            .or_else(|| field("VERSION_ID")),
+        sort_key: field("IMAGE_ID").or_else(|| field("ID")),
+        efi: Some(path.to_string()),
+        options: cmdline
+            .into_iter()
+            .filter(|cmdline| !cmdline.is_empty())
+            .collect(),
+        ..BlsEntry::default()
+    })
+}
+
+/// Looks up `key` in an os-release file, removing the value's quotes.
+pub fn os_release_field(os_release: &str, key: &str) -> Option<String> {
+    os_release.lines().find_map(|line| {
+        let (name, value) = line.trim().split_once('=')?;
+        if name != key {
+            return None;
+        }
+        let value = value.trim();
+        let unquoted = ['"', '\''].iter().find_map(|&quote| {
+            value
+                .strip_prefix(quote)
+                .and_then(|value| value.strip_suffix(quote))
+        });
+        let mut unescaped = String::new();
+        let mut chars = unquoted.unwrap_or(value).chars();
+        while let Some(c) = chars.next() {
+            unescaped.extend(match c {
+                '\\' => chars.next(),
+                c => Some(c),
+            });
+        }
+        Some(unescaped)
+    })
+}
+
+#[test_case]
+fn os_release() {
+    let os_release = "NAME=\"Fedora Linux\"\n\
+                      ID=fedora\n\
+                      PRETTY_NAME='Fedora Linux 37 (Workstation \\\"Edition\\\")'\n";
+    assert_eq!(
+        os_release_field(os_release, "ID"),
+        Some("fedora".to_string())
+    );
+    assert_eq!(
+        os_release_field(os_release, "PRETTY_NAME"),
+        Some("Fedora Linux 37 (Workstation \"Edition\")".to_string())
+    );
+    assert_eq!(os_release_field(os_release, "VERSION_ID"), None);
+}
@@ -0,0 +1,102 @@
+use super::str::ToCString16;
+use alloc::{string::String, vec::Vec};
+use uefi::{
+    table::runtime::{VariableAttributes, VariableVendor},
+    Guid, Result,
+};
+
+/// The vendor GUID the loader keeps its own variables under, apart from the
+/// Boot Loader Interface ones that systemd's tools read and write.
+pub const LOADER_VENDOR: VariableVendor = VariableVendor(Guid::from_values(
+    0x85390066,
+    0xebe9,
+    0x45ed,
+    0x89c0,
+    0xf4995c58d42b,
+));
+
+/// The id of the entry booted last, for `"default_entry": "@saved"`.
+pub const SAVED_ENTRY: &str = "SavedEntry";
+
+/// Attributes of a variable that survives reboots.
+pub fn non_volatile() -> VariableAttributes {
+    VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS
+}
+
+pub fn get(name: &str, vendor: &VariableVendor) -> Option<Vec<u8>> {
+    get_with_attributes(name, vendor).map(|(data, _)| data)
+}
+
+/// Reads a variable along with the attributes it was stored with.
+pub fn get_with_attributes(
+    name: &str,
+    vendor: &VariableVendor,
+) -> Option<(Vec<u8>, VariableAttributes)> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let runtime_services = system_table.runtime_services();
+    let name = name.to_cstring16();
+    let size = runtime_services.get_variable_size(&name, vendor).ok()?;
+    let mut buffer = vec![0; size];
+    let (data, attributes) = runtime_services
+        .get_variable(&name, vendor, &mut buffer)
+        .ok()?;
+    Some((data.to_vec(), attributes))
+}
+
+pub fn set(
+    name: &str,
+    vendor: &VariableVendor,
+    attributes: VariableAttributes,
+    data: &[u8],
+) -> Result {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    system_table
+        .runtime_services()
+        .set_variable(&name.to_cstring16(), vendor, attributes, data)
+}
+
+/// Reads a variable holding a NUL-terminated UTF-16 string.
+pub fn get_string(name: &str, vendor: &VariableVendor) -> Option<String> {
+    from_utf16(&get(name, vendor)?)
+}
+
+/// Stores `value` as a NUL-terminated UTF-16 string.
+pub fn set_string(
+    name: &str,
+    vendor: &VariableVendor,
+    attributes: VariableAttributes,
+    value: &str,
+) -> Result {
+    set(name, vendor, attributes, &to_utf16(value))
+}
+
+fn to_utf16(value: &str) -> Vec<u8> {
+    value
+        .encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+/// Decodes a NUL-terminated UTF-16 string as variables hold them.
+pub fn from_utf16(data: &[u8]) -> Option<String> {
+    let units = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0);
+    char::decode_utf16(units)
+        .collect::<core::result::Result<_, _>>()
+        .ok()
+}
+
+#[test_case]
+fn utf16() {
+    let data = to_utf16("arch-6.1");
+    assert_eq!(data.len(), 18);
+    assert_eq!(from_utf16(&data).as_deref(), Some("arch-6.1"));
+    assert_eq!(from_utf16(&data[..4]).as_deref(), Some("ar"));
+}
@@ -0,0 +1,688 @@
+use super::{
+    btrfs::Btrfs, ext4::Ext4, fs::FILE_INFO_SIZE, linux::ProtocolServices, str::ToCString16,
+};
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{mem::size_of_val, ptr, slice};
+use uefi::{
+    prelude::*,
+    proto::{
+        media::{
+            block::BlockIO,
+            file::{
+                FileAttribute, FileInfo, FileMode, FileProtocolInfo, FileSystemInfo,
+                FileSystemVolumeLabel,
+            },
+            fs::SimpleFileSystem,
+        },
+        Protocol,
+    },
+    table::{
+        boot::{OpenProtocolAttributes, OpenProtocolParams},
+        runtime::{Daylight, Time, TimeParams},
+    },
+    unsafe_guid, CStr16, Char16, Error, Guid, Identify, Result,
+};
+
+/// How many symbolic links a path may go through, as in Linux.
+const MAX_SYMLINKS: usize = 40;
+/// The longest symbolic link target, as in Linux.
+const PATH_MAX: u64 = 4096;
+
+/// Random access to the bytes of a partition.
+pub trait Disk {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result;
+
+    /// The size of the partition in bytes.
+    fn size(&self) -> u64;
+}
+
+pub type NodeId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// What the loader needs to know about a file, with times in seconds since
+/// the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: Kind,
+    pub size: u64,
+    pub accessed: i64,
+    pub modified: i64,
+    pub changed: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeInfo {
+    pub label: String,
+    pub uuid: [u8; 16],
+    pub size: u64,
+    pub free_space: u64,
+    pub block_size: u32,
+}
+
+/// A read-only file system the loader reads itself rather than through a
+/// firmware driver.
+pub trait Volume {
+    fn root(&self) -> NodeId;
+
+    fn metadata(&self, node: NodeId) -> Result<Metadata>;
+
+    /// The entries of a directory other than `.` and `..`.
+    fn read_dir(&self, node: NodeId) -> Result<Vec<(String, NodeId)>>;
+
+    /// Reads from `offset` into `buffer`, returning how much was read.
+    fn read(&self, node: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize>;
+
+    fn info(&self) -> VolumeInfo;
+
+    fn lookup(&self, directory: NodeId, name: &str) -> Result<Option<NodeId>> {
+        let entries = self.read_dir(directory)?;
+        let entry = entries.into_iter().find(|(entry, _)| entry == name);
+        Ok(entry.map(|(_, node)| node))
+    }
+
+    /// The subvolumes and snapshots of file systems that have them.
+    fn subvolumes(&self) -> Vec<Subvolume> {
+        Vec::new()
+    }
+}
+
+/// A subvolume or snapshot at `path` from the root of its volume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subvolume {
+    pub id: u64,
+    pub path: String,
+    /// Whether Linux mounts it when not told which.
+    pub is_default: bool,
+    /// Whether it was made as a snapshot of another subvolume.
+    pub is_snapshot: bool,
+    /// When it was made, in seconds since the Unix epoch.
+    pub created: i64,
+}
+
+/// The file system the loader reads itself on `volume`, if it does.
+pub fn get_volume(image_handle: Handle, volume: Handle) -> Option<&'static dyn Volume> {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let file_system = system_table
+        .boot_services()
+        .open_protocol::<SimpleFileSystem>(
+            OpenProtocolParams {
+                handle: volume,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+        .ok()?;
+    let file_system = file_system.interface.get().cast::<SimpleFileSystemImpl>();
+    // Only the installed protocols go on past `open_volume`.
+    let is_installed = unsafe { ptr::addr_of!((*file_system).open_volume).read() } as usize
+        == open_volume as usize;
+    is_installed.then(|| unsafe { (*file_system).volume })
+}
+
+/// Formats a UUID the way Linux shows file system UUIDs.
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex = uuid
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Installs the Simple File System protocol on every partition that has none
+/// but holds a file system the loader can read itself, so that its files
+/// open like the ones on FAT volumes. Whole disks are left alone: their
+/// file systems are on their partitions.
+pub fn mount_all(image_handle: Handle) {
+    let system_table = uefi_services::system_table();
+    let system_table = unsafe { system_table.as_ref() };
+    let boot_services = system_table.boot_services();
+    let handles = boot_services.find_handles::<BlockIO>().unwrap_or_default();
+    for handle in handles {
+        if boot_services
+            .open_protocol::<SimpleFileSystem>(
+                OpenProtocolParams {
+                    handle,
+                    agent: image_handle,
+                    controller: None,
+                },
+                OpenProtocolAttributes::GetProtocol,
+            )
+            .is_ok()
+        {
+            continue;
+        }
+        let disk = match BlockDevice::open(image_handle, handle) {
+            Some(disk) if disk.is_partition => disk,
+            _ => continue,
+        };
+        let volume: Box<dyn Volume> = if let Ok(ext4) = Ext4::new(disk) {
+            Box::new(ext4)
+        } else if let Ok(btrfs) = Btrfs::new(disk) {
+            Box::new(btrfs)
+        } else {
+            continue;
+        };
+        let _ = install(boot_services, handle, volume);
+    }
+}
+
+fn install(boot_services: &BootServices, handle: Handle, volume: Box<dyn Volume>) -> Result {
+    let file_system = Box::leak(Box::new(SimpleFileSystemImpl {
+        revision: 0x0001_0000,
+        open_volume,
+        volume: Box::leak(volume),
+    }));
+    let interface = file_system as *const SimpleFileSystemImpl as *const _;
+    ProtocolServices::get(boot_services).install(
+        &mut Some(handle),
+        &SimpleFileSystem::GUID,
+        interface,
+    )
+}
+
+/// A disk or partition read through the Disk I/O protocol, which takes care
+/// of reads that don't start or end on a block boundary.
+#[derive(Clone, Copy)]
+pub struct BlockDevice {
+    disk_io: &'static DiskIo,
+    media_id: u32,
+    pub block_size: u32,
+    pub last_block: u64,
+    pub is_partition: bool,
+}
+
+impl BlockDevice {
+    /// Opens the block device on `handle` if there is media in it.
+    pub fn open(image_handle: Handle, handle: Handle) -> Option<Self> {
+        let system_table = uefi_services::system_table();
+        let system_table = unsafe { system_table.as_ref() };
+        let boot_services = system_table.boot_services();
+        let params = || OpenProtocolParams {
+            handle,
+            agent: image_handle,
+            controller: None,
+        };
+        let attributes = || OpenProtocolAttributes::GetProtocol;
+        let block_io = boot_services
+            .open_protocol::<BlockIO>(params(), attributes())
+            .ok()?;
+        let media = unsafe { &*block_io.interface.get() }.media();
+        let disk_io = boot_services
+            .open_protocol::<DiskIo>(params(), attributes())
+            .ok()?;
+        media.is_media_preset().then(|| Self {
+            disk_io: unsafe { &*disk_io.interface.get() },
+            media_id: media.media_id(),
+            block_size: media.block_size(),
+            last_block: media.last_block(),
+            is_partition: media.is_logical_partition(),
+        })
+    }
+}
+
+impl Disk for BlockDevice {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result {
+        unsafe {
+            (self.disk_io.read_disk)(
+                self.disk_io,
+                self.media_id,
+                offset,
+                buffer.len(),
+                buffer.as_mut_ptr(),
+            )
+        }
+        .into()
+    }
+
+    fn size(&self) -> u64 {
+        (self.last_block + 1) * u64::from(self.block_size)
+    }
+}
+
+/// Resolves `path` from the directory at the end of `ancestors`, following
+/// symbolic links, and returns the ancestors of the node it names.
+fn resolve(volume: &dyn Volume, ancestors: &[NodeId], path: &str) -> Result<Vec<NodeId>> {
+    let mut ancestors = match path.starts_with(['\\', '/']) {
+        true => Vec::from([volume.root()]),
+        false => Vec::from(ancestors),
+    };
+    let mut pending = components(path).rev().map(String::from).collect::<Vec<_>>();
+    let mut symlinks = 0;
+    while let Some(component) = pending.pop() {
+        let directory = *ancestors.last().expect("no ancestors");
+        match component.as_str() {
+            "." => continue,
+            ".." => {
+                if ancestors.len() > 1 {
+                    ancestors.pop();
+                }
+                continue;
+            }
+            _ => (),
+        }
+        if volume.metadata(directory)?.kind != Kind::Directory {
+            return Err(Error::from(Status::NOT_FOUND));
+        }
+        let node = volume
+            .lookup(directory, &component)?
+            .ok_or_else(|| Error::from(Status::NOT_FOUND))?;
+        let metadata = volume.metadata(node)?;
+        if metadata.kind != Kind::Symlink {
+            ancestors.push(node);
+            continue;
+        }
+        symlinks += 1;
+        if symlinks > MAX_SYMLINKS {
+            return Err(Error::from(Status::NOT_FOUND));
+        }
+        if metadata.size > PATH_MAX {
+            return Err(Error::from(Status::VOLUME_CORRUPTED));
+        }
+        let mut target = vec![0; metadata.size as usize];
+        let len = volume.read(node, 0, &mut target)?;
+        let target = String::from_utf8_lossy(&target[..len]).into_owned();
+        if target.starts_with('/') {
+            ancestors.truncate(1);
+        }
+        pending.extend(components(&target).rev().map(String::from));
+    }
+    Ok(ancestors)
+}
+
+/// The names in a path with either kind of separator.
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split(|c| c == '\\' || c == '/')
+        .filter(|name| !name.is_empty())
+}
+
+/// Converts seconds since the Unix epoch to a UTC time, clamped to the years
+/// 1900 to 9999 that `EFI_TIME` holds.
+pub fn time(seconds: i64) -> Time {
+    let seconds = seconds.clamp(-2_208_988_800, 253_402_300_799);
+    let (days, seconds) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+    // Howard Hinnant's `civil_from_days`, with eras starting on 1 March.
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u8;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u8;
+    let year = (year_of_era + era * 400 + i64::from(month <= 2)) as u16;
+    Time::new(TimeParams {
+        year,
+        month,
+        day,
+        hour: (seconds / 3600) as u8,
+        minute: (seconds / 60 % 60) as u8,
+        second: (seconds % 60) as u8,
+        nanosecond: 0,
+        time_zone: None,
+        daylight: Daylight::empty(),
+    })
+    .unwrap_or_else(|_| Time::invalid())
+}
+
+#[repr(C)]
+#[unsafe_guid("ce345171-ba0b-11d2-8e4f-00a0c969723b")]
+#[derive(Protocol)]
+struct DiskIo {
+    revision: u64,
+    read_disk: unsafe extern "efiapi" fn(
+        this: &DiskIo,
+        media_id: u32,
+        offset: u64,
+        buffer_size: usize,
+        buffer: *mut u8,
+    ) -> Status,
+    _write_disk: usize,
+}
+
+#[repr(C)]
+struct SimpleFileSystemImpl {
+    revision: u64,
+    open_volume:
+        unsafe extern "efiapi" fn(this: &SimpleFileSystemImpl, root: &mut *mut FileImpl) -> Status,
+    volume: &'static dyn Volume,
+}
+
+/// An open file or directory, laid out as `EFI_FILE_PROTOCOL` followed by
+/// the state behind it.
+#[repr(C)]
+struct FileImpl {
+    revision: u64,
+    open: unsafe extern "efiapi" fn(
+        this: &mut FileImpl,
+        new_handle: &mut *mut FileImpl,
+        file_name: *const Char16,
+        open_mode: FileMode,
+        attributes: FileAttribute,
+    ) -> Status,
+    close: unsafe extern "efiapi" fn(this: *mut FileImpl) -> Status,
+    delete: unsafe extern "efiapi" fn(this: *mut FileImpl) -> Status,
+    read: unsafe extern "efiapi" fn(
+        this: &mut FileImpl,
+        buffer_size: &mut usize,
+        buffer: *mut u8,
+    ) -> Status,
+    write: unsafe extern "efiapi" fn(
+        this: &mut FileImpl,
+        buffer_size: &mut usize,
+        buffer: *const u8,
+    ) -> Status,
+    get_position: unsafe extern "efiapi" fn(this: &mut FileImpl, position: &mut u64) -> Status,
+    set_position: unsafe extern "efiapi" fn(this: &mut FileImpl, position: u64) -> Status,
+    get_info: unsafe extern "efiapi" fn(
+        this: &mut FileImpl,
+        information_type: &Guid,
+        buffer_size: &mut usize,
+        buffer: *mut u8,
+    ) -> Status,
+    set_info: unsafe extern "efiapi" fn(
+        this: &mut FileImpl,
+        information_type: &Guid,
+        buffer_size: usize,
+        buffer: *const u8,
+    ) -> Status,
+    flush: unsafe extern "efiapi" fn(this: &mut FileImpl) -> Status,
+    volume: &'static dyn Volume,
+    /// The directories leading to the file and the file itself.
+    ancestors: Vec<NodeId>,
+    name: String,
+    metadata: Metadata,
+    /// The offset in a file or the index of the next entry in a directory.
+    position: u64,
+    entries: Option<Vec<(String, NodeId)>>,
+}
+
+impl FileImpl {
+    fn new(volume: &'static dyn Volume, ancestors: Vec<NodeId>, name: String) -> Result<Self> {
+        let node = *ancestors.last().expect("no ancestors");
+        Ok(Self {
+            revision: 0x0001_0000,
+            open,
+            close,
+            delete,
+            read,
+            write,
+            get_position,
+            set_position,
+            get_info,
+            set_info,
+            flush,
+            volume,
+            metadata: volume.metadata(node)?,
+            ancestors,
+            name,
+            position: 0,
+            entries: None,
+        })
+    }
+
+    fn node(&self) -> NodeId {
+        *self.ancestors.last().expect("no ancestors")
+    }
+
+    fn file_info(&self, name: &str, metadata: &Metadata) -> Vec<u64> {
+        let mut attribute = FileAttribute::READ_ONLY;
+        if metadata.kind == Kind::Directory {
+            attribute |= FileAttribute::DIRECTORY;
+        }
+        let name = name.to_cstring16();
+        let mut buffer = vec![0u64; (FILE_INFO_SIZE + name.num_bytes() + 7) / 8];
+        let storage = unsafe {
+            slice::from_raw_parts_mut(buffer.as_mut_ptr().cast::<u8>(), buffer.len() * 8)
+        };
+        let info = FileInfo::new(
+            storage,
+            metadata.size,
+            metadata.size,
+            time(metadata.changed),
+            time(metadata.accessed),
+            time(metadata.modified),
+            attribute,
+            &name,
+        )
+        .expect("FileInfo::new failed");
+        let len = size_of_val(info);
+        buffer.truncate((len + 7) / 8);
+        buffer
+    }
+}
+
+/// Copies an information structure to the caller's buffer, or tells how
+/// big it needs to be.
+fn copy_info<T: FileProtocolInfo + ?Sized>(
+    info: &T,
+    buffer_size: &mut usize,
+    buffer: *mut u8,
+) -> Status {
+    let len = size_of_val(info);
+    if *buffer_size < len {
+        *buffer_size = len;
+        return Status::BUFFER_TOO_SMALL;
+    }
+    *buffer_size = len;
+    unsafe { ptr::copy_nonoverlapping((info as *const T).cast::<u8>(), buffer, len) };
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn open_volume(
+    this: &SimpleFileSystemImpl,
+    root: &mut *mut FileImpl,
+) -> Status {
+    match FileImpl::new(this.volume, Vec::from([this.volume.root()]), String::new()) {
+        Ok(file) => {
+            *root = Box::into_raw(Box::new(file));
+            Status::SUCCESS
+        }
+        Err(err) => err.status(),
+    }
+}
+
+unsafe extern "efiapi" fn open(
+    this: &mut FileImpl,
+    new_handle: &mut *mut FileImpl,
+    file_name: *const Char16,
+    open_mode: FileMode,
+    _attributes: FileAttribute,
+) -> Status {
+    if open_mode != FileMode::Read {
+        return Status::WRITE_PROTECTED;
+    }
+    let path = CStr16::from_ptr(file_name).to_string();
+    let ancestors = match resolve(this.volume, &this.ancestors, &path) {
+        Ok(ancestors) => ancestors,
+        Err(err) => return err.status(),
+    };
+    let name = components(&path).last().unwrap_or_default();
+    match FileImpl::new(this.volume, ancestors, String::from(name)) {
+        Ok(file) => {
+            *new_handle = Box::into_raw(Box::new(file));
+            Status::SUCCESS
+        }
+        Err(err) => err.status(),
+    }
+}
+
+unsafe extern "efiapi" fn close(this: *mut FileImpl) -> Status {
+    drop(Box::from_raw(this));
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn delete(this: *mut FileImpl) -> Status {
+    drop(Box::from_raw(this));
+    Status::WARN_DELETE_FAILURE
+}
+
+unsafe extern "efiapi" fn read(
+    this: &mut FileImpl,
+    buffer_size: &mut usize,
+    buffer: *mut u8,
+) -> Status {
+    if this.metadata.kind != Kind::Directory {
+        let len = (*buffer_size as u64).min(this.metadata.size.saturating_sub(this.position));
+        let buffer = slice::from_raw_parts_mut(buffer, len as usize);
+        return match this.volume.read(this.node(), this.position, buffer) {
+            Ok(len) => {
+                this.position += len as u64;
+                *buffer_size = len;
+                Status::SUCCESS
+            }
+            Err(err) => err.status(),
+        };
+    }
+    if this.entries.is_none() {
+        match this.volume.read_dir(this.node()) {
+            Ok(entries) => this.entries = Some(entries),
+            Err(err) => return err.status(),
+        }
+    }
+    let entries = this.entries.as_ref().expect("no entries");
+    let (name, node) = match entries.get(this.position as usize) {
+        Some(entry) => entry,
+        None => {
+            *buffer_size = 0;
+            return Status::SUCCESS;
+        }
+    };
+    let metadata = match this.volume.metadata(*node) {
+        Ok(metadata) => metadata,
+        Err(err) => return err.status(),
+    };
+    let info = this.file_info(name, &metadata);
+    let info = slice::from_raw_parts(info.as_ptr().cast::<u8>(), info.len() * 8);
+    let len = FILE_INFO_SIZE + (name.encode_utf16().count() + 1) * 2;
+    if *buffer_size < len {
+        *buffer_size = len;
+        return Status::BUFFER_TOO_SMALL;
+    }
+    ptr::copy_nonoverlapping(info.as_ptr(), buffer, len);
+    *buffer_size = len;
+    this.position += 1;
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn write(
+    _this: &mut FileImpl,
+    _buffer_size: &mut usize,
+    _buffer: *const u8,
+) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+unsafe extern "efiapi" fn get_position(this: &mut FileImpl, position: &mut u64) -> Status {
+    if this.metadata.kind == Kind::Directory {
+        return Status::UNSUPPORTED;
+    }
+    *position = this.position;
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn set_position(this: &mut FileImpl, position: u64) -> Status {
+    match (this.metadata.kind, position) {
+        (Kind::Directory, 0) => this.position = 0,
+        (Kind::Directory, _) => return Status::UNSUPPORTED,
+        (_, u64::MAX) => this.position = this.metadata.size,
+        (_, position) => this.position = position,
+    }
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn get_info(
+    this: &mut FileImpl,
+    information_type: &Guid,
+    buffer_size: &mut usize,
+    buffer: *mut u8,
+) -> Status {
+    let info = this.volume.info();
+    let label = info.label.to_cstring16();
+    let mut storage = vec![0u64; (FILE_INFO_SIZE + label.num_bytes() + 7) / 8];
+    let storage = slice::from_raw_parts_mut(storage.as_mut_ptr().cast::<u8>(), storage.len() * 8);
+    match *information_type {
+        FileInfo::GUID => {
+            let info = this.file_info(&this.name, &this.metadata);
+            let info = slice::from_raw_parts(info.as_ptr().cast::<u8>(), info.len() * 8);
+            let len = FILE_INFO_SIZE + (this.name.encode_utf16().count() + 1) * 2;
+            if *buffer_size < len {
+                *buffer_size = len;
+                return Status::BUFFER_TOO_SMALL;
+            }
+            ptr::copy_nonoverlapping(info.as_ptr(), buffer, len);
+            *buffer_size = len;
+            Status::SUCCESS
+        }
+        FileSystemInfo::GUID => {
+            let file_system_info = FileSystemInfo::new(
+                storage,
+                true,
+                info.size,
+                info.free_space,
+                info.block_size,
+                &label,
+            )
+            .expect("FileSystemInfo::new failed");
+            copy_info(file_system_info, buffer_size, buffer)
+        }
+        FileSystemVolumeLabel::GUID => {
+            let volume_label = FileSystemVolumeLabel::new(storage, &label)
+                .expect("FileSystemVolumeLabel::new failed");
+            copy_info(volume_label, buffer_size, buffer)
+        }
+        _ => Status::UNSUPPORTED,
+    }
+}
+
+unsafe extern "efiapi" fn set_info(
+    _this: &mut FileImpl,
+    _information_type: &Guid,
+    _buffer_size: usize,
+    _buffer: *const u8,
+) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+unsafe extern "efiapi" fn flush(_this: &mut FileImpl) -> Status {
+    Status::SUCCESS
+}
+
+#[test_case]
+fn paths_and_times() {
+    let names = components("\\boot//grub\\x86_64-efi/").collect::<Vec<_>>();
+    assert_eq!(names, ["boot", "grub", "x86_64-efi"]);
+    let epoch = time(0);
+    assert_eq!((epoch.year(), epoch.month(), epoch.day()), (1970, 1, 1));
+    let leap_day = time(951_782_400 + 3723);
+    assert_eq!(
+        (leap_day.year(), leap_day.month(), leap_day.day()),
+        (2000, 2, 29)
+    );
+    assert_eq!(
+        (leap_day.hour(), leap_day.minute(), leap_day.second()),
+        (1, 2, 3)
+    );
+    let before_epoch = time(-1);
+    assert_eq!((before_epoch.year(), before_epoch.hour()), (1969, 23));
+    assert_eq!((time(i64::MIN).year(), time(i64::MAX).year()), (1900, 9999));
+}